@@ -1,7 +1,10 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use yew_project::image_processing::{generate_gem_art_preview, generate_gem_art_final, generate_text_image, GemArtData};
-use yew_project::models::{ImageFitOption, Color, GemCount, ColorMappingMode};
+use yew_project::image_processing::{generate_gem_art_preview, generate_gem_art_preview_with_settings, generate_gem_art_pattern_with_settings, generate_gem_art_final, generate_text_image, preview_data_url, render_preview_image};
+use yew_project::models::{ImageFitOption, Color, GemCount, ColorMappingMode, ColorMetric, GenerationSettings};
+use yew_project::color_distance::{lab_distance, lightness_bound_factor};
+use yew_project::palette_index::LightnessIndex;
 use yew_project::dmc_colors;
+use palette::{IntoColor, Lab, Srgb};
 use image::{ImageBuffer, Rgba};
 use std::time::Duration;
 use base64::{engine::general_purpose, Engine as _};
@@ -44,6 +47,7 @@ fn benchmark_generate_gem_art_preview(c: &mut Criterion) {
                 0.0, // mapping_weight
                 None, // custom_width_mm
                 None, // custom_height_mm
+                2.7, // gem_size_mm
             ).unwrap();
         }));
     }
@@ -71,6 +75,7 @@ fn benchmark_generate_gem_art_final(c: &mut Criterion) {
         0.0,
         None,
         None,
+        2.7,
     ).unwrap();
 
     let mut group = c.benchmark_group("generate_gem_art_final");
@@ -112,6 +117,7 @@ fn benchmark_generate_gem_art_color_count(c: &mut Criterion) {
                 0.0,
                 None, // custom_width_mm
                 None, // custom_height_mm
+                2.7, // gem_size_mm
             ).unwrap();
         }));
     }
@@ -145,6 +151,7 @@ fn benchmark_generate_gem_art_fit_vs_crop(c: &mut Criterion) {
             0.0,
             None, // custom_width_mm
             None, // custom_height_mm
+            2.7, // gem_size_mm
         ).unwrap();
     }));
 
@@ -158,6 +165,7 @@ fn benchmark_generate_gem_art_fit_vs_crop(c: &mut Criterion) {
             0.0,
             None, // custom_width_mm
             None, // custom_height_mm
+            2.7, // gem_size_mm
         ).unwrap();
     }));
 
@@ -183,5 +191,142 @@ fn benchmark_generate_text_image(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, benchmark_generate_gem_art_preview, benchmark_generate_gem_art_final, benchmark_generate_gem_art_color_count, benchmark_generate_gem_art_fit_vs_crop, benchmark_generate_text_image);
+fn benchmark_weighted_mapping(c: &mut Criterion) {
+    let dmc_colors = dmc_colors::get_dmc_colors();
+    let colors: Vec<Color> = dmc_colors.iter().map(|c| Color {
+        value: format!("#{}", c.hex),
+        floss_number: c.floss.clone(),
+        r: c.r,
+        g: c.g,
+        b: c.b,
+        hex: c.hex.clone(),
+    }).collect();
+
+    let image_data = generate_test_image(500, 500);
+
+    let mut group = c.benchmark_group("weighted_mapping");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(5));
+
+    for metric in [ColorMetric::Cie76, ColorMetric::Ciede2000] {
+        let settings = GenerationSettings {
+            mapping_mode: ColorMappingMode::AdaptiveLightnessWeighted,
+            mapping_weight: 0.5,
+            color_metric: metric,
+            ..GenerationSettings::default()
+        };
+        group.bench_function(format!("{:?} all colors", metric), |b| b.iter(|| {
+            generate_gem_art_preview_with_settings(&image_data, &colors, &settings).unwrap();
+        }));
+    }
+    group.finish();
+}
+
+fn benchmark_lightness_index(c: &mut Criterion) {
+    let palette_labs: Vec<[f32; 3]> = dmc_colors::get_dmc_colors().iter().map(|c| {
+        let lab: Lab = Srgb::new(c.r as f32 / 255.0, c.g as f32 / 255.0, c.b as f32 / 255.0).into_color();
+        [lab.l, lab.a, lab.b]
+    }).collect();
+    let palette_ls: Vec<f32> = palette_labs.iter().map(|lab| lab[0]).collect();
+    let index = LightnessIndex::new(&palette_ls);
+    // Colors spread over the sRGB cube, like the cells of a real image
+    let queries: Vec<[f32; 3]> = (0..1000u32).map(|i| {
+        let lab: Lab = Srgb::new((i % 10) as f32 / 9.0, (i / 10 % 10) as f32 / 9.0, (i / 100) as f32 / 9.0).into_color();
+        [lab.l, lab.a, lab.b]
+    }).collect();
+    let weight = 0.5;
+    let metric = ColorMetric::Cie76;
+
+    let mut group = c.benchmark_group("lightness_index");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(5));
+
+    group.bench_function("full scan", |b| b.iter(|| {
+        for query in &queries {
+            let mut best_score = f32::INFINITY;
+            let mut best = 0;
+            for (i, lab) in palette_labs.iter().enumerate() {
+                let score = weight * (query[0] - lab[0]).abs() + (1.0 - weight) * lab_distance(metric, *query, *lab);
+                if score < best_score {
+                    best_score = score;
+                    best = i;
+                }
+            }
+            std::hint::black_box(best);
+        }
+    }));
+
+    group.bench_function("index", |b| b.iter(|| {
+        for query in &queries {
+            let best = index.nearest(query[0], query[0], weight, lightness_bound_factor(metric), |_| true, |i| {
+                lab_distance(metric, *query, palette_labs[i])
+            });
+            std::hint::black_box(best);
+        }
+    }));
+    group.finish();
+}
+
+fn benchmark_color_lut(c: &mut Criterion) {
+    let dmc_colors = dmc_colors::get_dmc_colors();
+    let colors: Vec<Color> = dmc_colors.iter().map(|c| Color {
+        value: format!("#{}", c.hex),
+        floss_number: c.floss.clone(),
+        r: c.r,
+        g: c.g,
+        b: c.b,
+        hex: c.hex.clone(),
+    }).collect();
+
+    let image_data = generate_test_image(500, 500);
+
+    let mut group = c.benchmark_group("color_lut");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(5));
+
+    for count in [10, 100, 500] {
+        let color_subset: Vec<Color> = colors.iter().take(count).cloned().collect();
+        for color_lut in [false, true] {
+            let settings = GenerationSettings { color_metric: ColorMetric::Ciede2000, color_lut, ..GenerationSettings::default() };
+            group.bench_function(format!("{} colors, lut {}", count, color_lut), |b| b.iter(|| {
+                generate_gem_art_preview_with_settings(&image_data, &color_subset, &settings).unwrap();
+            }));
+        }
+    }
+    group.finish();
+}
+
+// Output stage of each preview regeneration: PNG data URL (which the browser must then
+// decode again) versus raw pixels for `ImageData`
+fn benchmark_preview_output(c: &mut Criterion) {
+    let dmc_colors = dmc_colors::get_dmc_colors();
+    let colors: Vec<Color> = dmc_colors.iter().map(|c| Color {
+        value: format!("#{}", c.hex),
+        floss_number: c.floss.clone(),
+        r: c.r,
+        g: c.g,
+        b: c.b,
+        hex: c.hex.clone(),
+    }).collect();
+
+    let image_data = generate_test_image(300, 300);
+
+    let mut group = c.benchmark_group("preview_output");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(5));
+
+    for gem_size_mm in [2.0, 2.7, 5.0] {
+        let settings = GenerationSettings { gem_size_mm, ..GenerationSettings::default() };
+        let (_, gem_art_data) = generate_gem_art_pattern_with_settings(&image_data, &colors, &settings).unwrap();
+        group.bench_function(format!("{} mm, data url", gem_size_mm), |b| b.iter(|| {
+            preview_data_url(&gem_art_data).unwrap();
+        }));
+        group.bench_function(format!("{} mm, pixel buffer", gem_size_mm), |b| b.iter(|| {
+            render_preview_image(&gem_art_data);
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_generate_gem_art_preview, benchmark_generate_gem_art_final, benchmark_generate_gem_art_color_count, benchmark_generate_gem_art_fit_vs_crop, benchmark_generate_text_image, benchmark_weighted_mapping, benchmark_lightness_index, benchmark_color_lut, benchmark_preview_output);
 criterion_main!(benches);
@@ -0,0 +1,21 @@
+// Runs preview and chart generation off the page's main thread. Loaded as a module
+// worker by `GenerationWorker` (src/worker.rs).
+import init, { handle_worker_message } from './pkg/yew_project.js';
+
+const ready = init();
+
+self.onmessage = async (event) => {
+    try {
+        await ready;
+    } catch (error) {
+        // The page falls back to generating on its own thread
+        self.postMessage({ unavailable: String(error) });
+        return;
+    }
+    try {
+        handle_worker_message(event.data);
+    } catch (error) {
+        // A panic leaves the wasm instance unusable, so the page replaces this worker
+        self.postMessage({ crashed: String(error) });
+    }
+};
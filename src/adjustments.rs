@@ -0,0 +1,82 @@
+use image::DynamicImage;
+use rayon::prelude::*;
+use crate::models::ImageAdjustments;
+use crate::utils::{srgb_to_linear, linear_to_srgb};
+
+// Largest per-channel gain applied by auto white balance, to avoid blowing up
+// images that are genuinely dominated by one color.
+const MAX_WHITE_BALANCE_GAIN: f32 = 2.0;
+// Channel gain applied at full temperature or tint.
+const TEMPERATURE_TINT_GAIN: f32 = 0.25;
+
+/// Gray-world white balance: per-channel gains (in linear light) that make the
+/// average color of the visible pixels neutral.
+fn white_balance_gains(pixels: &[u8], linear_lut: &[f32]) -> [f32; 3] {
+    let (sums, weight) = pixels
+        .par_chunks_exact(4)
+        .map(|p| {
+            let alpha = p[3] as f32 / 255.0;
+            ([
+                linear_lut[p[0] as usize] * alpha,
+                linear_lut[p[1] as usize] * alpha,
+                linear_lut[p[2] as usize] * alpha,
+            ], alpha)
+        })
+        .reduce(
+            || ([0.0f32; 3], 0.0f32),
+            |(a, wa), (b, wb)| ([a[0] + b[0], a[1] + b[1], a[2] + b[2]], wa + wb),
+        );
+    if weight <= 0.0 || sums.iter().any(|&s| s <= 0.0) {
+        return [1.0; 3];
+    }
+    let gray = (sums[0] + sums[1] + sums[2]) / 3.0;
+    [
+        (gray / sums[0]).clamp(1.0 / MAX_WHITE_BALANCE_GAIN, MAX_WHITE_BALANCE_GAIN),
+        (gray / sums[1]).clamp(1.0 / MAX_WHITE_BALANCE_GAIN, MAX_WHITE_BALANCE_GAIN),
+        (gray / sums[2]).clamp(1.0 / MAX_WHITE_BALANCE_GAIN, MAX_WHITE_BALANCE_GAIN),
+    ]
+}
+
+/// Applies white balance, temperature/tint, brightness, contrast, saturation and gamma
+/// to the decoded image, in that order. Alpha is left untouched.
+pub fn apply_adjustments(img: DynamicImage, adjustments: &ImageAdjustments) -> DynamicImage {
+    if adjustments.is_identity() {
+        return img;
+    }
+
+    let mut rgba = img.into_rgba8();
+    let linear_lut: Vec<f32> = (0..256).map(|v| srgb_to_linear(v as f32 / 255.0)).collect();
+
+    // Color balance gains are applied in linear light
+    let mut gains = if adjustments.auto_white_balance {
+        white_balance_gains(&rgba, &linear_lut)
+    } else {
+        [1.0; 3]
+    };
+    let temperature = adjustments.temperature.clamp(-1.0, 1.0) * TEMPERATURE_TINT_GAIN;
+    let tint = adjustments.tint.clamp(-1.0, 1.0) * TEMPERATURE_TINT_GAIN;
+    gains[0] *= 1.0 + temperature;
+    gains[1] *= 1.0 - tint;
+    gains[2] *= 1.0 - temperature;
+
+    let brightness = adjustments.brightness.clamp(-1.0, 1.0);
+    let contrast = 1.0 + adjustments.contrast.clamp(-1.0, 1.0);
+    let saturation = 1.0 + adjustments.saturation.clamp(-1.0, 1.0);
+    let inverse_gamma = 1.0 / adjustments.gamma.max(0.01);
+
+    rgba.par_chunks_exact_mut(4).for_each(|p| {
+        let mut c = [0.0f32; 3];
+        for i in 0..3 {
+            let balanced = (linear_lut[p[i] as usize] * gains[i]).clamp(0.0, 1.0);
+            c[i] = linear_to_srgb(balanced);
+            c[i] = (c[i] - 0.5) * contrast + 0.5 + brightness;
+        }
+        let luma = 0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2];
+        for (i, value) in c.iter().enumerate() {
+            let saturated = (luma + (value - luma) * saturation).clamp(0.0, 1.0);
+            p[i] = (saturated.powf(inverse_gamma) * 255.0).round() as u8;
+        }
+    });
+
+    DynamicImage::ImageRgba8(rgba)
+}
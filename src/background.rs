@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use crate::color_distance::lab_distance;
+use crate::models::{BackgroundMode, ColorMetric};
+
+/// Flags the gem cells (column-major, `gx * num_gems_y + gy`) that belong to the background.
+///
+/// `FloodFillFromBorders` takes the color shared by most border cells as the background
+/// color and flood-fills inwards from every border cell within `tolerance` of it, so a
+/// subject touching one edge is not swallowed. `ColorKey` flags every cell within
+/// `tolerance` of `key_lab`, connected or not. Returns an empty vector for `Off`.
+pub fn detect_background(
+    lab_grid: &[[f32; 3]],
+    num_gems_x: u32,
+    num_gems_y: u32,
+    mode: BackgroundMode,
+    key_lab: [f32; 3],
+    tolerance: f32,
+    metric: ColorMetric,
+) -> Vec<bool> {
+    if lab_grid.is_empty() {
+        return Vec::new();
+    }
+    match mode {
+        BackgroundMode::Off => Vec::new(),
+        BackgroundMode::ColorKey => lab_grid.iter().map(|lab| lab_distance(metric, *lab, key_lab) <= tolerance).collect(),
+        BackgroundMode::FloodFillFromBorders => {
+            let nx = num_gems_x as i64;
+            let ny = num_gems_y as i64;
+            let idx = |gx: i64, gy: i64| (gx * ny + gy) as usize;
+            let border: Vec<(i64, i64)> = (0..nx)
+                .flat_map(|gx| (0..ny).map(move |gy| (gx, gy)))
+                .filter(|&(gx, gy)| gx == 0 || gy == 0 || gx == nx - 1 || gy == ny - 1)
+                .collect();
+
+            // Most widely shared border color; ties go to the first cell
+            let (_, reference) = border
+                .iter()
+                .map(|&(gx, gy)| lab_grid[idx(gx, gy)])
+                .enumerate()
+                .max_by_key(|&(i, candidate)| {
+                    let shared = border
+                        .iter()
+                        .filter(|&&(gx, gy)| lab_distance(metric, lab_grid[idx(gx, gy)], candidate) <= tolerance)
+                        .count();
+                    (shared, std::cmp::Reverse(i))
+                })
+                .unwrap();
+            let matches = |cell: usize| lab_distance(metric, lab_grid[cell], reference) <= tolerance;
+
+            let mut background = vec![false; lab_grid.len()];
+            let mut queue = VecDeque::new();
+            for &(gx, gy) in &border {
+                let cell = idx(gx, gy);
+                if !background[cell] && matches(cell) {
+                    background[cell] = true;
+                    queue.push_back((gx, gy));
+                }
+            }
+            while let Some((gx, gy)) = queue.pop_front() {
+                for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                    let (x, y) = (gx + dx, gy + dy);
+                    if x < 0 || y < 0 || x >= nx || y >= ny {
+                        continue;
+                    }
+                    let cell = idx(x, y);
+                    if !background[cell] && matches(cell) {
+                        background[cell] = true;
+                        queue.push_back((x, y));
+                    }
+                }
+            }
+            background
+        }
+    }
+}
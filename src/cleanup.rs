@@ -0,0 +1,108 @@
+use std::collections::{HashMap, VecDeque};
+use crate::color_distance::lab_distance;
+use crate::models::{ColorMetric, DmcColorPrecomputed, EMPTY_CELL};
+use crate::progress::GenerationHooks;
+
+/// Reassigns connected components of at most `max_component_size` gems to the color
+/// most common along their border, as long as the color difference between the two
+/// flosses does not exceed `max_delta_e`. The grid is indexed `gx * num_gems_y + gy`.
+/// Empty cells are left alone and never count as a border color.
+/// Returns the number of gems that changed color.
+pub fn remove_confetti(
+    gem_grid: &mut [usize],
+    num_gems_x: u32,
+    num_gems_y: u32,
+    palette: &[DmcColorPrecomputed],
+    metric: ColorMetric,
+    max_component_size: usize,
+    max_delta_e: f32,
+) -> usize {
+    // Hooks without a cancellation token never stop the pass
+    remove_confetti_with_hooks(gem_grid, num_gems_x, num_gems_y, palette, metric, max_component_size, max_delta_e, &GenerationHooks::default())
+        .unwrap_or_default()
+}
+
+/// `remove_confetti` reporting progress to, and stopping when cancelled through, `hooks`.
+/// Cancellation is checked between columns, so the grid may be partly cleaned up.
+#[allow(clippy::too_many_arguments)]
+pub fn remove_confetti_with_hooks(
+    gem_grid: &mut [usize],
+    num_gems_x: u32,
+    num_gems_y: u32,
+    palette: &[DmcColorPrecomputed],
+    metric: ColorMetric,
+    max_component_size: usize,
+    max_delta_e: f32,
+    hooks: &GenerationHooks,
+) -> Result<usize, String> {
+    if max_component_size == 0 || gem_grid.is_empty() {
+        return Ok(0);
+    }
+
+    let nx = num_gems_x as i64;
+    let ny = num_gems_y as i64;
+    let idx = |gx: i64, gy: i64| (gx * ny + gy) as usize;
+    let palette_lab = |i: usize| [palette[i].lab_l, palette[i].lab_a, palette[i].lab_b];
+
+    let mut visited = vec![false; gem_grid.len()];
+    let mut changed = 0usize;
+    let mut component = Vec::new();
+    let mut queue = VecDeque::new();
+
+    for start_x in 0..nx {
+        hooks.loop_checkpoint("Cleaning up", start_x as usize, nx as usize)?;
+        for start_y in 0..ny {
+            let start = idx(start_x, start_y);
+            if visited[start] {
+                continue;
+            }
+            let color = gem_grid[start];
+
+            // Flood-fill the 4-connected component and tally the colors around it
+            component.clear();
+            queue.clear();
+            visited[start] = true;
+            queue.push_back((start_x, start_y));
+            let mut border_counts: HashMap<usize, usize> = HashMap::new();
+            while let Some((gx, gy)) = queue.pop_front() {
+                component.push(idx(gx, gy));
+                for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                    let (x, y) = (gx + dx, gy + dy);
+                    if x < 0 || y < 0 || x >= nx || y >= ny {
+                        continue;
+                    }
+                    let n = idx(x, y);
+                    if gem_grid[n] == color {
+                        if !visited[n] {
+                            visited[n] = true;
+                            queue.push_back((x, y));
+                        }
+                    } else if gem_grid[n] != EMPTY_CELL {
+                        *border_counts.entry(gem_grid[n]).or_insert(0) += 1;
+                    }
+                }
+            }
+
+            if color == EMPTY_CELL || component.len() > max_component_size || border_counts.is_empty() {
+                continue;
+            }
+
+            let own_lab = palette_lab(color);
+            // Most frequent neighbour wins; ties go to the closer color, then the lower index
+            let (dominant, _, cost) = border_counts
+                .iter()
+                .map(|(&c, &count)| (c, count, lab_distance(metric, own_lab, palette_lab(c))))
+                .max_by(|a, b| a.1.cmp(&b.1).then(b.2.total_cmp(&a.2)).then(b.0.cmp(&a.0)))
+                .unwrap();
+            if cost > max_delta_e {
+                continue;
+            }
+            for &cell in &component {
+                gem_grid[cell] = dominant;
+            }
+            changed += component.len();
+        }
+    }
+
+    Ok(changed)
+}
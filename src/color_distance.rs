@@ -0,0 +1,92 @@
+use deltae::{DEMethod, DeltaE, LabValue};
+use palette::{IntoColor, Lab, Oklab, Srgb};
+use crate::models::ColorMetric;
+
+/// Converts a CIE Lab color into the coordinate space that `metric_distance` expects
+/// for the given metric. Palette entries are converted once up front so each query
+/// only pays for a single conversion.
+pub fn to_metric_space(metric: ColorMetric, lab: [f32; 3]) -> [f32; 3] {
+    match metric {
+        ColorMetric::Cie76 | ColorMetric::Cie94 | ColorMetric::Ciede2000 => lab,
+        ColorMetric::Oklab => {
+            let oklab: Oklab = Lab::new(lab[0], lab[1], lab[2]).into_color();
+            [oklab.l, oklab.a, oklab.b]
+        }
+        ColorMetric::Redmean => {
+            let rgb: Srgb = Lab::new(lab[0], lab[1], lab[2]).into_color();
+            [
+                rgb.red.clamp(0.0, 1.0) * 255.0,
+                rgb.green.clamp(0.0, 1.0) * 255.0,
+                rgb.blue.clamp(0.0, 1.0) * 255.0,
+            ]
+        }
+    }
+}
+
+/// Distance between two colors already in the metric's space (see `to_metric_space`).
+/// Oklab and redmean results are rescaled to roughly the CIE76 range so they blend
+/// with L* differences the same way in the adaptive mapping modes.
+pub fn metric_distance(metric: ColorMetric, a: [f32; 3], b: [f32; 3]) -> f32 {
+    match metric {
+        ColorMetric::Cie76 => euclidean(a, b),
+        ColorMetric::Cie94 => cie94(a, b),
+        ColorMetric::Ciede2000 => {
+            let lab0 = LabValue { l: a[0], a: a[1], b: a[2] };
+            let lab1 = LabValue { l: b[0], a: b[1], b: b[2] };
+            DeltaE::new(lab0, lab1, DEMethod::DE2000).value
+        }
+        ColorMetric::Oklab => euclidean(a, b) * 100.0,
+        ColorMetric::Redmean => {
+            let r_mean = (a[0] + b[0]) / 2.0;
+            let dr = a[0] - b[0];
+            let dg = a[1] - b[1];
+            let db = a[2] - b[2];
+            let d = ((2.0 + r_mean / 256.0) * dr * dr + 4.0 * dg * dg + (2.0 + (255.0 - r_mean) / 256.0) * db * db).sqrt();
+            d / 3.0
+        }
+    }
+}
+
+/// Convenience wrapper for one-off comparisons between two Lab colors.
+pub fn lab_distance(metric: ColorMetric, a: [f32; 3], b: [f32; 3]) -> f32 {
+    metric_distance(metric, to_metric_space(metric, a), to_metric_space(metric, b))
+}
+
+/// Whether plain Euclidean distance in the metric space gives the same ordering,
+/// which lets callers use the kd-tree instead of a linear scan.
+pub fn is_euclidean(metric: ColorMetric) -> bool {
+    matches!(metric, ColorMetric::Cie76 | ColorMetric::Oklab)
+}
+
+/// Largest `k` with `metric_distance >= k * |ΔL*|` for any two colors, used to prune
+/// palette searches by lightness. CIEDE2000 divides ΔL* by at most about 1.75; Oklab and
+/// redmean don't measure L* directly, so they get no bound.
+pub fn lightness_bound_factor(metric: ColorMetric) -> f32 {
+    match metric {
+        ColorMetric::Cie76 | ColorMetric::Cie94 => 1.0,
+        ColorMetric::Ciede2000 => 0.57,
+        ColorMetric::Oklab | ColorMetric::Redmean => 0.0,
+    }
+}
+
+fn euclidean(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let d0 = a[0] - b[0];
+    let d1 = a[1] - b[1];
+    let d2 = a[2] - b[2];
+    (d0 * d0 + d1 * d1 + d2 * d2).sqrt()
+}
+
+// CIE94 with graphic arts weights. The hue term is clamped at zero because rounding
+// can push it slightly negative for colors of near-identical hue.
+fn cie94(reference: [f32; 3], sample: [f32; 3]) -> f32 {
+    let dl = reference[0] - sample[0];
+    let c1 = (reference[1] * reference[1] + reference[2] * reference[2]).sqrt();
+    let c2 = (sample[1] * sample[1] + sample[2] * sample[2]).sqrt();
+    let dc = c1 - c2;
+    let da = reference[1] - sample[1];
+    let db = reference[2] - sample[2];
+    let dh_sq = (da * da + db * db - dc * dc).max(0.0);
+    let s_c = 1.0 + 0.045 * c1;
+    let s_h = 1.0 + 0.015 * c1;
+    (dl * dl + (dc / s_c).powi(2) + dh_sq / (s_h * s_h)).sqrt()
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use palette::{IntoColor, Lab, Srgb};
+use crate::color_distance::{is_euclidean, to_metric_space};
+use crate::models::{ColorMappingMode, ColorMetric};
+use crate::progress::GenerationHooks;
+
+// Buckets per channel are 2^LUT_BITS, so each bucket covers 4 x 4 x 4 sRGB values.
+const LUT_BITS: u32 = 6;
+const BUCKET_SHIFT: u32 = 8 - LUT_BITS;
+const BUCKET_SIDE: usize = 1 << BUCKET_SHIFT;
+const COLORS_PER_BUCKET: usize = BUCKET_SIDE * BUCKET_SIDE * BUCKET_SIDE;
+
+const UNBUILT: u32 = u32::MAX;
+// Set on bucket entries that point into `mixed` instead of holding a palette index
+const MIXED: u32 = 1 << 31;
+
+// Allowance for f32 rounding in the distances compared by `uniform_entry`
+const ROUNDING_SLACK: f32 = 1e-3;
+
+/// Lazily built sRGB -> palette index table for plain nearest-color matching.
+///
+/// A bucket whose colors all share the nearest entry stores that entry. This relies on
+/// the triangle inequality, so it only happens for Euclidean metrics, and only when the
+/// gap between the two nearest entries at the bucket centre exceeds the bucket's
+/// diameter. Since a bucket holds just 64 colors, its radius is measured over all of
+/// them, which makes the table give exactly the direct search's answers. All other
+/// buckets remember exact per-color answers as they are needed.
+pub struct ColorLut {
+    buckets: Vec<u32>,
+    mixed: Vec<[u32; COLORS_PER_BUCKET]>,
+}
+
+impl Default for ColorLut {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ColorLut {
+    pub fn new() -> Self {
+        ColorLut { buckets: vec![UNBUILT; 1 << (3 * LUT_BITS)], mixed: Vec::new() }
+    }
+
+    /// Fills in every entry `colors` will look up. `metric_palette` holds the palette in
+    /// the metric's space (see `to_metric_space`) and `nearest` is the exact search for a
+    /// Lab color. Progress is reported to, and cancellation checked through, `hooks`; a
+    /// cancelled table stays valid, with the remaining entries left to a later call.
+    pub fn prepare(&mut self, colors: &[[u8; 3]], metric: ColorMetric, metric_palette: &[[f32; 3]], nearest: impl Fn([f32; 3]) -> usize + Sync + Send, hooks: &GenerationHooks) -> Result<(), String> {
+        let mut missing_buckets: Vec<usize> = colors.iter().map(|&rgb| bucket_of(rgb)).filter(|&b| self.buckets[b] == UNBUILT).collect();
+        missing_buckets.sort_unstable();
+        missing_buckets.dedup();
+        let built: Vec<(usize, Option<u32>)> = hooks.part(0.0, 0.5).par_map("Matching colors", missing_buckets.len(), |i| {
+            let bucket = missing_buckets[i];
+            (bucket, uniform_entry(bucket, metric, metric_palette))
+        })?;
+        for (bucket, entry) in built {
+            self.buckets[bucket] = match entry {
+                Some(index) => index,
+                None => {
+                    self.mixed.push([UNBUILT; COLORS_PER_BUCKET]);
+                    MIXED | (self.mixed.len() - 1) as u32
+                }
+            };
+        }
+
+        let mut missing_colors: Vec<[u8; 3]> = colors
+            .iter()
+            .copied()
+            .filter(|&rgb| {
+                let entry = self.buckets[bucket_of(rgb)];
+                entry & MIXED != 0 && self.mixed[(entry & !MIXED) as usize][offset_of(rgb)] == UNBUILT
+            })
+            .collect();
+        missing_colors.sort_unstable();
+        missing_colors.dedup();
+        let found: Vec<([u8; 3], u32)> = hooks.part(0.5, 1.0).par_map("Matching colors", missing_colors.len(), |i| {
+            let rgb = missing_colors[i];
+            (rgb, nearest(rgb_to_lab(rgb)) as u32)
+        })?;
+        for (rgb, index) in found {
+            let entry = self.buckets[bucket_of(rgb)];
+            self.mixed[(entry & !MIXED) as usize][offset_of(rgb)] = index;
+        }
+        Ok(())
+    }
+
+    /// Palette index for a color passed to `prepare` beforehand.
+    pub fn get(&self, rgb: [u8; 3]) -> usize {
+        let entry = self.buckets[bucket_of(rgb)];
+        let index = if entry & MIXED != 0 { self.mixed[(entry & !MIXED) as usize][offset_of(rgb)] } else { entry };
+        debug_assert!(index != UNBUILT, "color lookup before prepare");
+        index as usize
+    }
+}
+
+fn bucket_of(rgb: [u8; 3]) -> usize {
+    let [r, g, b] = rgb.map(|c| (c >> BUCKET_SHIFT) as usize);
+    (r << (2 * LUT_BITS)) | (g << LUT_BITS) | b
+}
+
+fn offset_of(rgb: [u8; 3]) -> usize {
+    let mask = BUCKET_SIDE as u8 - 1;
+    let [r, g, b] = rgb.map(|c| (c & mask) as usize);
+    (r * BUCKET_SIDE + g) * BUCKET_SIDE + b
+}
+
+fn rgb_to_lab(rgb: [u8; 3]) -> [f32; 3] {
+    rgb_f32_to_lab(rgb.map(|c| c as f32))
+}
+
+fn rgb_f32_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let lab: Lab = Srgb::new(rgb[0] / 255.0, rgb[1] / 255.0, rgb[2] / 255.0).into_color();
+    [lab.l, lab.a, lab.b]
+}
+
+/// The palette entry that every color of a bucket shares, if the bucket's radius around
+/// its centre proves it.
+fn uniform_entry(bucket: usize, metric: ColorMetric, metric_palette: &[[f32; 3]]) -> Option<u32> {
+    if !is_euclidean(metric) || metric_palette.len() < 2 {
+        return None;
+    }
+    let side = (1usize << LUT_BITS) - 1;
+    let low = [bucket >> (2 * LUT_BITS), (bucket >> LUT_BITS) & side, bucket & side].map(|c| (c << BUCKET_SHIFT) as f32);
+    let half_span = (BUCKET_SIDE - 1) as f32 / 2.0;
+    let to_space = |rgb: [f32; 3]| to_metric_space(metric, rgb_f32_to_lab(rgb));
+    let center = to_space(low.map(|c| c + half_span));
+    // Lookups only ever see the bucket's own 8-bit colors, so its radius is the largest
+    // distance to one of them
+    let radius = (0..COLORS_PER_BUCKET)
+        .map(|offset| {
+            let steps = [offset / (BUCKET_SIDE * BUCKET_SIDE), offset / BUCKET_SIDE % BUCKET_SIDE, offset % BUCKET_SIDE];
+            let rgb = [0, 1, 2].map(|axis| low[axis] + steps[axis] as f32);
+            distance(center, to_space(rgb))
+        })
+        .fold(0.0f32, f32::max);
+
+    let (mut first, mut second) = ((f32::INFINITY, 0), f32::INFINITY);
+    for (i, point) in metric_palette.iter().enumerate() {
+        let d = distance(center, *point);
+        if d < first.0 {
+            second = first.0;
+            first = (d, i);
+        } else if d < second {
+            second = d;
+        }
+    }
+    // Any color of the bucket is within `radius` of the centre, so it is closer to the
+    // first entry than to any other once the gap exceeds twice that
+    (second - first.0 > 2.0 * radius + ROUNDING_SLACK).then_some(first.1 as u32)
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Which palette, metric and mapping mode a cached table was built for.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct LutKey {
+    pub flosses: Vec<String>,
+    pub metric: ColorMetric,
+    pub mapping_mode: ColorMappingMode,
+}
+
+/// Tables kept between generations, keyed by what they were built for. Only the most
+/// recent few are kept, since a full table takes a few megabytes.
+///
+/// A table is taken out while a generation uses it and put back afterwards, so the cache
+/// is only locked briefly. A concurrent generation with the same key starts from an empty
+/// table instead of waiting.
+#[derive(Default)]
+pub struct LutCache {
+    tables: HashMap<LutKey, (u64, ColorLut)>,
+    clock: u64,
+}
+
+const LUT_CACHE_SIZE: usize = 2;
+
+impl LutCache {
+    /// Removes and returns the table for `key`, or an empty one if there is none.
+    pub fn take(&mut self, key: &LutKey) -> ColorLut {
+        self.tables.remove(key).map(|(_, table)| table).unwrap_or_default()
+    }
+
+    /// Stores `table` for `key`, evicting the least recently used table if needed.
+    pub fn insert(&mut self, key: LutKey, table: ColorLut) {
+        self.clock += 1;
+        if !self.tables.contains_key(&key) && self.tables.len() >= LUT_CACHE_SIZE {
+            if let Some(oldest) = self.tables.iter().min_by_key(|(_, (used, _))| *used).map(|(k, _)| k.clone()) {
+                self.tables.remove(&oldest);
+            }
+        }
+        self.tables.insert(key, (self.clock, table));
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use yew::prelude::*;
+use web_sys::HtmlInputElement;
+use crate::dmc_colors::DmcColor;
+use crate::models::{Color, GenerationSettings, ImageFitOption, TunedConfiguration, TuningConstraints};
+use crate::worker::{GenerationWorker, WorkerResponse};
+
+#[derive(Properties, PartialEq)]
+pub struct AutoTunePanelProps {
+    pub image_data: String,
+    /// Colors the tuner may pick from, normally the current selection.
+    pub allowed_colors: Vec<Color>,
+    pub generation_settings: GenerationSettings,
+    pub dmc_colors: UseStateHandle<Vec<DmcColor>>,
+    pub selected_dmc_colors: UseStateHandle<HashSet<String>>,
+    pub gem_size_mm: UseStateHandle<f32>,
+    pub image_fit_option: UseStateHandle<ImageFitOption>,
+    pub custom_width_mm: UseStateHandle<Option<f32>>,
+    pub custom_height_mm: UseStateHandle<Option<f32>>,
+    pub generation_worker: GenerationWorker,
+}
+
+fn optional_value(value: Option<impl ToString>) -> String {
+    value.map_or(String::new(), |v| v.to_string())
+}
+
+/// Finds the gem size, fit and palette size that give the best pattern within the
+/// customer's limits, and applies it to the settings and color selection. The search
+/// runs in the generation worker and can be cancelled.
+#[function_component(AutoTunePanel)]
+pub fn auto_tune_panel(props: &AutoTunePanelProps) -> Html {
+    let constraints = use_state(TuningConstraints::default);
+    let result = use_state::<Option<Result<TunedConfiguration, String>>, _>(|| None);
+    // Stage and fraction of the running search, `None` when idle
+    let progress = use_state::<Option<(String, f32)>, _>(|| None);
+    let job = use_mut_ref(|| None::<u32>);
+
+    {
+        // A search still running when the panel goes away is of no use
+        let job = job.clone();
+        let worker = props.generation_worker.clone();
+        use_effect_with_deps(
+            move |_| {
+                move || {
+                    if let Some(job) = job.borrow_mut().take() {
+                        worker.cancel(job);
+                    }
+                }
+            },
+            (),
+        );
+    }
+
+    let field = |id: &'static str, label: &'static str, value: String, apply: fn(&mut TuningConstraints, &str)| {
+        let constraints = constraints.clone();
+        let onchange = Callback::from(move |e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            let mut updated = (*constraints).clone();
+            apply(&mut updated, input.value().trim());
+            constraints.set(updated);
+        });
+        html! {
+            <div class={classes!("adjustment-row")}>
+                <label for={id}>{ label }</label>
+                <input type="number" id={id} min="0" {value} {onchange} />
+            </div>
+        }
+    };
+
+    let on_tune = {
+        let props_image_data = props.image_data.clone();
+        let allowed_colors = props.allowed_colors.clone();
+        let settings = props.generation_settings.clone();
+        let constraints = constraints.clone();
+        let result = result.clone();
+        let dmc_colors = props.dmc_colors.clone();
+        let selected_dmc_colors = props.selected_dmc_colors.clone();
+        let gem_size_mm = props.gem_size_mm.clone();
+        let image_fit_option = props.image_fit_option.clone();
+        let custom_width_mm = props.custom_width_mm.clone();
+        let custom_height_mm = props.custom_height_mm.clone();
+        let progress = progress.clone();
+        let job = job.clone();
+        let worker = props.generation_worker.clone();
+        Callback::from(move |_: MouseEvent| {
+            let (result, progress) = (result.clone(), progress.clone());
+            let (dmc_colors, selected_dmc_colors) = (dmc_colors.clone(), selected_dmc_colors.clone());
+            let (gem_size_mm, image_fit_option) = (gem_size_mm.clone(), image_fit_option.clone());
+            let (custom_width_mm, custom_height_mm) = (custom_width_mm.clone(), custom_height_mm.clone());
+            progress.set(Some(("Preparing image".to_string(), 0.0)));
+            let queued = worker.auto_tune(&props_image_data, &allowed_colors, &settings, &constraints, move |response, _| match response {
+                WorkerResponse::Progress { stage, fraction, .. } => progress.set(Some((stage, fraction))),
+                WorkerResponse::Tuned { configuration: tuned, .. } => {
+                    progress.set(None);
+                    gem_size_mm.set(tuned.settings.gem_size_mm);
+                    image_fit_option.set(tuned.settings.fit_option.clone());
+                    custom_width_mm.set(tuned.settings.custom_width_mm);
+                    custom_height_mm.set(tuned.settings.custom_height_mm);
+                    // Precomputed floss numbers may differ from the CSV ones in surrounding whitespace
+                    let new_selection: HashSet<String> = dmc_colors
+                        .iter()
+                        .filter(|c| tuned.flosses.iter().any(|floss| floss.trim() == c.floss.trim()))
+                        .map(|c| c.floss.clone())
+                        .collect();
+                    selected_dmc_colors.set(new_selection);
+                    result.set(Some(Ok(tuned)));
+                }
+                WorkerResponse::TuneFailed { error, .. } => {
+                    progress.set(None);
+                    result.set(Some(Err(error)));
+                }
+                _ => {}
+            });
+            *job.borrow_mut() = Some(queued);
+        })
+    };
+
+    let on_cancel = {
+        let progress = progress.clone();
+        let worker = props.generation_worker.clone();
+        Callback::from(move |_: MouseEvent| {
+            if let Some(job) = job.borrow_mut().take() {
+                worker.cancel(job);
+            }
+            progress.set(None);
+        })
+    };
+
+    html! {
+        <div class={classes!("section", "auto-tune")}>
+            <label>{ "Auto-tune to limits" }</label>
+            { field("tune_max_colors", "Max colors", constraints.max_colors.to_string(), |c, v| {
+                c.max_colors = v.parse().unwrap_or(c.max_colors).max(1);
+            }) }
+            { field("tune_max_gems", "Max gems", optional_value(constraints.max_gems), |c, v| c.max_gems = v.parse().ok()) }
+            { field("tune_canvas_width", "Canvas width (mm)", constraints.canvas_width_mm.to_string(), |c, v| {
+                c.canvas_width_mm = v.parse().unwrap_or(c.canvas_width_mm);
+            }) }
+            { field("tune_canvas_height", "Canvas height (mm)", constraints.canvas_height_mm.to_string(), |c, v| {
+                c.canvas_height_mm = v.parse().unwrap_or(c.canvas_height_mm);
+            }) }
+            { field("tune_cost_per_gem", "Cost per gem", constraints.cost_per_gem.to_string(), |c, v| {
+                c.cost_per_gem = v.parse().unwrap_or(0.0);
+            }) }
+            { field("tune_cost_per_color", "Cost per color", constraints.cost_per_color.to_string(), |c, v| {
+                c.cost_per_color = v.parse().unwrap_or(0.0);
+            }) }
+            { field("tune_max_cost", "Max cost", optional_value(constraints.max_cost), |c, v| c.max_cost = v.parse().ok()) }
+            <div class={classes!("mask-controls")}>
+                <button onclick={on_tune} disabled={props.allowed_colors.is_empty() || progress.is_some()}>{ "Find best settings" }</button>
+                { if progress.is_some() { html! { <button onclick={on_cancel}>{ "Cancel" }</button> } } else { html! {} } }
+            </div>
+            { if let Some((stage, fraction)) = (*progress).as_ref() { html! {
+                <div class={classes!("tune-progress")}>
+                    <progress max="1" value={fraction.to_string()}></progress>
+                    <span>{ stage }</span>
+                </div>
+            } } else { html! {} } }
+            { match (*result).as_ref() {
+                Some(Ok(tuned)) => html! {
+                    <p>{ format!(
+                        "Applied {} mm gems ({}), {} gems in {} colors, cost {:.2}, score ΔE {:.2}",
+                        tuned.settings.gem_size_mm,
+                        if tuned.settings.fit_option == ImageFitOption::Fit { "fit" } else { "crop" },
+                        tuned.total_gems,
+                        tuned.colors_used,
+                        tuned.cost,
+                        tuned.score,
+                    ) }</p>
+                },
+                Some(Err(e)) => html! { <p>{ e }</p> },
+                None => html! {},
+            } }
+        </div>
+    }
+}
@@ -1,4 +1,5 @@
 use yew::prelude::*;
+use web_sys::HtmlInputElement;
 use std::collections::HashSet;
 use crate::dmc_colors::DmcColor;
 
@@ -12,6 +13,11 @@ pub struct ColorSelectionPanelProps {
     pub on_select_all_click: Callback<MouseEvent>,
     pub on_deselect_all_click: Callback<MouseEvent>,
     pub on_dmc_color_click: Callback<String>,
+    pub auto_select_count: UseStateHandle<usize>,
+    pub on_auto_select_click: Callback<MouseEvent>,
+    pub auto_select_disabled: bool,
+    /// Why the last auto-select failed, if it did.
+    pub auto_select_error: Option<String>,
 }
 
 #[function_component(ColorSelectionPanel)]
@@ -27,7 +33,21 @@ pub fn color_selection_panel(props: &ColorSelectionPanelProps) -> Html {
                     <button onclick={props.on_select_all_click.clone()}>{ "Select All" }</button>
                     <button onclick={props.on_deselect_all_click.clone()}>{ "Deselect All" }</button>
                 </div>
+                <div class={classes!("select-buttons")}>
+                    <input type="number" id="auto_select_count" min="1" value={props.auto_select_count.to_string()} onchange={{
+                        let auto_select_count = props.auto_select_count.clone();
+                        Callback::from(move |e: Event| {
+                            let input: HtmlInputElement = e.target_unchecked_into();
+                            auto_select_count.set(input.value().parse::<usize>().unwrap_or(20).max(1));
+                        })
+                    }} />
+                    <button onclick={props.on_auto_select_click.clone()} disabled={props.auto_select_disabled}>{ "Auto-select" }</button>
+                </div>
             </div>
+            { match props.auto_select_error.as_ref() {
+                Some(error) => html! { <p>{ format!("Auto-select failed: {}", error) }</p> },
+                None => html! {},
+            } }
             <div class={classes!("color-grid")}>
                 { for {
                     let mut sorted_dmc_colors = (*props.dmc_colors).clone();
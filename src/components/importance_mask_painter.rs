@@ -0,0 +1,52 @@
+use yew::prelude::*;
+use web_sys::HtmlInputElement;
+use crate::components::MaskPainter;
+
+#[derive(Properties, PartialEq)]
+pub struct ImportanceMaskPainterProps {
+    pub image_data: String,
+    pub importance_mask: UseStateHandle<Option<String>>,
+}
+
+/// Section for painting the importance mask: white strokes mark areas whose colors
+/// should be matched most accurately.
+#[function_component(ImportanceMaskPainter)]
+pub fn importance_mask_painter(props: &ImportanceMaskPainterProps) -> Html {
+    let is_open = use_state(|| false);
+    let on_mask_change = {
+        let importance_mask = props.importance_mask.clone();
+        Callback::from(move |mask: Option<String>| importance_mask.set(mask))
+    };
+
+    html! {
+        <div class={classes!("section", "importance-mask")}>
+            <div class={classes!("mask-controls")}>
+                <input type="checkbox" id="paint_importance_mask" checked={*is_open} onchange={{
+                    let is_open = is_open.clone();
+                    Callback::from(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        is_open.set(input.checked());
+                    })
+                }} />
+                <label for="paint_importance_mask">{ "Paint important areas" }</label>
+                { if !*is_open && props.importance_mask.is_some() {
+                    html! { <span>{ "(mask active)" }</span> }
+                } else {
+                    html! {}
+                } }
+            </div>
+            { if *is_open {
+                html! {
+                    <MaskPainter
+                        id="importance_mask"
+                        image_data={props.image_data.clone()}
+                        mask={(*props.importance_mask).clone()}
+                        {on_mask_change}
+                    />
+                }
+            } else {
+                html! {}
+            } }
+        </div>
+    }
+}
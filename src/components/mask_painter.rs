@@ -0,0 +1,160 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use yew::prelude::*;
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, HtmlImageElement, HtmlInputElement};
+
+#[derive(Properties, PartialEq)]
+pub struct MaskPainterProps {
+    /// Prefix for the element ids of the brush controls.
+    pub id: AttrValue,
+    pub image_data: String,
+    pub mask: Option<String>,
+    pub on_mask_change: Callback<Option<String>>,
+}
+
+fn canvas_context(canvas: &HtmlCanvasElement) -> CanvasRenderingContext2d {
+    canvas
+        .get_context("2d")
+        .unwrap()
+        .unwrap()
+        .dyn_into::<CanvasRenderingContext2d>()
+        .unwrap()
+}
+
+/// Lets the user paint a white mask over the source image. The canvas is handed to
+/// `on_mask_change` as a PNG data URL after every stroke.
+#[function_component(MaskPainter)]
+pub fn mask_painter(props: &MaskPainterProps) -> Html {
+    let brush_size = use_state(|| 5.0f64);
+    let erasing = use_state(|| false);
+    let is_painting = use_mut_ref(|| false);
+    let canvas_ref = use_node_ref();
+
+    // Size the canvas to the source image and restore any existing mask onto it
+    let on_image_load = {
+        let canvas_ref = canvas_ref.clone();
+        let mask = props.mask.clone();
+        Callback::from(move |e: Event| {
+            let image: HtmlImageElement = e.target_unchecked_into();
+            let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() else {
+                return;
+            };
+            canvas.set_width(image.natural_width());
+            canvas.set_height(image.natural_height());
+            if let Some(mask) = mask.as_ref() {
+                let context = canvas_context(&canvas);
+                let mask_image = HtmlImageElement::new().unwrap();
+                let mask_image_clone = mask_image.clone();
+                let onload = Closure::wrap(Box::new(move || {
+                    context.draw_image_with_html_image_element(&mask_image_clone, 0.0, 0.0).unwrap();
+                }) as Box<dyn FnMut()>);
+                mask_image.set_onload(Some(onload.as_ref().unchecked_ref()));
+                onload.forget();
+                mask_image.set_src(mask);
+            }
+        })
+    };
+
+    let paint = {
+        let canvas_ref = canvas_ref.clone();
+        let brush_size = brush_size.clone();
+        let erasing = erasing.clone();
+        move |e: &MouseEvent| {
+            let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() else {
+                return;
+            };
+            if canvas.client_width() == 0 {
+                return;
+            }
+            // Brush size is a percentage of the longer image side
+            let scale = canvas.width() as f64 / canvas.client_width() as f64;
+            let radius = *brush_size / 100.0 * canvas.width().max(canvas.height()) as f64 / 2.0;
+            let context = canvas_context(&canvas);
+            context
+                .set_global_composite_operation(if *erasing { "destination-out" } else { "source-over" })
+                .unwrap();
+            context.set_fill_style(&JsValue::from_str("#ffffff"));
+            context.begin_path();
+            context
+                .arc(e.offset_x() as f64 * scale, e.offset_y() as f64 * scale, radius, 0.0, std::f64::consts::TAU)
+                .unwrap();
+            context.fill();
+        }
+    };
+
+    let on_mouse_down = {
+        let is_painting = is_painting.clone();
+        let paint = paint.clone();
+        Callback::from(move |e: MouseEvent| {
+            *is_painting.borrow_mut() = true;
+            paint(&e);
+        })
+    };
+    let on_mouse_move = {
+        let is_painting = is_painting.clone();
+        Callback::from(move |e: MouseEvent| {
+            if *is_painting.borrow() {
+                paint(&e);
+            }
+        })
+    };
+    let on_stroke_end = {
+        let is_painting = is_painting.clone();
+        let canvas_ref = canvas_ref.clone();
+        let on_mask_change = props.on_mask_change.clone();
+        Callback::from(move |_: MouseEvent| {
+            if !std::mem::replace(&mut *is_painting.borrow_mut(), false) {
+                return;
+            }
+            if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                on_mask_change.emit(canvas.to_data_url().ok());
+            }
+        })
+    };
+    let on_clear = {
+        let canvas_ref = canvas_ref.clone();
+        let on_mask_change = props.on_mask_change.clone();
+        Callback::from(move |_: MouseEvent| {
+            if let Some(canvas) = canvas_ref.cast::<HtmlCanvasElement>() {
+                canvas_context(&canvas).clear_rect(0.0, 0.0, canvas.width() as f64, canvas.height() as f64);
+            }
+            on_mask_change.emit(None);
+        })
+    };
+
+    let brush_id = format!("{}_brush_size", props.id);
+    let erase_id = format!("{}_erase", props.id);
+    html! {
+        <>
+            <div class={classes!("mask-controls")}>
+                <label for={brush_id.clone()}>{ "Brush" }</label>
+                <input type="range" id={brush_id} min="1" max="20" step="1" value={brush_size.to_string()} onchange={{
+                    let brush_size = brush_size.clone();
+                    Callback::from(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        brush_size.set(input.value().parse::<f64>().unwrap_or(5.0).clamp(1.0, 20.0));
+                    })
+                }} />
+                <input type="checkbox" id={erase_id.clone()} checked={*erasing} onchange={{
+                    let erasing = erasing.clone();
+                    Callback::from(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        erasing.set(input.checked());
+                    })
+                }} />
+                <label for={erase_id}>{ "Erase" }</label>
+                <button onclick={on_clear} disabled={props.mask.is_none()}>{ "Clear" }</button>
+            </div>
+            <div class={classes!("mask-painter")}>
+                <img src={props.image_data.clone()} onload={on_image_load} />
+                <canvas
+                    ref={canvas_ref}
+                    onmousedown={on_mouse_down}
+                    onmousemove={on_mouse_move}
+                    onmouseup={on_stroke_end.clone()}
+                    onmouseleave={on_stroke_end}
+                />
+            </div>
+        </>
+    }
+}
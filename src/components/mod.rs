@@ -1,25 +1,80 @@
 use wasm_bindgen::prelude::*;
 use yew::prelude::*;
-use web_sys::{HtmlCanvasElement, CanvasRenderingContext2d};
+use web_sys::{HtmlCanvasElement, CanvasRenderingContext2d, ImageData};
+use std::rc::Rc;
 use std::collections::HashSet;
-use crate::dmc_colors;
-use crate::image_processing::{generate_gem_art_preview, generate_gem_art_final, generate_text_image, GemArtData};
-use crate::models::{Color, GemCount, ImageFitOption, ColorMappingMode};
+use crate::dmc_colors::{self, DmcColor};
+use crate::image_processing::{generate_text_image, GemArtData};
+use crate::worker::{GenerationWorker, WorkerResponse};
+use crate::models::{Color, GemCount, ImageFitOption, BackgroundMode, CanvasShape, ColorMappingMode, ColorMetric, DitheringMode, GenerationSettings, ImageAdjustments, PaletteRegion, ResampleFilter, EMPTY_CELL};
+use crate::quality::heatmap_color;
 
 mod help_modal;
 mod file_input_buttons;
 mod settings_panel;
 mod color_selection_panel;
 mod gem_counts_display;
+mod mask_painter;
+mod importance_mask_painter;
+mod palette_regions_panel;
+mod palette_advisor;
+mod quality_metrics_display;
+mod auto_tune_panel;
 use help_modal::HelpModal;
 use file_input_buttons::FileInputButtons;
 use settings_panel::SettingsPanel;
 use color_selection_panel::ColorSelectionPanel;
 use gem_counts_display::GemCountsDisplay;
+use mask_painter::MaskPainter;
+use importance_mask_painter::ImportanceMaskPainter;
+use palette_regions_panel::PaletteRegionsPanel;
+use palette_advisor::PaletteAdvisor;
+use quality_metrics_display::QualityMetricsDisplay;
+use auto_tune_panel::AutoTunePanel;
+
+/// Saves `href` (a data or object URL) under `filename` through a temporary link.
+fn download_data_url(href: &str, filename: &str) {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let link = document.create_element("a").unwrap();
+    let link: web_sys::HtmlAnchorElement = link.dyn_into().unwrap();
+    link.set_href(href);
+    link.set_download(filename);
+    link.click();
+}
+
+/// Saves `bytes` under `filename` through an object URL, without copying them into
+/// a string first.
+fn download_bytes(bytes: &js_sys::Uint8Array, mime_type: &str, filename: &str) {
+    let blob = gloo_file::Blob::new_with_options(bytes.buffer(), Some(mime_type));
+    let url = gloo_file::ObjectUrl::from(blob);
+    download_data_url(&url, filename);
+    // Revoked once the browser has had time to start the download
+    wasm_bindgen_futures::spawn_local(async move {
+        gloo_timers::future::TimeoutFuture::new(10_000).await;
+        drop(url);
+    });
+}
+
+fn colors_for_selection(dmc_colors: &[DmcColor], selected_dmc_colors: &HashSet<String>) -> Vec<Color> {
+    selected_dmc_colors
+        .iter()
+        .filter_map(|floss| {
+            dmc_colors.iter().find(|dmc_color| &dmc_color.floss == floss)
+        })
+        .map(|dmc_color| Color {
+            value: "#".to_string() + &dmc_color.hex,
+            floss_number: dmc_color.floss.clone(),
+            r: dmc_color.r,
+            g: dmc_color.g,
+            b: dmc_color.b,
+            hex: dmc_color.hex.clone(),
+        })
+        .collect()
+}
 
 #[function_component(App)]
 pub fn app() -> Html {
-    let dmc_colors = use_state(|| dmc_colors::get_dmc_colors());
+    let dmc_colors = use_state(dmc_colors::get_dmc_colors);
     let selected_dmc_colors = use_state(|| {
         dmc_colors::get_dmc_colors().into_iter().map(|c| c.floss).collect::<HashSet<String>>()
     });
@@ -33,7 +88,35 @@ pub fn app() -> Html {
     let gem_size_mm = use_state(|| 2.7);
     let color_mapping_mode = use_state(|| ColorMappingMode::AdaptiveLightnessWeighted);
     let mapping_weight = use_state(|| 0.0f32);
+    let adjustments = use_state(ImageAdjustments::default);
+    let denoise_strength = use_state(|| 0.0f32);
+    let denoise_radius = use_state(|| 2u32);
+    let sharpen_strength = use_state(|| 0.0f32);
+    let sharpen_radius = use_state(|| 2.0f32);
+    // Deliberately not `GenerationSettings::default()`'s `Nearest` (see there)
+    let resample_filter = use_state(|| ResampleFilter::AreaAverage);
+    let color_metric = use_state(|| ColorMetric::Cie76);
+    let dithering_mode = use_state(|| DitheringMode::None);
+    let dither_strength = use_state(|| 1.0f32);
+    let adaptive_dithering = use_state(|| false);
+    let min_gems_per_color = use_state(|| 0u32);
+    let confetti_max_size = use_state(|| 0u32);
+    let confetti_max_delta_e = use_state(|| 10.0f32);
+    let importance_mask = use_state::<Option<String>, _>(|| None);
+    let palette_regions = use_state::<Vec<PaletteRegion>, _>(Vec::new);
+    let background_mode = use_state(|| BackgroundMode::Off);
+    let background_key_color = use_state(|| "#ffffff".to_string());
+    let background_tolerance = use_state(|| 10.0f32);
+    let background_floss = use_state::<Option<String>, _>(|| None);
+    let show_background = use_state(|| true);
+    let alpha_threshold = use_state(|| 0.5f32);
+    let canvas_shape = use_state(|| CanvasShape::Rectangle);
+    let custom_shape_mask = use_state::<Option<String>, _>(|| None);
+    let show_heatmap = use_state(|| false);
     let show_birthday_banner = use_state(|| false);
+    let auto_select_count = use_state(|| 20usize);
+    let auto_select_running = use_state(|| false);
+    let auto_select_error = use_state::<Option<String>, _>(|| None);
 
     let on_sort_by_color_click = {
         let sort_by_number = sort_by_number.clone();
@@ -107,10 +190,13 @@ pub fn app() -> Html {
     };
     let image_file = use_state::<Option<gloo_file::File>, _>(|| None);
     let image_data = use_state::<Option<String>, _>(|| None);
-    let generated_image_data = use_state::<Option<String>, _>(|| None);
-    let gem_counts = use_state::<Vec<GemCount>, _>(|| vec![]);
+    // Preview pixels, painted straight onto the canvas
+    let preview_image = use_state::<Option<Rc<image::RgbaImage>>, _>(|| None);
+    let gem_counts = use_state::<Vec<GemCount>, _>(Vec::new);
     let reader = use_state::<Option<gloo_file::callbacks::FileReader>, _>(|| None);
     let gem_art_data_state = use_state::<Option<GemArtData>, _>(|| None);
+    // Stage name and fraction done of the job the generation worker is running
+    let generation_progress = use_state::<Option<(String, f32)>, _>(|| None);
 
     let file_input_ref = use_node_ref();
 
@@ -131,16 +217,21 @@ pub fn app() -> Html {
     let on_file_change = {
         let image_file = image_file.clone();
         let image_data = image_data.clone();
+        let importance_mask = importance_mask.clone();
+        let palette_regions = palette_regions.clone();
         let reader = reader.clone();
         Callback::from(move |e: Event| {
             let input: web_sys::HtmlInputElement = e.target_unchecked_into();
             if let Some(files) = input.files() {
                 if let Some(file) = files.get(0) {
-                    let file = gloo_file::File::from(web_sys::File::from(file));
+                    let file = gloo_file::File::from(file);
                     let image_data = image_data.clone();
                     let task = gloo_file::callbacks::read_as_data_url(&file, move |res| {
                         image_data.set(Some(res.unwrap()));
                     });
+                    // Masks painted on the previous image don't line up with the new one
+                    importance_mask.set(None);
+                    palette_regions.set(Vec::new());
                     reader.set(Some(task));
                     image_file.set(Some(file));
                 }
@@ -148,56 +239,130 @@ pub fn app() -> Html {
         })
     };
 
-    let generated_image_data_for_effect = generated_image_data.clone();
+    let preview_image_for_effect = preview_image.clone();
     let gem_counts_for_effect = gem_counts.clone();
     let dmc_colors_for_effect = dmc_colors.clone();
     let gem_art_data_state_for_effect = gem_art_data_state.clone();
+    let generation_worker = use_state(GenerationWorker::new);
+    let on_preview_response = {
+        let preview_image = preview_image.clone();
+        let gem_counts = gem_counts.clone();
+        let gem_art_data_state = gem_art_data_state.clone();
+        let generation_progress = generation_progress.clone();
+        move |response: WorkerResponse, payload: Option<js_sys::Uint8Array>| match response {
+            WorkerResponse::Progress { stage, fraction, .. } => generation_progress.set(Some((stage, fraction))),
+            WorkerResponse::Preview { counts, gem_art_data, width, height, .. } => {
+                generation_progress.set(None);
+                preview_image.set(payload.and_then(|pixels| image::RgbaImage::from_raw(width, height, pixels.to_vec())).map(Rc::new));
+                gem_counts.set(counts);
+                gem_art_data_state.set(Some(gem_art_data));
+            }
+            WorkerResponse::PreviewFailed { .. } => {
+                generation_progress.set(None);
+                preview_image.set(None);
+                gem_counts.set(vec![]);
+                gem_art_data_state.set(None);
+            }
+            _ => {}
+        }
+    };
+    let generation_worker_for_effect = generation_worker.clone();
+    let generation_progress_for_effect = generation_progress.clone();
+    let generation_settings = GenerationSettings {
+        margin_mm: *margin_mm,
+        fit_option: (*image_fit_option).clone(),
+        mapping_mode: (*color_mapping_mode).clone(),
+        mapping_weight: *mapping_weight,
+        custom_width_mm: *custom_width_mm,
+        custom_height_mm: *custom_height_mm,
+        gem_size_mm: *gem_size_mm,
+        adjustments: (*adjustments).clone(),
+        denoise_strength: *denoise_strength,
+        denoise_radius: *denoise_radius,
+        sharpen_strength: *sharpen_strength,
+        sharpen_radius: *sharpen_radius,
+        resample_filter: *resample_filter,
+        color_metric: *color_metric,
+        dithering_mode: *dithering_mode,
+        dither_strength: *dither_strength,
+        adaptive_dithering: *adaptive_dithering,
+        color_lut: true,
+        min_gems_per_color: *min_gems_per_color,
+        confetti_max_size: *confetti_max_size,
+        confetti_max_delta_e: *confetti_max_delta_e,
+        importance_mask: (*importance_mask).clone(),
+        palette_regions: (*palette_regions).clone(),
+        background_mode: *background_mode,
+        background_key_color: (*background_key_color).clone(),
+        background_tolerance: *background_tolerance,
+        background_floss: (*background_floss).clone(),
+        alpha_threshold: *alpha_threshold,
+        canvas_shape: *canvas_shape,
+        custom_shape_mask: (*custom_shape_mask).clone(),
+    };
+    let on_auto_select_click = {
+        let image_data = image_data.clone();
+        let dmc_colors = dmc_colors.clone();
+        let selected_dmc_colors = selected_dmc_colors.clone();
+        let auto_select_count = auto_select_count.clone();
+        let auto_select_running = auto_select_running.clone();
+        let auto_select_error = auto_select_error.clone();
+        let generation_settings = generation_settings.clone();
+        let generation_worker = generation_worker.clone();
+        Callback::from(move |_| {
+            let Some(image_data) = (*image_data).as_ref() else {
+                return;
+            };
+            let allowed_colors = colors_for_selection(&dmc_colors, &selected_dmc_colors);
+            auto_select_running.set(true);
+            auto_select_error.set(None);
+            let (dmc_colors, selected_dmc_colors) = (dmc_colors.clone(), selected_dmc_colors.clone());
+            let (auto_select_running, auto_select_error) = (auto_select_running.clone(), auto_select_error.clone());
+            generation_worker.auto_select_colors(image_data, &allowed_colors, *auto_select_count, &generation_settings, move |response, _| match response {
+                WorkerResponse::AutoSelected { flosses: chosen, .. } => {
+                    auto_select_running.set(false);
+                    // Precomputed floss numbers may differ from the CSV ones in surrounding whitespace
+                    let new_selection: HashSet<String> = dmc_colors
+                        .iter()
+                        .filter(|c| chosen.iter().any(|floss| floss.trim() == c.floss.trim()))
+                        .map(|c| c.floss.clone())
+                        .collect();
+                    selected_dmc_colors.set(new_selection);
+                }
+                WorkerResponse::AutoSelectFailed { error, .. } => {
+                    auto_select_running.set(false);
+                    auto_select_error.set(Some(error));
+                }
+                _ => {}
+            });
+        })
+    };
+
     use_effect_with_deps(
-        move |(image_data, selected_dmc_colors, margin_mm, image_fit_option, custom_width_mm, custom_height_mm, gem_size_mm, color_mapping_mode, mapping_weight)| {
-            let current_dmc_colors = dmc_colors_for_effect.clone();
-            let colors_for_generation: Vec<Color> = selected_dmc_colors
-                .iter()
-                .filter_map(|floss| {
-                    current_dmc_colors.iter().find(|dmc_color| &dmc_color.floss == floss)
-                })
-                .map(|dmc_color| Color {
-                    value: "#".to_string() + &dmc_color.hex,
-                    floss_number: dmc_color.floss.clone(),
-                    r: dmc_color.r,
-                    g: dmc_color.g,
-                    b: dmc_color.b,
-                    hex: dmc_color.hex.clone(),
-                })
-                .collect();
+        move |(image_data, selected_dmc_colors, generation_settings)| {
+            let colors_for_generation = colors_for_selection(&dmc_colors_for_effect, selected_dmc_colors);
 
             if colors_for_generation.is_empty() {
-                generated_image_data_for_effect.set(None);
+                generation_worker_for_effect.cancel_preview();
+                generation_progress_for_effect.set(None);
+                preview_image_for_effect.set(None);
                 gem_counts_for_effect.set(vec![]);
                 gem_art_data_state_for_effect.set(None);
                 return;
             }
 
             if let Some(image_data) = (*image_data).as_ref() {
-                match generate_gem_art_preview(image_data, &colors_for_generation, **margin_mm, image_fit_option, color_mapping_mode, **mapping_weight, **custom_width_mm, **custom_height_mm, **gem_size_mm) {
-                    Ok((preview_data, counts, gem_art_data)) => {
-                        generated_image_data_for_effect.set(Some(preview_data));
-                        gem_counts_for_effect.set(counts);
-                        gem_art_data_state_for_effect.set(Some(gem_art_data));
-                    }
-                    Err(_e) => {
-                        // Handle error
-                        generated_image_data_for_effect.set(None);
-                        gem_counts_for_effect.set(vec![]);
-                        gem_art_data_state_for_effect.set(None);
-                    }
-                }
+                // Replaces whatever preview is still being generated for older settings
+                generation_worker_for_effect.generate_preview(image_data, &colors_for_generation, generation_settings, on_preview_response.clone());
             }
         },
-        (image_data.clone(), selected_dmc_colors.clone(), margin_mm.clone(), image_fit_option.clone(), custom_width_mm.clone(), custom_height_mm.clone(), gem_size_mm.clone(), color_mapping_mode.clone(), mapping_weight.clone()),
+        (image_data.clone(), selected_dmc_colors.clone(), generation_settings.clone()),
     );
 
     let download = {
         let gem_art_data_state = gem_art_data_state.clone();
+        let generation_worker = generation_worker.clone();
+        let generation_progress = generation_progress.clone();
         let gem_counts = gem_counts.clone();
         let show_birthday_banner = show_birthday_banner.clone();
         Callback::from(move |_| {
@@ -210,31 +375,35 @@ pub fn app() -> Html {
                     show_birthday_banner.set(false);
                 });
             }
+            // The chart downloads once the worker has drawn it
             if let Some(gem_art_data) = (*gem_art_data_state).as_ref() {
-                if let Ok(final_image_data) = generate_gem_art_final(gem_art_data) {
-                    let document = web_sys::window().unwrap().document().unwrap();
-                    let link = document.create_element("a").unwrap();
-                    let link: web_sys::HtmlAnchorElement = link.dyn_into().unwrap();
-                    link.set_href(&final_image_data);
-                    link.set_download("gem_art.png");
-                    link.click();
-                }
+                let generation_progress = generation_progress.clone();
+                generation_worker.generate_final(gem_art_data, move |response, payload| match response {
+                    WorkerResponse::Progress { stage, fraction, .. } => generation_progress.set(Some((stage, fraction))),
+                    WorkerResponse::Final { .. } => {
+                        generation_progress.set(None);
+                        if let Some(png) = payload {
+                            download_bytes(&png, "image/png", "gem_art.png");
+                        }
+                    }
+                    WorkerResponse::FinalFailed { error, .. } => {
+                        generation_progress.set(None);
+                        log::error!("Failed to generate the chart: {}", error);
+                    }
+                    _ => {}
+                });
             }
 
             if let Ok(text_image_data) = generate_text_image(&gem_counts) {
-                let document = web_sys::window().unwrap().document().unwrap();
-                let link = document.create_element("a").unwrap();
-                let link: web_sys::HtmlAnchorElement = link.dyn_into().unwrap();
-                link.set_href(&text_image_data);
-                link.set_download("gem_art_legend.png");
-                link.click();
+                download_data_url(&text_image_data, "gem_art_legend.png");
             }
         })
     };
 
+    let gem_art_data_for_overlay = gem_art_data_state.clone();
     use_effect_with_deps(
-        move |generated_image_data| {
-            if let Some(data) = generated_image_data {
+        move |(preview_image, show_background, show_heatmap)| {
+            if let Some(pixels) = preview_image {
                 let document = web_sys::window().unwrap().document().unwrap();
                 let canvas = document.get_element_by_id("preview-canvas").unwrap();
                 let canvas: HtmlCanvasElement = canvas.dyn_into().unwrap();
@@ -244,20 +413,37 @@ pub fn app() -> Html {
                     .unwrap()
                     .dyn_into::<CanvasRenderingContext2d>()
                     .unwrap();
-                let image = web_sys::HtmlImageElement::new().unwrap();
-                image.set_src(data);
-                let context = context.clone();
-                let image_clone = image.clone();
-                let onload = Closure::wrap(Box::new(move || {
-                    canvas.set_width(image_clone.width());
-                    canvas.set_height(image_clone.height());
-                    context.draw_image_with_html_image_element(&image_clone, 0.0, 0.0).unwrap();
-                }) as Box<dyn FnMut()>);
-                image.set_onload(Some(onload.as_ref().unchecked_ref()));
-                onload.forget();
+                canvas.set_width(pixels.width());
+                canvas.set_height(pixels.height());
+                let image_data = ImageData::new_with_u8_clamped_array_and_sh(wasm_bindgen::Clamped(pixels.as_raw()), pixels.width(), pixels.height()).unwrap();
+                context.put_image_data(&image_data, 0.0, 0.0).unwrap();
+                let Some(data) = (*gem_art_data_for_overlay).as_ref() else {
+                    return;
+                };
+                let layout = data.preview_layout();
+                let size = layout.gem_px as f64;
+                let fill_cell = |cell: usize| {
+                    let gx = (cell / data.num_gems_y as usize) as f64;
+                    let gy = (cell % data.num_gems_y as usize) as f64;
+                    context.fill_rect(layout.origin_x as f64 + gx * size, layout.origin_y as f64 + gy * size, size, size);
+                };
+                if *show_heatmap {
+                    for (cell, &error) in data.cell_errors.iter().enumerate().filter(|&(cell, _)| data.gem_grid[cell] != EMPTY_CELL) {
+                        let [r, g, b] = heatmap_color(error);
+                        context.set_fill_style(&JsValue::from_str(&format!("rgb({}, {}, {})", r, g, b)));
+                        fill_cell(cell);
+                    }
+                }
+                // Tint the detected background cells on top of the preview
+                if *show_background {
+                    context.set_fill_style(&JsValue::from_str("rgba(255, 0, 200, 0.35)"));
+                    for (cell, _) in data.background_mask.iter().enumerate().filter(|(_, &is_background)| is_background) {
+                        fill_cell(cell);
+                    }
+                }
             }
         },
-        (*generated_image_data).clone(),
+        ((*preview_image).clone(), *show_background, *show_heatmap),
     );
 
     html! {
@@ -297,7 +483,7 @@ pub fn app() -> Html {
                     on_file_change={on_file_change.clone()}
                     on_upload_button_click={on_upload_button_click.clone()}
                     download={download.clone()}
-                    generated_image_data_is_none={(*generated_image_data).is_none()}
+                    generated_image_data_is_none={(*gem_art_data_state).is_none()}
                     on_settings_click={on_settings_click.clone()}
                 />
                 { if *is_settings_open {
@@ -313,12 +499,63 @@ pub fn app() -> Html {
                             on_help_icon_mouseout={on_help_icon_mouseout.clone()}
                             on_help_icon_click={on_help_icon_click.clone()}
                             gem_size_mm={gem_size_mm.clone()}
+                            color_mapping_mode={color_mapping_mode.clone()}
                             mapping_weight={mapping_weight.clone()}
+                            adjustments={adjustments.clone()}
+                            denoise_strength={denoise_strength.clone()}
+                            denoise_radius={denoise_radius.clone()}
+                            sharpen_strength={sharpen_strength.clone()}
+                            sharpen_radius={sharpen_radius.clone()}
+                            resample_filter={resample_filter.clone()}
+                            color_metric={color_metric.clone()}
+                            dithering_mode={dithering_mode.clone()}
+                            dither_strength={dither_strength.clone()}
+                            adaptive_dithering={adaptive_dithering.clone()}
+                            min_gems_per_color={min_gems_per_color.clone()}
+                            confetti_max_size={confetti_max_size.clone()}
+                            confetti_max_delta_e={confetti_max_delta_e.clone()}
+                            background_mode={background_mode.clone()}
+                            background_key_color={background_key_color.clone()}
+                            background_tolerance={background_tolerance.clone()}
+                            background_floss={background_floss.clone()}
+                            show_background={show_background.clone()}
+                            alpha_threshold={alpha_threshold.clone()}
+                            canvas_shape={canvas_shape.clone()}
+                            custom_shape_mask={custom_shape_mask.clone()}
                         />
                     }
                 } else {
                                         html! {}
                 } }
+                { if let Some(image_data) = (*image_data).as_ref() {
+                    html! {
+                        <>
+                            <ImportanceMaskPainter
+                                image_data={image_data.clone()}
+                                importance_mask={importance_mask.clone()}
+                            />
+                            <PaletteRegionsPanel
+                                image_data={image_data.clone()}
+                                palette_regions={palette_regions.clone()}
+                                selected_dmc_colors={selected_dmc_colors.clone()}
+                            />
+                            <AutoTunePanel
+                                image_data={image_data.clone()}
+                                allowed_colors={colors_for_selection(&dmc_colors, &selected_dmc_colors)}
+                                generation_settings={generation_settings.clone()}
+                                dmc_colors={dmc_colors.clone()}
+                                selected_dmc_colors={selected_dmc_colors.clone()}
+                                gem_size_mm={gem_size_mm.clone()}
+                                image_fit_option={image_fit_option.clone()}
+                                custom_width_mm={custom_width_mm.clone()}
+                                custom_height_mm={custom_height_mm.clone()}
+                                generation_worker={(*generation_worker).clone()}
+                            />
+                        </>
+                    }
+                } else {
+                    html! {}
+                } }
                 <ColorSelectionPanel
                     dmc_colors={dmc_colors.clone()}
                     selected_dmc_colors={selected_dmc_colors.clone()}
@@ -328,13 +565,52 @@ pub fn app() -> Html {
                     on_select_all_click={on_select_all_click.clone()}
                     on_deselect_all_click={on_deselect_all_click.clone()}
                     on_dmc_color_click={on_dmc_color_click.clone()}
+                    auto_select_count={auto_select_count.clone()}
+                    on_auto_select_click={on_auto_select_click.clone()}
+                    auto_select_disabled={(*image_data).is_none() || *auto_select_running}
+                    auto_select_error={(*auto_select_error).clone()}
                 />
-                <GemCountsDisplay
-                    gem_counts={gem_counts.clone()}
+                { match (*gem_art_data_state).as_ref() {
+                    Some(data) if data.confetti_cells_changed > 0 => html! {
+                        <p class={classes!("cleanup-note")}>{ format!("Confetti cleanup changed {} gems", data.confetti_cells_changed) }</p>
+                    },
+                    _ => html! {},
+                } }
+                <QualityMetricsDisplay
+                    quality={(*gem_art_data_state).as_ref().map(|data| data.quality)}
+                    show_heatmap={show_heatmap.clone()}
                 />
+                <div class={classes!("counts-and-advisor")}>
+                    <GemCountsDisplay
+                        gem_counts={gem_counts.clone()}
+                    />
+                    { if let Some(image_data) = (*image_data).as_ref() {
+                        html! {
+                            <PaletteAdvisor
+                                image_data={image_data.clone()}
+                                colors_for_generation={colors_for_selection(&dmc_colors, &selected_dmc_colors)}
+                                generation_settings={generation_settings.clone()}
+                                dmc_colors={dmc_colors.clone()}
+                                selected_dmc_colors={selected_dmc_colors.clone()}
+                                generation_worker={(*generation_worker).clone()}
+                            />
+                        }
+                    } else {
+                        html! {}
+                    } }
+                </div>
             </div>
             <div class={classes!("right-panel")}>
-                <canvas id="preview-canvas"></canvas>
+                <div class={classes!("preview-container")}>
+                    <canvas id="preview-canvas"></canvas>
+                    { if let Some((stage, fraction)) = (*generation_progress).as_ref() { html! {
+                        <div class={classes!("generation-progress")}>
+                            <div class={classes!("spinner")}></div>
+                            <progress max="1" value={fraction.to_string()}></progress>
+                            <span>{ stage }</span>
+                        </div>
+                    } } else { html! {} } }
+                </div>
             </div>
         </div>
     }
@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+use gloo_timers::callback::Timeout;
+use yew::prelude::*;
+use crate::dmc_colors::DmcColor;
+use crate::models::{Color, GenerationSettings, PaletteReport};
+use crate::worker::{GenerationWorker, WorkerResponse};
+
+// Number of unselected flosses suggested by the advisor.
+const SUGGESTION_COUNT: usize = 5;
+// How long the inputs must stay unchanged before the report is recomputed, so dragging
+// a slider doesn't queue an analysis per step
+const ANALYSIS_DEBOUNCE_MS: u32 = 400;
+
+#[derive(Properties, PartialEq)]
+pub struct PaletteAdvisorProps {
+    pub image_data: String,
+    pub colors_for_generation: Vec<Color>,
+    pub generation_settings: GenerationSettings,
+    pub dmc_colors: UseStateHandle<Vec<DmcColor>>,
+    pub selected_dmc_colors: UseStateHandle<HashSet<String>>,
+    pub generation_worker: GenerationWorker,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortColumn {
+    Floss,
+    Gems,
+    Mean,
+    Max,
+}
+
+/// One table row: floss, hex, gems, mean and max deltaE change.
+type AdvisorRow = (String, String, u32, f32, f32);
+
+fn sort_rows(rows: &mut [AdvisorRow], column: SortColumn, descending: bool) {
+    rows.sort_by(|a, b| {
+        let order = match column {
+            SortColumn::Floss => a.0.trim().cmp(b.0.trim()),
+            SortColumn::Gems => a.2.cmp(&b.2),
+            SortColumn::Mean => a.3.total_cmp(&b.3),
+            SortColumn::Max => a.4.total_cmp(&b.4),
+        };
+        if descending { order.reverse() } else { order }
+    });
+}
+
+fn format_delta(value: f32) -> String {
+    if value.is_finite() { format!("{:.2}", value) } else { "∞".to_string() }
+}
+
+/// Analyzes what each selected floss contributes and suggests flosses to add. The report
+/// is recomputed in the generation worker while the advisor is open, shortly after the
+/// inputs stop changing, so add and remove update it without blocking the page.
+#[function_component(PaletteAdvisor)]
+pub fn palette_advisor(props: &PaletteAdvisorProps) -> Html {
+    let is_open = use_state(|| false);
+    let report = use_state::<Option<PaletteReport>, _>(|| None);
+    let sort = use_state(|| (SortColumn::Mean, true));
+
+    // The analysis waiting for the inputs to settle, and the one queued on the worker
+    let pending = use_mut_ref(|| None::<Timeout>);
+    let job = use_mut_ref(|| None::<u32>);
+
+    {
+        let report = report.clone();
+        let worker = props.generation_worker.clone();
+        use_effect_with_deps(
+            move |(is_open, image_data, colors, settings)| {
+                // Dropping the timer stops it; the report shown stays until the next one arrives
+                *pending.borrow_mut() = None;
+                if let Some(job) = job.borrow_mut().take() {
+                    worker.cancel(job);
+                }
+                if *is_open {
+                    let (image_data, colors, settings) = (image_data.clone(), colors.clone(), settings.clone());
+                    *pending.borrow_mut() = Some(Timeout::new(ANALYSIS_DEBOUNCE_MS, move || {
+                        let queued = worker.analyze_palette(&image_data, &colors, &settings, SUGGESTION_COUNT, move |response, _| match response {
+                            WorkerResponse::PaletteReport { report: new_report, .. } => report.set(Some(new_report)),
+                            WorkerResponse::PaletteAnalysisFailed { .. } => report.set(None),
+                            _ => {}
+                        });
+                        *job.borrow_mut() = Some(queued);
+                    }));
+                } else {
+                    report.set(None);
+                }
+            },
+            (*is_open, props.image_data.clone(), props.colors_for_generation.clone(), props.generation_settings.clone()),
+        );
+    }
+
+    // Adds or removes a floss, matching CSV floss numbers regardless of whitespace
+    let set_selected = {
+        let dmc_colors = props.dmc_colors.clone();
+        let selected_dmc_colors = props.selected_dmc_colors.clone();
+        move |floss: String, selected: bool| {
+            let dmc_colors = dmc_colors.clone();
+            let selected_dmc_colors = selected_dmc_colors.clone();
+            Callback::from(move |_: MouseEvent| {
+                let mut selection = (*selected_dmc_colors).clone();
+                for color in dmc_colors.iter().filter(|c| c.floss.trim() == floss.trim()) {
+                    if selected {
+                        selection.insert(color.floss.clone());
+                    } else {
+                        selection.remove(&color.floss);
+                    }
+                }
+                selected_dmc_colors.set(selection);
+            })
+        }
+    };
+
+    let header = |column: SortColumn, label: &'static str| {
+        let sort = sort.clone();
+        let (current, descending) = *sort;
+        let marker = match (current == column, descending) {
+            (false, _) => "",
+            (true, true) => " ▼",
+            (true, false) => " ▲",
+        };
+        let onclick = Callback::from(move |_: MouseEvent| {
+            sort.set((column, if current == column { !descending } else { true }));
+        });
+        html! { <th {onclick}>{ format!("{}{}", label, marker) }</th> }
+    };
+
+    let table = |rows: Vec<AdvisorRow>, action_label: &'static str, select: bool| {
+        let mut rows = rows;
+        sort_rows(&mut rows, sort.0, sort.1);
+        html! {
+            <table>
+                <thead>
+                    <tr>
+                        { header(SortColumn::Floss, "Floss") }
+                        { header(SortColumn::Gems, "Gems") }
+                        { header(SortColumn::Mean, if select { "Mean ΔE −" } else { "Mean ΔE +" }) }
+                        { header(SortColumn::Max, if select { "Max ΔE −" } else { "Max ΔE +" }) }
+                        <th></th>
+                    </tr>
+                </thead>
+                <tbody>
+                    { for rows.into_iter().map(|(floss, hex, gems, mean, max)| html! {
+                        <tr>
+                            <td>
+                                <span class={classes!("gem-count-circle")} style={format!("background-color: #{}", hex)}></span>
+                                { format!(" #{}", floss.trim()) }
+                            </td>
+                            <td>{ gems }</td>
+                            <td>{ format_delta(mean) }</td>
+                            <td>{ format_delta(max) }</td>
+                            <td><button onclick={set_selected(floss, select)}>{ action_label }</button></td>
+                        </tr>
+                    }) }
+                </tbody>
+            </table>
+        }
+    };
+
+    html! {
+        <div class={classes!("palette-advisor")}>
+            <div class={classes!("mask-controls")}>
+                <button onclick={{
+                    let is_open = is_open.clone();
+                    Callback::from(move |_: MouseEvent| is_open.set(!*is_open))
+                }}>{ if *is_open { "Hide palette advisor" } else { "Analyze palette" } }</button>
+            </div>
+            { match (*report).as_ref() {
+                Some(report) => html! {
+                    <>
+                        <p>{ format!("Mean ΔE {:.2}, max ΔE {:.2}", report.mean_delta_e, report.max_delta_e) }</p>
+                        { table(
+                            // An irreplaceable floss shows, and sorts, as an infinite increase
+                            report.contributions.iter().map(|c| (c.floss.clone(), c.hex.clone(), c.gems, c.mean_delta_e_increase.unwrap_or(f32::INFINITY), c.max_delta_e_increase.unwrap_or(f32::INFINITY))).collect(),
+                            "Remove",
+                            false,
+                        ) }
+                        <p>{ "Suggested additions" }</p>
+                        { table(
+                            report.suggestions.iter().map(|s| (s.floss.clone(), s.hex.clone(), s.gems, s.mean_delta_e_decrease, s.max_delta_e_decrease)).collect(),
+                            "Add",
+                            true,
+                        ) }
+                    </>
+                },
+                None => html! {},
+            } }
+        </div>
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+use yew::prelude::*;
+use crate::components::MaskPainter;
+use crate::models::PaletteRegion;
+
+#[derive(Properties, PartialEq)]
+pub struct PaletteRegionsPanelProps {
+    pub image_data: String,
+    pub palette_regions: UseStateHandle<Vec<PaletteRegion>>,
+    pub selected_dmc_colors: UseStateHandle<HashSet<String>>,
+}
+
+fn selection_flosses(selected_dmc_colors: &HashSet<String>) -> Vec<String> {
+    let mut flosses: Vec<String> = selected_dmc_colors.iter().cloned().collect();
+    flosses.sort();
+    flosses
+}
+
+/// Lists the palette regions. Each region takes its flosses from the current color
+/// selection and its area from a mask painted over the source image.
+#[function_component(PaletteRegionsPanel)]
+pub fn palette_regions_panel(props: &PaletteRegionsPanelProps) -> Html {
+    let editing = use_state(|| None::<usize>);
+
+    // Applies `update` to a copy of region `index` and stores the result
+    let update_region = {
+        let palette_regions = props.palette_regions.clone();
+        move |index: usize, update: Box<dyn Fn(&mut PaletteRegion)>| {
+            let mut regions = (*palette_regions).clone();
+            if let Some(region) = regions.get_mut(index) {
+                update(region);
+                palette_regions.set(regions);
+            }
+        }
+    };
+
+    let on_add_region = {
+        let palette_regions = props.palette_regions.clone();
+        let selected_dmc_colors = props.selected_dmc_colors.clone();
+        let editing = editing.clone();
+        Callback::from(move |_: MouseEvent| {
+            let mut regions = (*palette_regions).clone();
+            regions.push(PaletteRegion {
+                name: format!("Region {}", regions.len() + 1),
+                mask: None,
+                polygon: None,
+                allowed_flosses: selection_flosses(&selected_dmc_colors),
+            });
+            editing.set(Some(regions.len() - 1));
+            palette_regions.set(regions);
+        })
+    };
+
+    html! {
+        <div class={classes!("section", "palette-regions")}>
+            <div class={classes!("mask-controls")}>
+                <label>{ "Palette regions" }</label>
+                <button onclick={on_add_region} disabled={props.selected_dmc_colors.is_empty()}>{ "Add region from selection" }</button>
+            </div>
+            { for props.palette_regions.iter().enumerate().map(|(index, region)| {
+                let is_editing = *editing == Some(index);
+                let on_use_selection = {
+                    let update_region = update_region.clone();
+                    let selected_dmc_colors = props.selected_dmc_colors.clone();
+                    Callback::from(move |_: MouseEvent| {
+                        let flosses = selection_flosses(&selected_dmc_colors);
+                        update_region(index, Box::new(move |region| region.allowed_flosses = flosses.clone()));
+                    })
+                };
+                let on_toggle_paint = {
+                    let editing = editing.clone();
+                    Callback::from(move |_: MouseEvent| {
+                        editing.set(if is_editing { None } else { Some(index) });
+                    })
+                };
+                let on_remove = {
+                    let palette_regions = props.palette_regions.clone();
+                    let editing = editing.clone();
+                    Callback::from(move |_: MouseEvent| {
+                        let mut regions = (*palette_regions).clone();
+                        regions.remove(index);
+                        palette_regions.set(regions);
+                        editing.set(None);
+                    })
+                };
+                let on_mask_change = {
+                    let update_region = update_region.clone();
+                    Callback::from(move |mask: Option<String>| {
+                        update_region(index, Box::new(move |region| region.mask = mask.clone()));
+                    })
+                };
+                html! {
+                    <div key={index} class={classes!("palette-region")}>
+                        <div class={classes!("mask-controls")}>
+                            <span>{ format!("{} ({} colors)", region.name, region.allowed_flosses.len()) }</span>
+                            <button onclick={on_use_selection}>{ "Use selection" }</button>
+                            <button onclick={on_toggle_paint}>{ if is_editing { "Done" } else { "Paint" } }</button>
+                            <button onclick={on_remove}>{ "Remove" }</button>
+                        </div>
+                        { if is_editing {
+                            html! {
+                                <MaskPainter
+                                    id={format!("region_{}", index)}
+                                    image_data={props.image_data.clone()}
+                                    mask={region.mask.clone()}
+                                    {on_mask_change}
+                                />
+                            }
+                        } else {
+                            html! {}
+                        } }
+                    </div>
+                }
+            }) }
+        </div>
+    }
+}
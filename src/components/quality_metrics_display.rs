@@ -0,0 +1,64 @@
+use yew::prelude::*;
+use web_sys::HtmlInputElement;
+use crate::models::QualityMetrics;
+
+#[derive(Properties, PartialEq)]
+pub struct QualityMetricsDisplayProps {
+    pub quality: Option<QualityMetrics>,
+    pub show_heatmap: UseStateHandle<bool>,
+}
+
+/// Shows the quality metrics of the current pattern next to those of the previous one,
+/// so the effect of a settings change can be compared.
+#[function_component(QualityMetricsDisplay)]
+pub fn quality_metrics_display(props: &QualityMetricsDisplayProps) -> Html {
+    // (previous, current)
+    let history = use_state(|| (None::<QualityMetrics>, None::<QualityMetrics>));
+    {
+        let history = history.clone();
+        use_effect_with_deps(
+            move |quality| {
+                let (_, current) = *history;
+                if current != *quality {
+                    history.set((current, *quality));
+                }
+            },
+            props.quality,
+        );
+    }
+
+    let Some(quality) = props.quality else {
+        return html! {};
+    };
+    let previous = history.0;
+    let row = |label: &'static str, value: fn(&QualityMetrics) -> f32, digits: usize| {
+        let was = previous.as_ref().map_or(String::new(), |p| format!(" (was {:.*})", digits, value(p)));
+        html! {
+            <tr>
+                <td>{ label }</td>
+                <td>{ format!("{:.*}{}", digits, value(&quality), was) }</td>
+            </tr>
+        }
+    };
+
+    html! {
+        <div class={classes!("quality-metrics")}>
+            <table>
+                { row("Mean ΔE", |q| q.mean_delta_e, 2) }
+                { row("95th percentile ΔE", |q| q.p95_delta_e, 2) }
+                { row("Max ΔE", |q| q.max_delta_e, 2) }
+                { row("SSIM", |q| q.ssim, 3) }
+            </table>
+            <div class={classes!("mask-controls")}>
+                <input type="checkbox" id="show_heatmap" checked={*props.show_heatmap} onchange={{
+                    let show_heatmap = props.show_heatmap.clone();
+                    Callback::from(move |e: Event| {
+                        let input: HtmlInputElement = e.target_unchecked_into();
+                        show_heatmap.set(input.checked());
+                    })
+                }} />
+                <label for="show_heatmap">{ "Show error heatmap" }</label>
+            </div>
+        </div>
+    }
+}
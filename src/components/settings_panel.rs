@@ -1,6 +1,6 @@
 use yew::prelude::*;
-use web_sys::HtmlInputElement;
-use crate::models::ImageFitOption;
+use web_sys::{HtmlInputElement, HtmlSelectElement};
+use crate::models::{ImageFitOption, BackgroundMode, CanvasShape, ColorMappingMode, ColorMetric, DitheringMode, ImageAdjustments, ResampleFilter};
 use crate::components::HelpModal;
 
 #[derive(Properties, PartialEq)]
@@ -15,11 +15,145 @@ pub struct SettingsPanelProps {
     pub on_help_icon_mouseout: Callback<MouseEvent>,
     pub on_help_icon_click: Callback<MouseEvent>,
     pub gem_size_mm: UseStateHandle<f32>,
+    pub color_mapping_mode: UseStateHandle<ColorMappingMode>,
     pub mapping_weight: UseStateHandle<f32>,
+    pub adjustments: UseStateHandle<ImageAdjustments>,
+    pub denoise_strength: UseStateHandle<f32>,
+    pub denoise_radius: UseStateHandle<u32>,
+    pub sharpen_strength: UseStateHandle<f32>,
+    pub sharpen_radius: UseStateHandle<f32>,
+    pub resample_filter: UseStateHandle<ResampleFilter>,
+    pub color_metric: UseStateHandle<ColorMetric>,
+    pub dithering_mode: UseStateHandle<DitheringMode>,
+    pub dither_strength: UseStateHandle<f32>,
+    pub adaptive_dithering: UseStateHandle<bool>,
+    pub min_gems_per_color: UseStateHandle<u32>,
+    pub confetti_max_size: UseStateHandle<u32>,
+    pub confetti_max_delta_e: UseStateHandle<f32>,
+    pub background_mode: UseStateHandle<BackgroundMode>,
+    pub background_key_color: UseStateHandle<String>,
+    pub background_tolerance: UseStateHandle<f32>,
+    pub background_floss: UseStateHandle<Option<String>>,
+    pub show_background: UseStateHandle<bool>,
+    pub alpha_threshold: UseStateHandle<f32>,
+    pub canvas_shape: UseStateHandle<CanvasShape>,
+    pub custom_shape_mask: UseStateHandle<Option<String>>,
+}
+
+const MAPPING_MODE_OPTIONS: [(ColorMappingMode, &str, &str); 4] = [
+    (ColorMappingMode::AdaptiveLightnessWeighted, "min_max", "Min/max stretch"),
+    (ColorMappingMode::PercentileStretch, "percentile", "Percentile stretch (ignores highlights)"),
+    (ColorMappingMode::HistogramEqualization, "equalize", "Histogram equalization"),
+    (ColorMappingMode::Clahe, "clahe", "Local equalization (CLAHE)"),
+];
+
+const RESAMPLE_FILTER_OPTIONS: [(ResampleFilter, &str, &str); 5] = [
+    (ResampleFilter::AreaAverage, "area_average", "Area average (recommended)"),
+    (ResampleFilter::Nearest, "nearest", "Nearest pixel"),
+    (ResampleFilter::Triangle, "triangle", "Triangle"),
+    (ResampleFilter::CatmullRom, "catmull_rom", "Catmull-Rom"),
+    (ResampleFilter::Lanczos3, "lanczos3", "Lanczos3"),
+];
+
+const COLOR_METRIC_OPTIONS: [(ColorMetric, &str, &str); 5] = [
+    (ColorMetric::Cie76, "cie76", "CIE76 (fastest)"),
+    (ColorMetric::Cie94, "cie94", "CIE94"),
+    (ColorMetric::Ciede2000, "ciede2000", "CIEDE2000 (most accurate)"),
+    (ColorMetric::Oklab, "oklab", "Oklab"),
+    (ColorMetric::Redmean, "redmean", "Weighted RGB (redmean)"),
+];
+
+const DITHERING_OPTIONS: [(DitheringMode, &str, &str); 6] = [
+    (DitheringMode::None, "none", "None"),
+    (DitheringMode::FloydSteinberg, "floyd_steinberg", "Floyd–Steinberg"),
+    (DitheringMode::Atkinson, "atkinson", "Atkinson"),
+    (DitheringMode::Stucki, "stucki", "Stucki"),
+    (DitheringMode::Bayer, "bayer", "Bayer (ordered)"),
+    (DitheringMode::BlueNoise, "blue_noise", "Blue noise (ordered)"),
+];
+
+const CANVAS_SHAPE_OPTIONS: [(CanvasShape, &str, &str); 5] = [
+    (CanvasShape::Rectangle, "rectangle", "Rectangle"),
+    (CanvasShape::Circle, "circle", "Circle"),
+    (CanvasShape::Oval, "oval", "Oval"),
+    (CanvasShape::Heart, "heart", "Heart"),
+    (CanvasShape::Custom, "custom", "Custom mask"),
+];
+
+const BACKGROUND_OPTIONS: [(BackgroundMode, &str, &str); 3] = [
+    (BackgroundMode::Off, "off", "Keep as is"),
+    (BackgroundMode::FloodFillFromBorders, "flood_fill", "Detect from image borders"),
+    (BackgroundMode::ColorKey, "color_key", "Detect by key color"),
+];
+
+/// A labelled range input that edits one field of the image adjustments.
+fn adjustment_slider(
+    adjustments: &UseStateHandle<ImageAdjustments>,
+    id: &'static str,
+    label: &'static str,
+    (min, max, step): (f32, f32, f32),
+    get: fn(&ImageAdjustments) -> f32,
+    set: fn(&mut ImageAdjustments, f32),
+) -> Html {
+    let onchange = {
+        let adjustments = adjustments.clone();
+        Callback::from(move |e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            let mut updated = (*adjustments).clone();
+            set(&mut updated, input.value().parse::<f32>().unwrap_or(get(&ImageAdjustments::default())).clamp(min, max));
+            adjustments.set(updated);
+        })
+    };
+    html! {
+        <div class={classes!("adjustment-row")}>
+            <label for={id}>{ label }</label>
+            <input type="range" id={id} min={min.to_string()} max={max.to_string()} step={step.to_string()} value={get(adjustments).to_string()} {onchange} />
+        </div>
+    }
+}
+
+/// A labelled range input bound directly to a numeric state value.
+fn filter_slider(
+    state: &UseStateHandle<f32>,
+    id: &'static str,
+    label: &'static str,
+    (min, max, step): (f32, f32, f32),
+) -> Html {
+    let onchange = {
+        let state = state.clone();
+        Callback::from(move |e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            if let Ok(value) = input.value().parse::<f32>() {
+                state.set(value.clamp(min, max));
+            }
+        })
+    };
+    html! {
+        <div class={classes!("adjustment-row")}>
+            <label for={id}>{ label }</label>
+            <input type="range" id={id} min={min.to_string()} max={max.to_string()} step={step.to_string()} value={state.to_string()} {onchange} />
+        </div>
+    }
 }
 
 #[function_component(SettingsPanel)]
 pub fn settings_panel(props: &SettingsPanelProps) -> Html {
+    let shape_reader = use_state::<Option<gloo_file::callbacks::FileReader>, _>(|| None);
+    let on_shape_file_change = {
+        let custom_shape_mask = props.custom_shape_mask.clone();
+        let shape_reader = shape_reader.clone();
+        Callback::from(move |e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            if let Some(file) = input.files().and_then(|files| files.get(0)) {
+                let custom_shape_mask = custom_shape_mask.clone();
+                let task = gloo_file::callbacks::read_as_data_url(&gloo_file::File::from(file), move |res| {
+                    custom_shape_mask.set(res.ok());
+                });
+                shape_reader.set(Some(task));
+            }
+        })
+    };
+
     html! {
         { if *props.is_settings_open {
             html! {
@@ -93,6 +227,33 @@ pub fn settings_panel(props: &SettingsPanelProps) -> Html {
                             </div>
                         </div>
                     </div>
+                    <div class={classes!("setting")}>
+                        <label for="canvas_shape">{ "Canvas shape" }</label>
+                        <select id="canvas_shape" onchange={{
+                            let canvas_shape = props.canvas_shape.clone();
+                            Callback::from(move |e: Event| {
+                                let select: HtmlSelectElement = e.target_unchecked_into();
+                                let value = select.value();
+                                if let Some((shape, _, _)) = CANVAS_SHAPE_OPTIONS.iter().find(|(_, key, _)| *key == value) {
+                                    canvas_shape.set(*shape);
+                                }
+                            })
+                        }}>
+                            { for CANVAS_SHAPE_OPTIONS.iter().map(|(shape, key, label)| html! {
+                                <option value={*key} selected={*props.canvas_shape == *shape}>{ *label }</option>
+                            }) }
+                        </select>
+                        { if *props.canvas_shape == CanvasShape::Custom {
+                            html! {
+                                <div class={classes!("adjustment-row")}>
+                                    <label for="custom_shape_mask">{ "Shape mask (white gets gems)" }</label>
+                                    <input type="file" id="custom_shape_mask" accept="image/*" onchange={on_shape_file_change} />
+                                </div>
+                            }
+                        } else {
+                            html! {}
+                        } }
+                    </div>
                     <div class={classes!("setting")}>
                         <label for="mapping_weight">{ "Color mapping style" }</label>
                         <div class={classes!("slider-row")}>
@@ -118,6 +279,238 @@ pub fn settings_panel(props: &SettingsPanelProps) -> Html {
                             </datalist>
                             <span>{ "Balance tones across selected colors" }</span>
                         </div>
+                        <label for="color_mapping_mode">{ "Tone mapping" }</label>
+                        <select id="color_mapping_mode" disabled={*props.mapping_weight == 0.0} onchange={{
+                            let color_mapping_mode = props.color_mapping_mode.clone();
+                            Callback::from(move |e: Event| {
+                                let select: HtmlSelectElement = e.target_unchecked_into();
+                                let value = select.value();
+                                if let Some((mode, _, _)) = MAPPING_MODE_OPTIONS.iter().find(|(_, key, _)| *key == value) {
+                                    color_mapping_mode.set(mode.clone());
+                                }
+                            })
+                        }}>
+                            { for MAPPING_MODE_OPTIONS.iter().map(|(mode, key, label)| html! {
+                                <option value={*key} selected={*props.color_mapping_mode == *mode}>{ *label }</option>
+                            }) }
+                        </select>
+                    </div>
+                    <div class={classes!("setting")}>
+                        <label>{ "Image adjustments" }</label>
+                        { adjustment_slider(&props.adjustments, "adjust_brightness", "Brightness", (-0.5, 0.5, 0.05), |a| a.brightness, |a, v| a.brightness = v) }
+                        { adjustment_slider(&props.adjustments, "adjust_contrast", "Contrast", (-1.0, 1.0, 0.05), |a| a.contrast, |a, v| a.contrast = v) }
+                        { adjustment_slider(&props.adjustments, "adjust_saturation", "Saturation", (-1.0, 1.0, 0.05), |a| a.saturation, |a, v| a.saturation = v) }
+                        { adjustment_slider(&props.adjustments, "adjust_gamma", "Gamma", (0.2, 3.0, 0.05), |a| a.gamma, |a, v| a.gamma = v) }
+                        { adjustment_slider(&props.adjustments, "adjust_temperature", "Temperature", (-1.0, 1.0, 0.05), |a| a.temperature, |a, v| a.temperature = v) }
+                        { adjustment_slider(&props.adjustments, "adjust_tint", "Tint", (-1.0, 1.0, 0.05), |a| a.tint, |a, v| a.tint = v) }
+                        <div class={classes!("adjustment-row")}>
+                            <input type="checkbox" id="auto_white_balance" checked={props.adjustments.auto_white_balance} onchange={{
+                                let adjustments = props.adjustments.clone();
+                                Callback::from(move |e: Event| {
+                                    let input: HtmlInputElement = e.target_unchecked_into();
+                                    let mut updated = (*adjustments).clone();
+                                    updated.auto_white_balance = input.checked();
+                                    adjustments.set(updated);
+                                })
+                            }} />
+                            <label for="auto_white_balance">{ "Auto white balance" }</label>
+                            <button onclick={{
+                                let adjustments = props.adjustments.clone();
+                                Callback::from(move |_| adjustments.set(ImageAdjustments::default()))
+                            }} disabled={props.adjustments.is_identity()}>{ "Reset" }</button>
+                        </div>
+                    </div>
+                    <div class={classes!("setting")}>
+                        <label>{ "Noise and detail" }</label>
+                        { filter_slider(&props.denoise_strength, "denoise_strength", "Denoise", (0.0, 1.0, 0.05)) }
+                        <div class={classes!("adjustment-row")}>
+                            <label for="denoise_radius">{ "Denoise radius" }</label>
+                            <input type="range" id="denoise_radius" min="1" max="8" step="1" value={props.denoise_radius.to_string()} onchange={{
+                                let denoise_radius = props.denoise_radius.clone();
+                                Callback::from(move |e: Event| {
+                                    let input: HtmlInputElement = e.target_unchecked_into();
+                                    denoise_radius.set(input.value().parse::<u32>().unwrap_or(2).clamp(1, 8));
+                                })
+                            }} />
+                        </div>
+                        { filter_slider(&props.sharpen_strength, "sharpen_strength", "Sharpen", (0.0, 3.0, 0.1)) }
+                        { filter_slider(&props.sharpen_radius, "sharpen_radius", "Sharpen radius", (0.5, 10.0, 0.5)) }
+                    </div>
+                    <div class={classes!("setting")}>
+                        <label for="resample_filter">{ "Resampling" }</label>
+                        <select id="resample_filter" onchange={{
+                            let resample_filter = props.resample_filter.clone();
+                            Callback::from(move |e: Event| {
+                                let select: HtmlSelectElement = e.target_unchecked_into();
+                                let value = select.value();
+                                if let Some((filter, _, _)) = RESAMPLE_FILTER_OPTIONS.iter().find(|(_, key, _)| *key == value) {
+                                    resample_filter.set(*filter);
+                                }
+                            })
+                        }}>
+                            { for RESAMPLE_FILTER_OPTIONS.iter().map(|(filter, key, label)| html! {
+                                <option value={*key} selected={*props.resample_filter == *filter}>{ *label }</option>
+                            }) }
+                        </select>
+                    </div>
+                    <div class={classes!("setting")}>
+                        <label for="color_metric">{ "Color difference" }</label>
+                        <select id="color_metric" onchange={{
+                            let color_metric = props.color_metric.clone();
+                            Callback::from(move |e: Event| {
+                                let select: HtmlSelectElement = e.target_unchecked_into();
+                                let value = select.value();
+                                if let Some((metric, _, _)) = COLOR_METRIC_OPTIONS.iter().find(|(_, key, _)| *key == value) {
+                                    color_metric.set(*metric);
+                                }
+                            })
+                        }}>
+                            { for COLOR_METRIC_OPTIONS.iter().map(|(metric, key, label)| html! {
+                                <option value={*key} selected={*props.color_metric == *metric}>{ *label }</option>
+                            }) }
+                        </select>
+                    </div>
+                    <div class={classes!("setting")}>
+                        <label for="dithering_mode">{ "Dithering" }</label>
+                        <select id="dithering_mode" onchange={{
+                            let dithering_mode = props.dithering_mode.clone();
+                            Callback::from(move |e: Event| {
+                                let select: HtmlSelectElement = e.target_unchecked_into();
+                                let value = select.value();
+                                if let Some((mode, _, _)) = DITHERING_OPTIONS.iter().find(|(_, key, _)| *key == value) {
+                                    dithering_mode.set(*mode);
+                                }
+                            })
+                        }}>
+                            { for DITHERING_OPTIONS.iter().map(|(mode, key, label)| html! {
+                                <option value={*key} selected={*props.dithering_mode == *mode}>{ *label }</option>
+                            }) }
+                        </select>
+                    </div>
+                    { if *props.dithering_mode != DitheringMode::None {
+                        html! {
+                            <div class={classes!("setting")}>
+                                <label for="dither_strength">{ "Dither strength" }</label>
+                                <input type="range" id="dither_strength" min="0" max="1" step="0.05" value={props.dither_strength.to_string()} onchange={{
+                                    let dither_strength = props.dither_strength.clone();
+                                    Callback::from(move |e: Event| {
+                                        let input: HtmlInputElement = e.target_unchecked_into();
+                                        dither_strength.set(input.value().parse::<f32>().unwrap_or(1.0).clamp(0.0, 1.0));
+                                    })
+                                }} />
+                                <input type="checkbox" id="adaptive_dithering" checked={*props.adaptive_dithering} onchange={{
+                                    let adaptive_dithering = props.adaptive_dithering.clone();
+                                    Callback::from(move |e: Event| {
+                                        let input: HtmlInputElement = e.target_unchecked_into();
+                                        adaptive_dithering.set(input.checked());
+                                    })
+                                }} />
+                                <label for="adaptive_dithering">{ "Keep edges crisp" }</label>
+                            </div>
+                        }
+                    } else {
+                        html! {}
+                    } }
+                    <div class={classes!("setting")}>
+                        <label for="min_gems_per_color">{ "Minimum gems per color" }</label>
+                        <input type="number" id="min_gems_per_color" value={props.min_gems_per_color.to_string()} onchange={{
+                            let min_gems_per_color = props.min_gems_per_color.clone();
+                            Callback::from(move |e: Event| {
+                                let input: HtmlInputElement = e.target_unchecked_into();
+                                min_gems_per_color.set(input.value().parse().unwrap_or(0));
+                            })
+                        }} min="0" />
+                    </div>
+                    <div class={classes!("setting")}>
+                        <label for="confetti_max_size">{ "Remove confetti up to (gems)" }</label>
+                        <input type="number" id="confetti_max_size" value={props.confetti_max_size.to_string()} onchange={{
+                            let confetti_max_size = props.confetti_max_size.clone();
+                            Callback::from(move |e: Event| {
+                                let input: HtmlInputElement = e.target_unchecked_into();
+                                confetti_max_size.set(input.value().parse().unwrap_or(0));
+                            })
+                        }} min="0" />
+                        <label for="confetti_max_delta_e">{ "Max color change (ΔE)" }</label>
+                        <input type="number" id="confetti_max_delta_e" value={props.confetti_max_delta_e.to_string()} onchange={{
+                            let confetti_max_delta_e = props.confetti_max_delta_e.clone();
+                            Callback::from(move |e: Event| {
+                                let input: HtmlInputElement = e.target_unchecked_into();
+                                confetti_max_delta_e.set(input.value().parse::<f32>().unwrap_or(10.0).max(0.0));
+                            })
+                        }} min="0" step="0.5" />
+                    </div>
+                    <div class={classes!("setting")}>
+                        <label for="background_mode">{ "Background" }</label>
+                        <select id="background_mode" onchange={{
+                            let background_mode = props.background_mode.clone();
+                            Callback::from(move |e: Event| {
+                                let select: HtmlSelectElement = e.target_unchecked_into();
+                                let value = select.value();
+                                if let Some((mode, _, _)) = BACKGROUND_OPTIONS.iter().find(|(_, key, _)| *key == value) {
+                                    background_mode.set(*mode);
+                                }
+                            })
+                        }}>
+                            { for BACKGROUND_OPTIONS.iter().map(|(mode, key, label)| html! {
+                                <option value={*key} selected={*props.background_mode == *mode}>{ *label }</option>
+                            }) }
+                        </select>
+                        { filter_slider(&props.alpha_threshold, "alpha_threshold", "Transparency cutoff", (0.0, 1.0, 0.05)) }
+                        { if *props.background_mode != BackgroundMode::Off {
+                            html! {
+                                <>
+                                    <div class={classes!("adjustment-row")}>
+                                        <label for="background_tolerance">{ "Tolerance (ΔE)" }</label>
+                                        <input type="number" id="background_tolerance" value={props.background_tolerance.to_string()} onchange={{
+                                            let background_tolerance = props.background_tolerance.clone();
+                                            Callback::from(move |e: Event| {
+                                                let input: HtmlInputElement = e.target_unchecked_into();
+                                                background_tolerance.set(input.value().parse::<f32>().unwrap_or(10.0).max(0.0));
+                                            })
+                                        }} min="0" step="1" />
+                                    </div>
+                                    { if *props.background_mode == BackgroundMode::ColorKey {
+                                        html! {
+                                            <div class={classes!("adjustment-row")}>
+                                                <label for="background_key_color">{ "Key color" }</label>
+                                                <input type="color" id="background_key_color" value={(*props.background_key_color).clone()} onchange={{
+                                                    let background_key_color = props.background_key_color.clone();
+                                                    Callback::from(move |e: Event| {
+                                                        let input: HtmlInputElement = e.target_unchecked_into();
+                                                        background_key_color.set(input.value());
+                                                    })
+                                                }} />
+                                            </div>
+                                        }
+                                    } else {
+                                        html! {}
+                                    } }
+                                    <div class={classes!("adjustment-row")}>
+                                        <label for="background_floss">{ "Fill floss" }</label>
+                                        <input type="text" id="background_floss" placeholder="Leave empty" value={props.background_floss.as_ref().map_or(String::new(), |f| f.clone())} onchange={{
+                                            let background_floss = props.background_floss.clone();
+                                            Callback::from(move |e: Event| {
+                                                let input: HtmlInputElement = e.target_unchecked_into();
+                                                let value = input.value().trim().to_string();
+                                                background_floss.set(if value.is_empty() { None } else { Some(value) });
+                                            })
+                                        }} />
+                                    </div>
+                                    <div class={classes!("adjustment-row")}>
+                                        <input type="checkbox" id="show_background" checked={*props.show_background} onchange={{
+                                            let show_background = props.show_background.clone();
+                                            Callback::from(move |e: Event| {
+                                                let input: HtmlInputElement = e.target_unchecked_into();
+                                                show_background.set(input.checked());
+                                            })
+                                        }} />
+                                        <label for="show_background">{ "Highlight detected background" }</label>
+                                    </div>
+                                </>
+                            }
+                        } else {
+                            html! {}
+                        } }
                     </div>
                     <div class={classes!("setting")}>
                         <a href="https://www.instructables.com/DIY-Diamond-Painting-Make-Your-Own-Simple-Adhesive/" target="_blank">{ "DIY Instructions" }</a>
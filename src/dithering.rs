@@ -0,0 +1,219 @@
+use rayon::prelude::*;
+use crate::models::{DitheringMode, EMPTY_CELL};
+use crate::progress::GenerationHooks;
+
+// Error diffusion kernels as (dx, dy, weight) taps relative to the current cell.
+const FLOYD_STEINBERG: [(i32, i32, f32); 4] = [
+    (1, 0, 7.0 / 16.0),
+    (-1, 1, 3.0 / 16.0),
+    (0, 1, 5.0 / 16.0),
+    (1, 1, 1.0 / 16.0),
+];
+
+// Atkinson only diffuses 6/8 of the error, which keeps highlights and shadows clean.
+const ATKINSON: [(i32, i32, f32); 6] = [
+    (1, 0, 1.0 / 8.0),
+    (2, 0, 1.0 / 8.0),
+    (-1, 1, 1.0 / 8.0),
+    (0, 1, 1.0 / 8.0),
+    (1, 1, 1.0 / 8.0),
+    (0, 2, 1.0 / 8.0),
+];
+
+const STUCKI: [(i32, i32, f32); 12] = [
+    (1, 0, 8.0 / 42.0),
+    (2, 0, 4.0 / 42.0),
+    (-2, 1, 2.0 / 42.0),
+    (-1, 1, 4.0 / 42.0),
+    (0, 1, 8.0 / 42.0),
+    (1, 1, 4.0 / 42.0),
+    (2, 1, 2.0 / 42.0),
+    (-2, 2, 1.0 / 42.0),
+    (-1, 2, 2.0 / 42.0),
+    (0, 2, 4.0 / 42.0),
+    (1, 2, 2.0 / 42.0),
+    (2, 2, 1.0 / 42.0),
+];
+
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+// Lab gradient at which adaptive dithering is switched off completely.
+const EDGE_GRADIENT_LIMIT: f32 = 20.0;
+
+fn diffusion_kernel(mode: DitheringMode) -> Option<&'static [(i32, i32, f32)]> {
+    match mode {
+        DitheringMode::FloydSteinberg => Some(&FLOYD_STEINBERG),
+        DitheringMode::Atkinson => Some(&ATKINSON),
+        DitheringMode::Stucki => Some(&STUCKI),
+        _ => None,
+    }
+}
+
+/// Returns the ordered dithering threshold for a cell, centred on zero (-0.5..0.5).
+fn ordered_threshold(mode: DitheringMode, x: u32, y: u32) -> Option<f32> {
+    match mode {
+        DitheringMode::Bayer => {
+            let v = BAYER_8X8[(y % 8) as usize][(x % 8) as usize] as f32;
+            Some((v + 0.5) / 64.0 - 0.5)
+        }
+        DitheringMode::BlueNoise => {
+            // Interleaved gradient noise: a cheap, tileless approximation of a blue-noise mask.
+            let v = 52.982_918 * (0.067_110_56 * x as f32 + 0.005_837_15 * y as f32).fract();
+            Some(v.fract() - 0.5)
+        }
+        _ => None,
+    }
+}
+
+fn lab_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let dl = a[0] - b[0];
+    let da = a[1] - b[1];
+    let db = a[2] - b[2];
+    (dl * dl + da * da + db * db).sqrt()
+}
+
+/// Average distance from each palette color to its closest neighbour in the palette.
+/// Used as the amplitude for ordered dithering.
+fn palette_spread(palette_labs: &[[f32; 3]]) -> f32 {
+    if palette_labs.len() < 2 {
+        return 0.0;
+    }
+    let total: f32 = palette_labs
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            palette_labs
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, b)| lab_distance(*a, *b))
+                .fold(f32::INFINITY, f32::min)
+        })
+        .sum();
+    total / palette_labs.len() as f32
+}
+
+/// Per-cell dither scale: 1.0 in flat areas, falling to 0.0 on strong edges.
+fn edge_attenuation(lab_grid: &[[f32; 3]], num_gems_x: u32, num_gems_y: u32) -> Vec<f32> {
+    let idx = |gx: u32, gy: u32| (gx * num_gems_y + gy) as usize;
+    (0..num_gems_x)
+        .into_par_iter()
+        .flat_map(|gx| (0..num_gems_y).into_par_iter().map(move |gy| (gx, gy)))
+        .map(|(gx, gy)| {
+            let left = lab_grid[idx(gx.saturating_sub(1), gy)];
+            let right = lab_grid[idx((gx + 1).min(num_gems_x - 1), gy)];
+            let up = lab_grid[idx(gx, gy.saturating_sub(1))];
+            let down = lab_grid[idx(gx, (gy + 1).min(num_gems_y - 1))];
+            let grad_x = lab_distance(left, right) / 2.0;
+            let grad_y = lab_distance(up, down) / 2.0;
+            let gradient = (grad_x * grad_x + grad_y * grad_y).sqrt();
+            (1.0 - gradient / EDGE_GRADIENT_LIMIT).clamp(0.0, 1.0)
+        })
+        .collect()
+}
+
+fn clamp_lab(lab: [f32; 3]) -> [f32; 3] {
+    [lab[0].clamp(0.0, 100.0), lab[1].clamp(-128.0, 127.0), lab[2].clamp(-128.0, 127.0)]
+}
+
+/// Maps a Lab grid (column-major, `gx * num_gems_y + gy`) to palette indices using
+/// `nearest`, which receives the cell index and the (dithered) Lab color, applying the
+/// requested dithering on top. Cells with high `importance` (0..1) are dithered less so
+/// they keep their closest color. `nearest` may return `EMPTY_CELL` to leave a cell
+/// without a gem; such cells diffuse no error. Progress is reported to, and cancellation
+/// checked through, `hooks` as the grid is filled in.
+#[allow(clippy::too_many_arguments)]
+pub fn dither_gem_grid<F>(
+    lab_grid: &[[f32; 3]],
+    num_gems_x: u32,
+    num_gems_y: u32,
+    palette_labs: &[[f32; 3]],
+    mode: DitheringMode,
+    strength: f32,
+    adaptive: bool,
+    importance: Option<&[f32]>,
+    nearest: F,
+    hooks: &GenerationHooks,
+) -> Result<Vec<usize>, String>
+where
+    F: Fn(usize, [f32; 3]) -> usize + Sync + Send,
+{
+    let strength = strength.clamp(0.0, 1.0);
+    if mode == DitheringMode::None || strength == 0.0 || palette_labs.len() < 2 {
+        return hooks.par_map("Matching colors", lab_grid.len(), |i| nearest(i, lab_grid[i]));
+    }
+
+    let mut attenuation = if adaptive {
+        edge_attenuation(lab_grid, num_gems_x, num_gems_y)
+    } else {
+        vec![1.0; lab_grid.len()]
+    };
+    if let Some(importance) = importance {
+        for (scale, imp) in attenuation.iter_mut().zip(importance) {
+            *scale *= 1.0 - imp.clamp(0.0, 1.0);
+        }
+    }
+    let idx = |gx: u32, gy: u32| (gx * num_gems_y + gy) as usize;
+
+    if let Some(kernel) = diffusion_kernel(mode) {
+        let mut error = vec![[0.0f32; 3]; lab_grid.len()];
+        let mut gem_grid = vec![0usize; lab_grid.len()];
+        for gy in 0..num_gems_y {
+            hooks.loop_checkpoint("Matching colors", gy as usize, num_gems_y as usize)?;
+            for gx in 0..num_gems_x {
+                let i = idx(gx, gy);
+                let scale = attenuation[i];
+                let lab = lab_grid[i];
+                let target = clamp_lab([
+                    lab[0] + error[i][0] * scale,
+                    lab[1] + error[i][1] * scale,
+                    lab[2] + error[i][2] * scale,
+                ]);
+                let chosen = nearest(i, target);
+                gem_grid[i] = chosen;
+                if chosen == EMPTY_CELL {
+                    continue;
+                }
+
+                let chosen_lab = palette_labs[chosen];
+                let residual = [
+                    (target[0] - chosen_lab[0]) * strength,
+                    (target[1] - chosen_lab[1]) * strength,
+                    (target[2] - chosen_lab[2]) * strength,
+                ];
+                for &(dx, dy, weight) in kernel {
+                    let nx = gx as i32 + dx;
+                    let ny = gy as i32 + dy;
+                    if nx < 0 || nx >= num_gems_x as i32 || ny >= num_gems_y as i32 {
+                        continue;
+                    }
+                    let e = &mut error[idx(nx as u32, ny as u32)];
+                    e[0] += residual[0] * weight;
+                    e[1] += residual[1] * weight;
+                    e[2] += residual[2] * weight;
+                }
+            }
+        }
+        Ok(gem_grid)
+    } else {
+        // Ordered dithering modulates lightness by the threshold map, scaled to the
+        // typical gap between neighbouring palette colors.
+        let amplitude = palette_spread(palette_labs) * strength;
+        hooks.par_map("Matching colors", lab_grid.len(), |i| {
+            let (gx, gy) = (i as u32 / num_gems_y, i as u32 % num_gems_y);
+            let lab = lab_grid[i];
+            let threshold = ordered_threshold(mode, gx, gy).unwrap_or(0.0);
+            let offset = threshold * amplitude * attenuation[i];
+            nearest(i, clamp_lab([lab[0] + offset, lab[1], lab[2]]))
+        })
+    }
+}
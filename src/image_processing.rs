@@ -1,16 +1,32 @@
-use image::{GenericImageView, DynamicImage, Rgba, imageops::FilterType, GenericImage};
+use image::{GenericImageView, DynamicImage, GrayImage, Luma, Rgba, RgbaImage, GenericImage};
+use image::imageops::FilterType;
 use base64::{engine::general_purpose, Engine as _};
 use palette::{Srgb, Lab, IntoColor};
 use imageproc::drawing::{draw_hollow_circle_mut, draw_text_mut, draw_filled_circle_mut};
+use imageproc::filter::{median_filter, separable_filter_equal};
 use rusttype::{Font, Scale};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use rayon::prelude::*;
-use std::sync::OnceLock;
+use serde::{Serialize, Deserialize};
+use std::sync::{Mutex, OnceLock};
 use kiddo::KdTree;
-use crate::models::{ImageFitOption, GemCount, Color, DmcColorPrecomputed, ColorMappingMode};
-use crate::utils::{to_excel_column, expand_shorthand_hex};
+use crate::models::{ImageFitOption, GemCount, Color, ColorContribution, ColorSuggestion, PaletteReport, QualityMetrics, TuningConstraints, TunedConfiguration, DmcColorPrecomputed, ColorMappingMode, ColorMetric, DitheringMode, GenerationSettings, ImageAdjustments, PaletteRegion, ResampleFilter, BackgroundMode, CanvasShape, EMPTY_CELL};
+use crate::color_distance::{to_metric_space, metric_distance, lab_distance, is_euclidean, lightness_bound_factor};
+use crate::palette_index::LightnessIndex;
+use crate::color_lut::{LutCache, LutKey};
+use crate::dithering::dither_gem_grid;
+use crate::lightness::{build_lightness_map, LightnessMap};
+use crate::background::detect_background;
+use crate::shapes::shape_cells;
+use crate::quality::{cell_errors, quality_metrics, gaussian_kernel, heatmap_color};
+use crate::cleanup::remove_confetti_with_hooks;
+use crate::utils::{to_excel_column, expand_shorthand_hex, srgb_to_linear, linear_to_srgb, parse_hex_color};
+use crate::adjustments::apply_adjustments;
+use crate::progress::GenerationHooks;
 
 static DMC_COLORS_DATA: OnceLock<(Vec<DmcColorPrecomputed>, KdTree<f32, usize, 3>)> = OnceLock::new();
+// Lookup tables for nearest-color matching, kept across generations
+static COLOR_LUTS: OnceLock<Mutex<LutCache>> = OnceLock::new();
 
 fn init_dmc_colors_data() -> Result<(Vec<DmcColorPrecomputed>, KdTree<f32, usize, 3>), String> {
     let file_content = include_str!("../src/dmc_colors_precomputed.json");
@@ -25,7 +41,7 @@ fn init_dmc_colors_data() -> Result<(Vec<DmcColorPrecomputed>, KdTree<f32, usize
     Ok((precomputed_colors, kdtree))
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct GemArtData {
     pub gem_grid: Vec<usize>,
     pub letter_map: HashMap<String, String>,
@@ -36,19 +52,292 @@ pub struct GemArtData {
     pub a4_height_px: u32,
     pub margin_px: u32,
     pub filtered_dmc_colors: Vec<DmcColorPrecomputed>,
+    /// Number of gems reassigned by the confetti cleanup pass.
+    pub confetti_cells_changed: usize,
+    /// Cells detected as background (column-major), empty when detection is off.
+    pub background_mask: Vec<bool>,
+    /// DeltaE between each resized source cell and its floss (column-major, 0.0 for empty cells).
+    pub cell_errors: Vec<f32>,
+    pub quality: QualityMetrics,
+}
+
+impl GemArtData {
+    /// Top-left pixel of the gem grid on the rendered page; the grid is centred
+    /// inside the margins.
+    pub fn grid_origin(&self) -> (u32, u32) {
+        let available_width_px = self.a4_width_px - 2 * self.margin_px;
+        let available_height_px = self.a4_height_px - 2 * self.margin_px;
+        (
+            self.margin_px + (available_width_px - self.num_gems_x * self.gem_pixels_on_final_image) / 2,
+            self.margin_px + (available_height_px - self.num_gems_y * self.gem_pixels_on_final_image) / 2,
+        )
+    }
+
+    /// Geometry of the on-screen preview: the same page scaled down to a whole number
+    /// of pixels per gem, with the long side close to `PREVIEW_PAGE_PX`.
+    pub fn preview_layout(&self) -> PreviewLayout {
+        let gem_size = self.gem_pixels_on_final_image.max(1) as f32;
+        let long_side = self.a4_width_px.max(self.a4_height_px) as f32;
+        let gem_px = ((gem_size * PREVIEW_PAGE_PX / long_side).round() as u32).max(1);
+        let scale = gem_px as f32 / gem_size;
+        let (origin_x, origin_y) = self.grid_origin();
+        let origin_x = (origin_x as f32 * scale).round() as u32;
+        let origin_y = (origin_y as f32 * scale).round() as u32;
+        PreviewLayout {
+            // Rounding must not push the grid past the page edge
+            width: ((self.a4_width_px as f32 * scale).round() as u32).max(origin_x + self.num_gems_x * gem_px),
+            height: ((self.a4_height_px as f32 * scale).round() as u32).max(origin_y + self.num_gems_y * gem_px),
+            origin_x,
+            origin_y,
+            gem_px,
+        }
+    }
+}
+
+// Long side of the preview page in pixels, about what a screen canvas shows.
+const PREVIEW_PAGE_PX: f32 = 1200.0;
+
+/// Where the gem grid sits on the preview image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PreviewLayout {
+    pub width: u32,
+    pub height: u32,
+    pub origin_x: u32,
+    pub origin_y: u32,
+    pub gem_px: u32,
 }
 
-pub fn generate_gem_art_preview(image_data: &str, selected_colors: &Vec<Color>, margin_mm: f32, fit_option: &ImageFitOption, mapping_mode: &ColorMappingMode, mapping_weight: f32, custom_width_mm: Option<f32>, custom_height_mm: Option<f32>, gem_size_mm: f32) -> Result<(String, Vec<GemCount>, GemArtData), String> {
+/// Palette subset allowed inside one palette region, with its own search tree.
+struct RegionPalette {
+    allowed: Vec<bool>,
+    // Built over the allowed entries in the metric's coordinate space
+    kdtree: KdTree<f32, usize, 3>,
+}
+
+/// Picks the palette entry for a Lab color according to the active mapping mode.
+struct PaletteMatcher<'a> {
+    palette: &'a [DmcColorPrecomputed],
+    kdtree: &'a KdTree<f32, usize, 3>,
+    metric: ColorMetric,
+    // Palette colors converted into the metric's coordinate space
+    metric_palette: Vec<[f32; 3]>,
+    // Only built for Euclidean metrics that don't live in Lab (Oklab)
+    metric_kdtree: Option<KdTree<f32, usize, 3>>,
+    // Answers the blended searches that the kd-trees can't
+    lightness_index: LightnessIndex,
+    weight: f32,
+    lightness: Option<LightnessMap>,
+    // Per-cell importance; important cells ignore the lightness remap in favor of accuracy
+    importance: Option<&'a [f32]>,
+    regions: Vec<RegionPalette>,
+    // Region index per cell (column-major); empty when there are no regions
+    cell_regions: Vec<Option<usize>>,
+}
+
+impl<'a> PaletteMatcher<'a> {
+    fn new(palette: &'a [DmcColorPrecomputed], kdtree: &'a KdTree<f32, usize, 3>, metric: ColorMetric, weight: f32, lightness: Option<LightnessMap>, importance: Option<&'a [f32]>) -> Self {
+        let metric_palette: Vec<[f32; 3]> = palette
+            .iter()
+            .map(|c| to_metric_space(metric, [c.lab_l, c.lab_a, c.lab_b]))
+            .collect();
+        let metric_kdtree = if metric == ColorMetric::Oklab {
+            let mut tree = KdTree::new();
+            for (i, point) in metric_palette.iter().enumerate() {
+                let _ = tree.add(point, i);
+            }
+            Some(tree)
+        } else {
+            None
+        };
+        let palette_ls: Vec<f32> = palette.iter().map(|c| c.lab_l).collect();
+        PaletteMatcher {
+            palette,
+            kdtree,
+            metric,
+            metric_palette,
+            metric_kdtree,
+            lightness_index: LightnessIndex::new(&palette_ls),
+            weight,
+            lightness,
+            importance,
+            regions: Vec::new(),
+            cell_regions: Vec::new(),
+        }
+    }
+
+    /// Restricts cells to per-region palette subsets. `region_allowed` flags the palette
+    /// entries of each region, `cell_regions` gives each cell's region, if any.
+    fn with_regions(mut self, region_allowed: Vec<Vec<bool>>, cell_regions: Vec<Option<usize>>) -> Self {
+        self.regions = region_allowed
+            .into_iter()
+            .map(|allowed| {
+                let mut kdtree = KdTree::new();
+                for (i, point) in self.metric_palette.iter().enumerate() {
+                    if allowed[i] {
+                        let _ = kdtree.add(point, i);
+                    }
+                }
+                RegionPalette { allowed, kdtree }
+            })
+            .collect();
+        self.cell_regions = cell_regions;
+        self
+    }
+
+    fn region(&self, cell: usize) -> Option<&RegionPalette> {
+        self.cell_regions.get(cell).copied().flatten().map(|r| &self.regions[r])
+    }
+
+    /// Whether a palette entry may be used at a cell under the region restrictions.
+    fn allows(&self, cell: usize, index: usize) -> bool {
+        self.region(cell).is_none_or(|r| r.allowed[index])
+    }
+
+    /// Best palette entry for the Lab color of gem cell `cell` (column-major index).
+    fn nearest(&self, cell: usize, lab: [f32; 3]) -> usize {
+        let region = self.region(cell);
+        if self.weight == 0.0 && is_euclidean(self.metric) {
+            return self.tree_nearest(region, lab);
+        }
+
+        self.scan(cell, lab, |i| region.is_none_or(|r| r.allowed[i])).unwrap_or(0)
+    }
+
+    /// What `nearest` returns for a cell outside any palette region when matching by color
+    /// alone (`weight` 0), which doesn't depend on the cell.
+    fn nearest_unrestricted(&self, lab: [f32; 3]) -> usize {
+        debug_assert!(self.weight == 0.0);
+        if is_euclidean(self.metric) {
+            return self.tree_nearest(None, lab);
+        }
+        self.scan_blended(lab, lab[0], 0.0, |_| true).unwrap_or(0)
+    }
+
+    fn tree_nearest(&self, region: Option<&RegionPalette>, lab: [f32; 3]) -> usize {
+        let query = to_metric_space(self.metric, lab);
+        let tree = match region {
+            Some(region) => &region.kdtree,
+            None => self.metric_kdtree.as_ref().unwrap_or(self.kdtree),
+        };
+        // `nearest_one` prunes too eagerly in this kiddo version and often misses the closest point
+        let nearest_neighbor = tree
+            .nearest(&query, 1, &kiddo::distance::squared_euclidean)
+            .unwrap();
+        *nearest_neighbor[0].1
+    }
+
+    /// Like `nearest`, but only considers palette entries flagged in `active`. If none of
+    /// the cell's region colors are active, any active entry may be used.
+    fn nearest_in(&self, cell: usize, lab: [f32; 3], active: &[bool]) -> usize {
+        self.scan(cell, lab, |i| active[i] && self.allows(cell, i))
+            .or_else(|| self.scan(cell, lab, |i| active[i]))
+            .unwrap_or(0)
+    }
+
+    /// Remaps cells holding a color their region doesn't allow, e.g. after cleanup passes
+    /// borrowed a color from across a region border. Background cells are left alone.
+    fn restore_region_colors(&self, gem_grid: &mut [usize], lab_grid: &[[f32; 3]], background: &[bool]) {
+        if self.cell_regions.is_empty() {
+            return;
+        }
+        for (cell, (index, lab)) in gem_grid.iter_mut().zip(lab_grid).enumerate() {
+            if *index == EMPTY_CELL || background.get(cell).copied().unwrap_or(false) {
+                continue;
+            }
+            if !self.allows(cell, *index) {
+                *index = self.nearest(cell, *lab);
+            }
+        }
+    }
+
+    fn scan(&self, cell: usize, lab: [f32; 3], allowed: impl Fn(usize) -> bool) -> Option<usize> {
+        // Remap L* and compute blended score
+        let l_stretched = self.lightness.as_ref().map_or(lab[0], |map| map.apply(cell, lab[0]));
+        let w = self.weight * (1.0 - self.importance.map_or(0.0, |imp| imp[cell]));
+        self.scan_blended(lab, l_stretched, w, allowed)
+    }
+
+    fn scan_blended(&self, lab: [f32; 3], target_l: f32, w: f32, allowed: impl Fn(usize) -> bool) -> Option<usize> {
+        let query = to_metric_space(self.metric, lab);
+        self.lightness_index.nearest(target_l, lab[0], w, lightness_bound_factor(self.metric), allowed, |i| {
+            metric_distance(self.metric, query, self.metric_palette[i])
+        })
+    }
+}
+
+/// Repeatedly drops the least used color that appears on fewer than `min_count` gems and
+/// remaps its cells to the next best remaining color, until every used color meets the limit.
+/// Usage is weighted by importance, so colors in important areas are dropped last.
+/// Cells flattened to the background floss keep it, so that floss is never dropped.
+fn enforce_min_gem_count(gem_grid: &mut [usize], lab_grid: &[[f32; 3]], background: &[bool], matcher: &PaletteMatcher, min_count: u32, hooks: &GenerationHooks) -> Result<(), String> {
+    if min_count <= 1 {
+        return Ok(());
+    }
+    let palette_len = matcher.palette.len();
+    let cell_weight = |i: usize| importance_weight(matcher.importance, i);
+    let is_background = |i: usize| background.get(i).copied().unwrap_or(false);
+    let mut counts = vec![0u32; palette_len];
+    let mut usage = vec![0.0f32; palette_len];
+    let mut locked = vec![false; palette_len];
+    for (i, &idx) in gem_grid.iter().enumerate() {
+        if idx != EMPTY_CELL {
+            counts[idx] += 1;
+            usage[idx] += cell_weight(i);
+            locked[idx] |= is_background(i);
+        }
+    }
+    let mut active: Vec<bool> = counts.iter().map(|&c| c > 0).collect();
+    let droppable = |active: &[bool], counts: &[u32]| (0..palette_len).filter(|&i| active[i] && !locked[i] && counts[i] < min_count).collect::<Vec<_>>();
+    let initially_droppable = droppable(&active, &counts).len().max(1);
+
+    loop {
+        let used = active.iter().filter(|&&a| a).count();
+        if used <= 1 {
+            break;
+        }
+        let candidates = droppable(&active, &counts);
+        hooks.checkpoint("Cleaning up", 1.0 - candidates.len() as f32 / initially_droppable as f32)?;
+        let rarest = candidates.into_iter().min_by(|&a, &b| usage[a].total_cmp(&usage[b]));
+        let Some(dropped) = rarest else {
+            break;
+        };
+        active[dropped] = false;
+        for (i, (cell, lab)) in gem_grid.iter_mut().zip(lab_grid).enumerate() {
+            if *cell == dropped {
+                let replacement = matcher.nearest_in(i, *lab, &active);
+                counts[replacement] += 1;
+                usage[replacement] += cell_weight(i);
+                *cell = replacement;
+            }
+        }
+        counts[dropped] = 0;
+        usage[dropped] = 0.0;
+    }
+    Ok(())
+}
+
+// Extra weight of a fully important cell when ranking or clustering colors, so a cell
+// under a white mask counts as 1 + IMPORTANCE_BOOST ordinary ones.
+const IMPORTANCE_BOOST: f32 = 4.0;
+
+fn importance_weight(importance: Option<&[f32]>, cell: usize) -> f32 {
+    1.0 + IMPORTANCE_BOOST * importance.map_or(0.0, |imp| imp[cell])
+}
+
+/// Kd-tree over palette Lab values, mapping each point to its palette index.
+type PaletteTree = KdTree<f32, usize, 3>;
+
+fn build_palette(selected_colors: &[Color]) -> Result<(Vec<DmcColorPrecomputed>, PaletteTree), String> {
     let (all_dmc_colors, _kdtree) = DMC_COLORS_DATA.get_or_init(|| init_dmc_colors_data().expect("Failed to initialize DMC colors data"));
 
     // Filter precomputed colors based on selected_colors
     let mut filtered_dmc_colors: Vec<DmcColorPrecomputed> = Vec::new();
     let mut filtered_kdtree = KdTree::new();
-    let mut floss_to_index_map: HashMap<String, usize> = HashMap::new();
 
     for selected_color in selected_colors.iter() {
         if selected_color.floss_number.trim().is_empty() {
             // Create a custom DmcColorPrecomputed for the custom color
+            let lab: Lab = Srgb::new(selected_color.r as f32 / 255.0, selected_color.g as f32 / 255.0, selected_color.b as f32 / 255.0).into_color();
             let custom_color = DmcColorPrecomputed {
                 floss: format!("#custom_{}", selected_color.value), // Unique identifier
                 dmc_name: "Custom".to_string(),
@@ -56,27 +345,19 @@ pub fn generate_gem_art_preview(image_data: &str, selected_colors: &Vec<Color>,
                 g: selected_color.g,
                 b: selected_color.b,
                 hex: expand_shorthand_hex(&selected_color.value),
-                lab_l: 0.0, // These values would need to be calculated if you want to use them in color matching
-                lab_a: 0.0,
-                lab_b: 0.0,
+                lab_l: lab.l,
+                lab_a: lab.a,
+                lab_b: lab.b,
                 blended_r: 0, // Placeholder, adjust as needed
                 blended_g: 0,
                 blended_b: 0,
             };
-            let lab: Lab = Srgb::new(custom_color.r as f32 / 255.0, custom_color.g as f32 / 255.0, custom_color.b as f32 / 255.0).into_color();
-            
-            let mut updated_custom_color = custom_color.clone();
-            updated_custom_color.lab_l = lab.l;
-            updated_custom_color.lab_a = lab.a;
-            updated_custom_color.lab_b = lab.b;
 
-            let _ = filtered_kdtree.add(&[updated_custom_color.lab_l, updated_custom_color.lab_a, updated_custom_color.lab_b], filtered_dmc_colors.len());
-            floss_to_index_map.insert(updated_custom_color.floss.clone(), filtered_dmc_colors.len());
-            filtered_dmc_colors.push(updated_custom_color);
+            let _ = filtered_kdtree.add(&[custom_color.lab_l, custom_color.lab_a, custom_color.lab_b], filtered_dmc_colors.len());
+            filtered_dmc_colors.push(custom_color);
 
         } else if let Some(dmc_color) = all_dmc_colors.iter().find(|c| c.floss.trim() == selected_color.floss_number.trim()) {
             let _ = filtered_kdtree.add(&[dmc_color.lab_l, dmc_color.lab_a, dmc_color.lab_b], filtered_dmc_colors.len());
-            floss_to_index_map.insert(dmc_color.floss.clone(), filtered_dmc_colors.len());
             filtered_dmc_colors.push(dmc_color.clone());
         }
     }
@@ -85,12 +366,90 @@ pub fn generate_gem_art_preview(image_data: &str, selected_colors: &Vec<Color>,
         return Err("No DMC colors selected or found.".to_string());
     }
 
+    Ok((filtered_dmc_colors, filtered_kdtree))
+}
+
+fn pixel_to_lab(pixel: Rgba<u8>) -> [f32; 3] {
+    let srgb_pixel = Srgb::new(
+        pixel[0] as f32 / 255.0,
+        pixel[1] as f32 / 255.0,
+        pixel[2] as f32 / 255.0,
+    );
+    let lab: Lab = srgb_pixel.into_color();
+    [lab.l, lab.a, lab.b]
+}
+
+/// Geometry of the gem grid on the printed page, in 300 DPI pixels.
+#[derive(Clone, Copy)]
+struct GemLayout {
+    num_gems_x: u32,
+    num_gems_y: u32,
+    gem_size_px: u32,
+    a4_width_px: u32,
+    a4_height_px: u32,
+    margin_px: u32,
+    // Region of the source image covered by the gem grid, as fractions (x, y, w, h)
+    source_fraction: (f32, f32, f32, f32),
+}
+
+fn decode_image_data(image_data: &str) -> Result<DynamicImage, String> {
     let base64_data = image_data.split(",").nth(1).ok_or("Invalid image data")?;
     let decoded_data = general_purpose::STANDARD.decode(base64_data).map_err(|e| e.to_string())?;
-    let img = image::load_from_memory(&decoded_data).map_err(|e| e.to_string())?;
+    image::load_from_memory(&decoded_data).map_err(|e| e.to_string())
+
+}
 
-    let mut canvas_width_mm = custom_width_mm.unwrap_or(210.0);
-    let mut canvas_height_mm = custom_height_mm.unwrap_or(297.0);
+/// Width and height of the image in a data URL, read from its header alone, so even a
+/// large photo is only partly decoded.
+pub fn image_dimensions(image_data: &str) -> Option<(u32, u32)> {
+    let base64_data = image_data.split(',').nth(1)?;
+    // Enough for a JPEG's EXIF and color profile ahead of its frame header
+    let prefix = &base64_data[..base64_data.len().min(1 << 20) / 4 * 4];
+    let header = general_purpose::STANDARD.decode(prefix).ok()?;
+    image::io::Reader::new(std::io::Cursor::new(header)).with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+/// Decodes the uploaded image and runs the pre-fit stages: color adjustments,
+/// denoising and detail enhancement.
+fn prepare_source_image(image_data: &str, settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<DynamicImage, String> {
+    prepare_decoded_image(decode_image_data(image_data)?, settings, hooks)
+}
+
+// Each step runs over the full-resolution image, so each gets a checkpoint
+fn prepare_decoded_image(img: DynamicImage, settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<DynamicImage, String> {
+    hooks.checkpoint("Adjusting colors", 0.0)?;
+    let img = apply_adjustments(img, &settings.adjustments);
+    hooks.checkpoint("Denoising", 0.3)?;
+    let img = denoise_image(img, settings.denoise_radius, settings.denoise_strength);
+    hooks.checkpoint("Sharpening", 0.8)?;
+    Ok(sharpen_image(img, settings.sharpen_radius, settings.sharpen_strength))
+}
+
+/// Fits or crops the image to the printable area and downsamples it to one pixel per gem.
+fn resize_to_gem_grid(img: &DynamicImage, settings: &GenerationSettings) -> Result<(DynamicImage, GemLayout), String> {
+    let fit = fit_to_page(img, settings)?;
+    downsample_to_gems(img, &fit, settings)
+}
+
+/// The page, and where the source image goes on it, before gem size comes into play.
+#[derive(Clone)]
+struct PageFit {
+    a4_width_px: u32,
+    a4_height_px: u32,
+    margin_px: u32,
+    // Size of the fitted or cropped image on the page
+    image_width_px: u32,
+    image_height_px: u32,
+    // Region of the source image on the page, in source pixels (x, y, w, h)
+    source_rect: (f32, f32, f32, f32),
+    // The fitted or cropped image at print resolution; area averaging reads the source directly
+    fitted: Option<DynamicImage>,
+}
+
+/// Works out the page geometry and fits or crops the image to the printable area.
+fn fit_to_page(img: &DynamicImage, settings: &GenerationSettings) -> Result<PageFit, String> {
+    let mut canvas_width_mm = settings.custom_width_mm.unwrap_or(210.0);
+    let mut canvas_height_mm = settings.custom_height_mm.unwrap_or(297.0);
     let dpi = 300.0;
     let mm_per_inch = 25.4;
     let pixels_per_mm = dpi / mm_per_inch;
@@ -108,9 +467,9 @@ pub fn generate_gem_art_preview(image_data: &str, selected_colors: &Vec<Color>,
         std::mem::swap(&mut canvas_width_mm, &mut canvas_height_mm);
     }
 
-    let a4_width_px = ((canvas_width_mm * pixels_per_mm) as f32).round() as u32;
-    let a4_height_px = ((canvas_height_mm * pixels_per_mm) as f32).round() as u32;
-    let margin_px = ((margin_mm * pixels_per_mm) as f32).round() as u32;
+    let a4_width_px = (canvas_width_mm * pixels_per_mm).round() as u32;
+    let a4_height_px = (canvas_height_mm * pixels_per_mm).round() as u32;
+    let margin_px = (settings.margin_mm * pixels_per_mm).round() as u32;
 
     if 2 * margin_px >= a4_width_px || 2 * margin_px >= a4_height_px {
         return Err("Image dimensions are too small to generate gem art due to large margins.".to_string());
@@ -122,155 +481,712 @@ pub fn generate_gem_art_preview(image_data: &str, selected_colors: &Vec<Color>,
     let img_aspect_ratio = img_width as f32 / img_height as f32;
     let printable_aspect_ratio = printable_width_px as f32 / printable_height_px as f32;
 
-    let (final_img_width_px, final_img_height_px);
-    let mut processed_img = img;
-
-    match fit_option {
+    // Region of the source image that ends up on the page, in source pixels (x, y, w, h)
+    let (final_img_width_px, final_img_height_px, source_rect) = match settings.fit_option {
         ImageFitOption::Fit => {
-            if img_aspect_ratio > printable_aspect_ratio {
+            let (width, height) = if img_aspect_ratio > printable_aspect_ratio {
                 // Image is wider, fit by width
-                final_img_width_px = printable_width_px;
-                final_img_height_px = (printable_width_px as f32 / img_aspect_ratio).round() as u32;
+                (printable_width_px, (printable_width_px as f32 / img_aspect_ratio).round() as u32)
             } else {
                 // Image is taller or same aspect, fit by height
-                final_img_height_px = printable_height_px;
-                final_img_width_px = (printable_height_px as f32 * img_aspect_ratio).round() as u32;
-            }
-            processed_img = processed_img.resize_exact(final_img_width_px, final_img_height_px, FilterType::Nearest);
+                ((printable_height_px as f32 * img_aspect_ratio).round() as u32, printable_height_px)
+            };
+            (width, height, (0.0, 0.0, img_width as f32, img_height as f32))
         },
         ImageFitOption::Crop => {
-            if img_aspect_ratio > printable_aspect_ratio {
+            let source_rect = if img_aspect_ratio > printable_aspect_ratio {
                 // Image is wider, scale height to fill and crop width
-                let scaled_height = printable_height_px;
                 let scaled_width = (printable_height_px as f32 * img_aspect_ratio).round() as u32;
-                processed_img = processed_img.resize_exact(scaled_width, scaled_height, FilterType::Nearest);
-
                 let crop_x = (scaled_width - printable_width_px) / 2;
-                processed_img = processed_img.crop_imm(crop_x, 0, printable_width_px, printable_height_px);
+                let scale = img_width as f32 / scaled_width as f32;
+                (crop_x as f32 * scale, 0.0, printable_width_px as f32 * scale, img_height as f32)
             } else {
                 // Image is taller, scale width to fill and crop height
-                let scaled_width = printable_width_px;
                 let scaled_height = (printable_width_px as f32 / img_aspect_ratio).round() as u32;
-                processed_img = processed_img.resize_exact(scaled_width, scaled_height, FilterType::Nearest);
-
                 let crop_y = (scaled_height - printable_height_px) / 2;
-                processed_img = processed_img.crop_imm(0, crop_y, printable_width_px, printable_height_px);
+                let scale = img_height as f32 / scaled_height as f32;
+                (0.0, crop_y as f32 * scale, img_width as f32, printable_height_px as f32 * scale)
+            };
+            (printable_width_px, printable_height_px, source_rect)
+        }
+    };
+
+    let fitted = settings.resample_filter.filter_type().map(|filter| {
+        match settings.fit_option {
+            ImageFitOption::Fit => {
+                img.resize_exact(final_img_width_px, final_img_height_px, filter)
+            },
+            ImageFitOption::Crop => {
+                if img_aspect_ratio > printable_aspect_ratio {
+                    let scaled_height = printable_height_px;
+                    let scaled_width = (printable_height_px as f32 * img_aspect_ratio).round() as u32;
+                    let scaled_img = img.resize_exact(scaled_width, scaled_height, filter);
+
+                    let crop_x = (scaled_width - printable_width_px) / 2;
+                    scaled_img.crop_imm(crop_x, 0, printable_width_px, printable_height_px)
+                } else {
+                    let scaled_width = printable_width_px;
+                    let scaled_height = (printable_width_px as f32 / img_aspect_ratio).round() as u32;
+                    let scaled_img = img.resize_exact(scaled_width, scaled_height, filter);
+
+                    let crop_y = (scaled_height - printable_height_px) / 2;
+                    scaled_img.crop_imm(0, crop_y, printable_width_px, printable_height_px)
+                }
             }
-            final_img_width_px = printable_width_px;
-            final_img_height_px = printable_height_px;
         }
-    }
+    });
 
-    let gem_size_px = (gem_size_mm * pixels_per_mm).round() as u32;
-    let num_gems_x = final_img_width_px / gem_size_px;
-    let num_gems_y = final_img_height_px / gem_size_px;
+    Ok(PageFit {
+        a4_width_px,
+        a4_height_px,
+        margin_px,
+        image_width_px: final_img_width_px,
+        image_height_px: final_img_height_px,
+        source_rect,
+        fitted,
+    })
+}
+
+/// Downsamples the fitted image (or the source region it covers) to one pixel per gem.
+fn downsample_to_gems(img: &DynamicImage, fit: &PageFit, settings: &GenerationSettings) -> Result<(DynamicImage, GemLayout), String> {
+    let PageFit { a4_width_px, a4_height_px, margin_px, source_rect, .. } = *fit;
+    let pixels_per_mm = 300.0 / 25.4;
+    let gem_size_px = (settings.gem_size_mm * pixels_per_mm).round() as u32;
+    let num_gems_x = fit.image_width_px / gem_size_px;
+    let num_gems_y = fit.image_height_px / gem_size_px;
 
     if num_gems_x == 0 || num_gems_y == 0 {
         return Err("Image dimensions are too small to generate gem art.".to_string());
     }
 
-    let resized_img = processed_img.resize_exact(num_gems_x, num_gems_y, FilterType::Nearest);
-
-    // Compute adaptive lightness stretch parameters if requested
-    // Determine effective weight based on mode and slider
-    let mut w = mapping_weight.clamp(0.0, 1.0);
-    match mapping_mode {
-        ColorMappingMode::Nearest => { w = 0.0; }
-        ColorMappingMode::AdaptiveLightnessStretch => { w = 1.0; }
-        ColorMappingMode::AdaptiveLightnessWeighted => {}
-    }
-    let mut use_adaptive = false;
-    let mut img_l_min = 0.0f32;
-    let mut img_l_max = 0.0f32;
-    let mut pal_l_min = 0.0f32;
-    let mut pal_l_max = 0.0f32;
-    if w > 0.0 {
-        // Compute image L* min/max over resized image
-        let mut local_min = f32::INFINITY;
-        let mut local_max = f32::NEG_INFINITY;
-        for gx in 0..num_gems_x {
-            for gy in 0..num_gems_y {
-                let p = resized_img.get_pixel(gx, gy);
-                let srgb = Srgb::new(p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0);
-                let lab: Lab = srgb.into_color();
-                if lab.l < local_min { local_min = lab.l; }
-                if lab.l > local_max { local_max = lab.l; }
+    let resized_img = match (&fit.fitted, settings.resample_filter.filter_type()) {
+        (Some(fitted), Some(filter)) => fitted.resize_exact(num_gems_x, num_gems_y, filter),
+        _ => area_average_resize(img, source_rect, num_gems_x, num_gems_y),
+    };
+
+    let (img_width, img_height) = img.dimensions();
+    let layout = GemLayout {
+        num_gems_x,
+        num_gems_y,
+        gem_size_px,
+        a4_width_px,
+        a4_height_px,
+        margin_px,
+        source_fraction: (
+            source_rect.0 / img_width as f32,
+            source_rect.1 / img_height as f32,
+            source_rect.2 / img_width as f32,
+            source_rect.3 / img_height as f32,
+        ),
+    };
+    Ok((resized_img, layout))
+}
+
+/// Box-filters `source_rect` (x, y, w, h in source pixels) down to `width` x `height`,
+/// weighting every source pixel by how much of it falls inside each target cell.
+/// Averaging happens in linear light with alpha-weighted color, so dark and
+/// transparent pixels don't skew the result.
+fn area_average_resize(img: &DynamicImage, source_rect: (f32, f32, f32, f32), width: u32, height: u32) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let (src_w, src_h) = rgba.dimensions();
+    let linear_lut: Vec<f32> = (0..256).map(|v| srgb_to_linear(v as f32 / 255.0)).collect();
+    let (rect_x, rect_y, rect_w, rect_h) = source_rect;
+    let cell_w = rect_w / width as f32;
+    let cell_h = rect_h / height as f32;
+
+    let pixels: Vec<Rgba<u8>> = (0..width)
+        .into_par_iter()
+        .flat_map(|gx| (0..height).into_par_iter().map(move |gy| (gx, gy)))
+        .map(|(gx, gy)| {
+            let x0 = rect_x + gx as f32 * cell_w;
+            let x1 = x0 + cell_w;
+            let y0 = rect_y + gy as f32 * cell_h;
+            let y1 = y0 + cell_h;
+            let mut color_sum = [0.0f32; 3];
+            let mut alpha_sum = 0.0f32;
+            let mut weight_sum = 0.0f32;
+            for sy in (y0.floor() as u32)..(y1.ceil() as u32).min(src_h) {
+                let wy = (y1.min(sy as f32 + 1.0) - y0.max(sy as f32)).max(0.0);
+                for sx in (x0.floor() as u32)..(x1.ceil() as u32).min(src_w) {
+                    let wx = (x1.min(sx as f32 + 1.0) - x0.max(sx as f32)).max(0.0);
+                    let weight = wx * wy;
+                    if weight <= 0.0 {
+                        continue;
+                    }
+                    let p = rgba.get_pixel(sx, sy);
+                    let alpha = p[3] as f32 / 255.0;
+                    let aw = alpha * weight;
+                    color_sum[0] += linear_lut[p[0] as usize] * aw;
+                    color_sum[1] += linear_lut[p[1] as usize] * aw;
+                    color_sum[2] += linear_lut[p[2] as usize] * aw;
+                    alpha_sum += aw;
+                    weight_sum += weight;
+                }
             }
+            if alpha_sum <= 0.0 || weight_sum <= 0.0 {
+                return Rgba([0, 0, 0, 0]);
+            }
+            let to_u8 = |linear: f32| (linear_to_srgb(linear / alpha_sum).clamp(0.0, 1.0) * 255.0).round() as u8;
+            Rgba([
+                to_u8(color_sum[0]),
+                to_u8(color_sum[1]),
+                to_u8(color_sum[2]),
+                ((alpha_sum / weight_sum) * 255.0).round() as u8,
+            ])
+        })
+        .collect();
+
+    let mut resized = DynamicImage::new_rgba8(width, height);
+    for gx in 0..width {
+        for gy in 0..height {
+            resized.put_pixel(gx, gy, pixels[(gx * height + gy) as usize]);
         }
-        // Compute palette L* min/max
-        let mut palette_min = f32::INFINITY;
-        let mut palette_max = f32::NEG_INFINITY;
-        for c in &filtered_dmc_colors {
-            if c.lab_l < palette_min { palette_min = c.lab_l; }
-            if c.lab_l > palette_max { palette_max = c.lab_l; }
-        }
-        if local_min.is_finite() && local_max.is_finite() && palette_min.is_finite() && palette_max.is_finite() && local_max > local_min && palette_max > palette_min {
-            use_adaptive = true;
-            img_l_min = local_min;
-            img_l_max = local_max;
-            pal_l_min = palette_min;
-            pal_l_max = palette_max;
-        }
     }
+    resized
+}
+
+/// Edge-preserving denoise: blends each pixel towards the per-channel median of its
+/// `radius` neighbourhood by `strength` (0.0 leaves the image unchanged, 1.0 is the full median).
+pub fn denoise_image(img: DynamicImage, radius: u32, strength: f32) -> DynamicImage {
+    let strength = strength.clamp(0.0, 1.0);
+    if radius == 0 || strength == 0.0 {
+        return img;
+    }
+    let original = img.into_rgba8();
+    let median = median_filter(&original, radius, radius);
+    DynamicImage::ImageRgba8(blend_towards(original, &median, strength))
+}
+
+/// Unsharp mask: adds `strength` times the difference between the image and a Gaussian
+/// blur of standard deviation `radius`, boosting detail at that scale.
+pub fn sharpen_image(img: DynamicImage, radius: f32, strength: f32) -> DynamicImage {
+    if radius <= 0.0 || strength <= 0.0 {
+        return img;
+    }
+    let original = img.into_rgba8();
+    // imageproc's own Gaussian kernel is cut at 2 sigma without renormalising, which
+    // darkens flat areas; use a normalised 3 sigma kernel instead.
+    let blurred = separable_filter_equal(&original, &gaussian_kernel(radius));
+    DynamicImage::ImageRgba8(blend_towards(original, &blurred, -strength))
+}
 
-    let gem_grid: Vec<usize> = (0..num_gems_x)
+// original + amount * (target - original) on the color channels, keeping alpha.
+fn blend_towards(mut original: RgbaImage, target: &RgbaImage, amount: f32) -> RgbaImage {
+    original
+        .par_chunks_exact_mut(4)
+        .zip(target.par_chunks_exact(4))
+        .for_each(|(p, t)| {
+            for i in 0..3 {
+                let value = p[i] as f32 + amount * (t[i] as f32 - p[i] as f32);
+                p[i] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    original
+}
+
+/// Converts a one-pixel-per-gem image into a Lab grid indexed `gx * num_gems_y + gy`.
+fn image_to_lab_grid(resized_img: &DynamicImage) -> Vec<[f32; 3]> {
+    let (num_gems_x, num_gems_y) = resized_img.dimensions();
+    (0..num_gems_x)
         .into_par_iter()
         .flat_map(|gx| (0..num_gems_y).into_par_iter().map(move |gy| (gx, gy)))
-        .map(|(gx, gy)| {
-            let pixel = resized_img.get_pixel(gx, gy);
-            let srgb_pixel = Srgb::new(
-                pixel[0] as f32 / 255.0,
-                pixel[1] as f32 / 255.0,
-                pixel[2] as f32 / 255.0,
-            );
-            let lab_orig: Lab = srgb_pixel.into_color();
-            if w == 0.0 {
-                let nearest_neighbor = filtered_kdtree
-                    .nearest_one(&[lab_orig.l, lab_orig.a, lab_orig.b], &kiddo::distance::squared_euclidean)
-                    .unwrap();
-                *nearest_neighbor.1
-            } else {
-                // Stretch L* and compute blended score
-                let scaled = pal_l_min + (lab_orig.l - img_l_min) * (pal_l_max - pal_l_min) / (img_l_max - img_l_min);
-                let l_stretched = scaled.max(pal_l_min).min(pal_l_max);
-                let mut best_idx = 0usize;
-                let mut best_score = f32::INFINITY;
-                for (i, c) in filtered_dmc_colors.iter().enumerate() {
-                    let dl = (l_stretched - c.lab_l).abs();
-                    let dlab_l = lab_orig.l - c.lab_l;
-                    let dlab_a = lab_orig.a - c.lab_a;
-                    let dlab_b = lab_orig.b - c.lab_b;
-                    let lab_dist = (dlab_l * dlab_l + dlab_a * dlab_a + dlab_b * dlab_b).sqrt();
-                    let score = w * dl + (1.0 - w) * lab_dist;
-                    if score < best_score {
-                        best_score = score;
-                        best_idx = i;
-                    }
+        .map(|(gx, gy)| pixel_to_lab(resized_img.get_pixel(gx, gy)))
+        .collect()
+}
+
+/// Flags the cells (column-major) that get no gem: those whose opacity falls below
+/// the alpha threshold, and those outside the canvas shape.
+fn empty_cells(resized_img: &DynamicImage, settings: &GenerationSettings) -> Result<Vec<bool>, String> {
+    let (num_gems_x, num_gems_y) = resized_img.dimensions();
+    let inside_shape = match (settings.canvas_shape, settings.custom_shape_mask.as_deref()) {
+        (CanvasShape::Custom, Some(mask_data)) => Some(custom_shape_cells(mask_data, num_gems_x, num_gems_y)?),
+        (shape, _) => shape_cells(shape, num_gems_x, num_gems_y),
+    };
+    Ok((0..num_gems_x)
+        .flat_map(|gx| (0..num_gems_y).map(move |gy| (gx, gy)))
+        .enumerate()
+        .map(|(i, (gx, gy))| {
+            let transparent = (resized_img.get_pixel(gx, gy)[3] as f32 / 255.0) < settings.alpha_threshold;
+            transparent || inside_shape.as_ref().is_some_and(|inside| !inside[i])
+        })
+        .collect())
+}
+
+/// Samples a custom shape mask at every cell centre, with the mask stretched over the
+/// whole gem grid. Bright cells are inside the shape.
+fn custom_shape_cells(mask_data: &str, num_gems_x: u32, num_gems_y: u32) -> Result<Vec<bool>, String> {
+    let mask = decode_mask(mask_data)?;
+    let (mask_width, mask_height) = mask.dimensions();
+    let sample = |cell: u32, cells: u32, size: u32| (((cell as f32 + 0.5) / cells as f32 * size as f32) as u32).min(size - 1);
+    Ok((0..num_gems_x)
+        .flat_map(|gx| (0..num_gems_y).map(move |gy| (gx, gy)))
+        .map(|(gx, gy)| mask.get_pixel(sample(gx, num_gems_x, mask_width), sample(gy, num_gems_y, mask_height))[0] >= 128)
+        .collect())
+}
+
+/// Samples the importance mask from `settings` onto the gem grid: one value in 0..1 per
+/// cell, column-major. Transparent areas count as unimportant.
+fn importance_grid(settings: &GenerationSettings, layout: &GemLayout) -> Result<Option<Vec<f32>>, String> {
+    settings.importance_mask.as_deref().map(|mask_data| mask_to_gem_grid(mask_data, layout)).transpose()
+}
+
+/// Region index for every cell (column-major), or an empty vector without regions.
+/// A cell belongs to a region if its centre lies inside the polygon or on the bright
+/// half of the mask.
+fn region_grid(settings: &GenerationSettings, layout: &GemLayout) -> Result<Vec<Option<usize>>, String> {
+    if settings.palette_regions.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (fx, fy, fw, fh) = layout.source_fraction;
+    let (nx, ny) = (layout.num_gems_x, layout.num_gems_y);
+    let mut cell_regions = vec![None; (nx * ny) as usize];
+    for (r, region) in settings.palette_regions.iter().enumerate() {
+        let mask = region.mask.as_deref().map(|mask_data| mask_to_gem_grid(mask_data, layout)).transpose()?;
+        for gx in 0..nx {
+            for gy in 0..ny {
+                let cell = (gx * ny + gy) as usize;
+                let point = (fx + fw * (gx as f32 + 0.5) / nx as f32, fy + fh * (gy as f32 + 0.5) / ny as f32);
+                let in_mask = mask.as_ref().is_some_and(|m| m[cell] >= 0.5);
+                let in_polygon = region.polygon.as_deref().is_some_and(|p| point_in_polygon(point, p));
+                if in_mask || in_polygon {
+                    cell_regions[cell] = Some(r);
                 }
-                best_idx
             }
-            
-            
-            
-            
-            
-            
-            
-            
-            
-        
+        }
+    }
+    Ok(cell_regions)
+}
+
+/// Even-odd test; polygons with fewer than three vertices contain nothing.
+fn point_in_polygon((x, y): (f32, f32), polygon: &[(f32, f32)]) -> bool {
+    if polygon.len() < 3 {
+        return false;
+    }
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (xi, yi) = polygon[i];
+        let (xj, yj) = polygon[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Samples a mask image that covers the whole source image (at any resolution) onto the
+/// gem grid, one value in 0..1 per cell, column-major. Alpha multiplies the gray level.
+fn mask_to_gem_grid(mask_data: &str, layout: &GemLayout) -> Result<Vec<f32>, String> {
+    let gray = decode_mask(mask_data)?;
+    let (mask_width, mask_height) = gray.dimensions();
+
+    let (fx, fy, fw, fh) = layout.source_fraction;
+    let crop_x = ((fx * mask_width as f32).floor() as u32).min(mask_width - 1);
+    let crop_y = ((fy * mask_height as f32).floor() as u32).min(mask_height - 1);
+    let crop_w = ((fw * mask_width as f32).round() as u32).clamp(1, mask_width - crop_x);
+    let crop_h = ((fh * mask_height as f32).round() as u32).clamp(1, mask_height - crop_y);
+    let cropped = image::imageops::crop_imm(&gray, crop_x, crop_y, crop_w, crop_h).to_image();
+    let scaled = image::imageops::resize(&cropped, layout.num_gems_x, layout.num_gems_y, FilterType::Triangle);
+
+    Ok((0..layout.num_gems_x)
+        .flat_map(|gx| (0..layout.num_gems_y).map(move |gy| (gx, gy)))
+        .map(|(gx, gy)| scaled.get_pixel(gx, gy)[0] as f32 / 255.0)
+        .collect())
+}
+
+/// Decodes a mask image to gray levels, treating transparent pixels as black.
+fn decode_mask(mask_data: &str) -> Result<GrayImage, String> {
+    let mask = decode_image_data(mask_data)?.to_luma_alpha8();
+    Ok(GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        let p = mask.get_pixel(x, y);
+        Luma([(p[0] as u32 * p[1] as u32 / 255) as u8])
+    }))
+}
+
+/// Builds the palette from the selection plus the background floss, if one is set and
+/// not already selected. Also returns the background floss's palette index.
+fn build_palette_with_background(selected_colors: &[Color], settings: &GenerationSettings) -> Result<(Vec<DmcColorPrecomputed>, PaletteTree, Option<usize>), String> {
+    let Some(floss) = settings.background_floss.as_deref().map(str::trim) else {
+        let (palette, kdtree) = build_palette(selected_colors)?;
+        return Ok((palette, kdtree, None));
+    };
+    let mut colors = selected_colors.to_vec();
+    if !colors.iter().any(|c| c.floss_number.trim() == floss) {
+        colors.push(Color { floss_number: floss.to_string(), ..Color::default() });
+    }
+    let (palette, kdtree) = build_palette(&colors)?;
+    let index = palette
+        .iter()
+        .position(|c| c.floss.trim() == floss)
+        .ok_or_else(|| format!("Unknown background floss: {}", floss))?;
+    Ok((palette, kdtree, Some(index)))
+}
+
+/// Runs the background detection chosen in `settings` on the gem grid.
+fn background_mask(settings: &GenerationSettings, lab_grid: &[[f32; 3]], num_gems_x: u32, num_gems_y: u32) -> Result<Vec<bool>, String> {
+    let key_lab = match settings.background_mode {
+        BackgroundMode::ColorKey => {
+            let (r, g, b) = parse_hex_color(&settings.background_key_color)
+                .ok_or_else(|| format!("Invalid background key color: {}", settings.background_key_color))?;
+            pixel_to_lab(Rgba([r, g, b, 255]))
+        }
+        _ => [0.0; 3],
+    };
+    Ok(detect_background(
+        lab_grid,
+        num_gems_x,
+        num_gems_y,
+        settings.background_mode,
+        key_lab,
+        settings.background_tolerance,
+        settings.color_metric,
+    ))
+}
+
+/// Flags the palette entries allowed in each region; a region without any selected
+/// flosses may use the whole palette.
+fn region_palettes(regions: &[PaletteRegion], palette: &[DmcColorPrecomputed]) -> Vec<Vec<bool>> {
+    regions
+        .iter()
+        .map(|region| {
+            let allowed: Vec<bool> = palette
+                .iter()
+                .map(|c| region.allowed_flosses.iter().any(|floss| floss.trim() == c.floss.trim()))
+                .collect();
+            if allowed.contains(&true) {
+                allowed
+            } else {
+                vec![true; palette.len()]
+            }
         })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn generate_gem_art_preview(image_data: &str, selected_colors: &[Color], margin_mm: f32, fit_option: &ImageFitOption, mapping_mode: &ColorMappingMode, mapping_weight: f32, custom_width_mm: Option<f32>, custom_height_mm: Option<f32>, gem_size_mm: f32) -> Result<(String, Vec<GemCount>, GemArtData), String> {
+    let settings = GenerationSettings {
+        margin_mm,
+        fit_option: fit_option.clone(),
+        mapping_mode: mapping_mode.clone(),
+        mapping_weight,
+        custom_width_mm,
+        custom_height_mm,
+        gem_size_mm,
+        ..GenerationSettings::default()
+    };
+    generate_gem_art_preview_with_settings(image_data, selected_colors, &settings)
+}
+
+/// Undithered nearest-color matching through the cached lookup table for this palette.
+/// Cells inside palette regions are matched directly.
+fn lut_gem_grid(resized_img: &DynamicImage, lab_grid: &[[f32; 3]], empty: &[bool], matcher: &PaletteMatcher, palette: &[DmcColorPrecomputed], mapping_mode: &ColorMappingMode, hooks: &GenerationHooks) -> Result<Vec<usize>, String> {
+    let num_gems_y = resized_img.height();
+    let rgb_at = |cell: usize| {
+        let pixel = resized_img.get_pixel(cell as u32 / num_gems_y, cell as u32 % num_gems_y);
+        [pixel[0], pixel[1], pixel[2]]
+    };
+    let looked_up = |cell: usize| !empty[cell] && matcher.region(cell).is_none();
+    let colors: Vec<[u8; 3]> = (0..lab_grid.len()).filter(|&cell| looked_up(cell)).map(rgb_at).collect();
+
+    let key = LutKey {
+        flosses: palette.iter().map(|c| c.floss.clone()).collect(),
+        metric: matcher.metric,
+        mapping_mode: mapping_mode.clone(),
+    };
+    let luts = COLOR_LUTS.get_or_init(Default::default);
+    let mut lut = luts.lock().unwrap_or_else(|e| e.into_inner()).take(&key);
+    // A cancelled table is still valid, so it goes back into the cache either way
+    let gem_grid = lut
+        .prepare(&colors, matcher.metric, &matcher.metric_palette, |lab| matcher.nearest_unrestricted(lab), &hooks.part(0.0, 0.5))
+        .and_then(|()| {
+            hooks.part(0.5, 1.0).par_map("Matching colors", lab_grid.len(), |cell| {
+                if empty[cell] {
+                    EMPTY_CELL
+                } else if looked_up(cell) {
+                    lut.get(rgb_at(cell))
+                } else {
+                    matcher.nearest(cell, lab_grid[cell])
+                }
+            })
+        });
+    luts.lock().unwrap_or_else(|e| e.into_inner()).insert(key, lut);
+    gem_grid
+}
+
+/// The image mapped onto the gem grid, before the page is rendered.
+struct MappedPattern {
+    palette: Vec<DmcColorPrecomputed>,
+    gem_grid: Vec<usize>,
+    background: Vec<bool>,
+    confetti_cells_changed: usize,
+    cell_errors: Vec<f32>,
+    quality: QualityMetrics,
+}
+
+/// Maps the gem-resolution image (and its Lab values from `image_to_lab_grid`) onto the
+/// selected flosses: background detection, lightness mapping, dithering and the cleanup passes.
+fn map_gem_grid(resized_img: &DynamicImage, lab_grid: &[[f32; 3]], layout: &GemLayout, selected_colors: &[Color], settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<MappedPattern, String> {
+    let (filtered_dmc_colors, filtered_kdtree, background_index) = build_palette_with_background(selected_colors, settings)?;
+    let importance = importance_grid(settings, layout)?;
+    let cell_regions = region_grid(settings, layout)?;
+    let GemLayout { num_gems_x, num_gems_y, .. } = *layout;
+    let empty = empty_cells(resized_img, settings)?;
+    // Empty cells stay empty even when they match the background
+    let mut background = background_mask(settings, lab_grid, num_gems_x, num_gems_y)?;
+    for (is_background, _) in background.iter_mut().zip(&empty).filter(|(_, &is_empty)| is_empty) {
+        *is_background = false;
+    }
+    // Cells that won't be matched against the image don't shape the lightness map
+    let unmatched: Vec<bool> = empty
+        .iter()
+        .enumerate()
+        .map(|(i, &is_empty)| is_empty || background.get(i).copied().unwrap_or(false))
         .collect();
 
+    // Determine effective weight based on mode and slider, then remap L* onto the palette
+    let w = match settings.mapping_mode {
+        ColorMappingMode::Nearest => 0.0,
+        ColorMappingMode::AdaptiveLightnessStretch => 1.0,
+        _ => settings.mapping_weight.clamp(0.0, 1.0),
+    };
+    let lightness = if w > 0.0 {
+        let palette_ls: Vec<f32> = filtered_dmc_colors.iter().map(|c| c.lab_l).collect();
+        build_lightness_map(&settings.mapping_mode, lab_grid, num_gems_x, num_gems_y, &palette_ls, &unmatched)
+    } else {
+        None
+    };
+
+    let matcher = PaletteMatcher::new(&filtered_dmc_colors, &filtered_kdtree, settings.color_metric, w, lightness, importance.as_deref())
+        .with_regions(region_palettes(&settings.palette_regions, &filtered_dmc_colors), cell_regions);
+    let palette_labs: Vec<[f32; 3]> = filtered_dmc_colors.iter().map(|c| [c.lab_l, c.lab_a, c.lab_b]).collect();
+
+    hooks.checkpoint("Matching colors", 0.45)?;
+    let matching_hooks = hooks.part(0.45, 0.7);
+    let mut gem_grid = if settings.color_lut && w == 0.0 && settings.dithering_mode == DitheringMode::None {
+        lut_gem_grid(resized_img, lab_grid, &empty, &matcher, &filtered_dmc_colors, &settings.mapping_mode, &matching_hooks)?
+    } else {
+        dither_gem_grid(
+            lab_grid,
+            num_gems_x,
+            num_gems_y,
+            &palette_labs,
+            settings.dithering_mode,
+            settings.dither_strength,
+            settings.adaptive_dithering,
+            importance.as_deref(),
+            |cell, lab| if empty[cell] { EMPTY_CELL } else { matcher.nearest(cell, lab) },
+            &matching_hooks,
+        )?
+    };
+    hooks.checkpoint("Cleaning up", 0.7)?;
+    let background_fill = background_index.unwrap_or(EMPTY_CELL);
+    for (cell, _) in gem_grid.iter_mut().zip(&background).filter(|(_, &is_background)| is_background) {
+        *cell = background_fill;
+    }
+    let confetti_cells_changed = remove_confetti_with_hooks(
+        &mut gem_grid,
+        num_gems_x,
+        num_gems_y,
+        &filtered_dmc_colors,
+        settings.color_metric,
+        settings.confetti_max_size as usize,
+        settings.confetti_max_delta_e,
+        &hooks.part(0.7, 0.77),
+    )?;
+    matcher.restore_region_colors(&mut gem_grid, lab_grid, &background);
+    enforce_min_gem_count(&mut gem_grid, lab_grid, &background, &matcher, settings.min_gems_per_color, &hooks.part(0.77, 0.8))?;
+
+    hooks.checkpoint("Measuring quality", 0.8)?;
+    let errors = cell_errors(lab_grid, &gem_grid, &palette_labs, settings.color_metric);
+    let quality = quality_metrics(lab_grid, &gem_grid, &palette_labs, &errors, num_gems_x, num_gems_y);
+
+    Ok(MappedPattern {
+        palette: filtered_dmc_colors,
+        gem_grid,
+        background,
+        confetti_cells_changed,
+        cell_errors: errors,
+        quality,
+    })
+}
+
+pub fn generate_gem_art_preview_with_settings(image_data: &str, selected_colors: &[Color], settings: &GenerationSettings) -> Result<(String, Vec<GemCount>, GemArtData), String> {
+    generate_gem_art_preview_with_hooks(image_data, selected_colors, settings, &GenerationHooks::default())
+}
+
+/// `generate_gem_art_preview_with_settings` reporting progress to, and stopping when
+/// cancelled through, `hooks`.
+pub fn generate_gem_art_preview_with_hooks(image_data: &str, selected_colors: &[Color], settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<(String, Vec<GemCount>, GemArtData), String> {
+    PreviewPipeline::default().generate_with_hooks(image_data, selected_colors, settings, hooks)
+}
+
+/// `generate_gem_art_preview_with_settings` without the preview image.
+pub fn generate_gem_art_pattern_with_settings(image_data: &str, selected_colors: &[Color], settings: &GenerationSettings) -> Result<(Vec<GemCount>, GemArtData), String> {
+    PreviewPipeline::default().generate_pattern(image_data, selected_colors, settings)
+}
+
+/// How many times each stage of a `PreviewPipeline` has run.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct PipelineStats {
+    pub decodes: u32,
+    pub preparations: u32,
+    pub fits: u32,
+    pub downsamples: u32,
+    pub mappings: u32,
+    pub renders: u32,
+}
+
+/// Preview generation split into stages that keep their results between calls:
+/// decoded image, prepared (adjusted, denoised, sharpened) image, image fitted to the
+/// page, gem-resolution image with its Lab values, gem grid, and the preview PNG (only
+/// encoded by `generate`).
+/// A stage only reruns when its own inputs change, and rerunning it discards every
+/// later stage. Toggling a color, for instance, only remaps and re-renders.
+#[derive(Default)]
+pub struct PreviewPipeline {
+    decoded: Option<(String, DynamicImage)>,
+    prepared: Option<(PrepareKey, DynamicImage)>,
+    fitted: Option<(FitKey, PageFit)>,
+    gem_image: Option<(f32, DynamicImage, GemLayout, Vec<[f32; 3]>)>,
+    pattern: Option<(Vec<Color>, GenerationSettings)>,
+    gem_art: Option<(Vec<GemCount>, GemArtData)>,
+    preview_url: Option<String>,
+    stats: PipelineStats,
+}
+
+// Settings read by `prepare_source_image`
+type PrepareKey = (ImageAdjustments, f32, u32, f32, f32);
+// Settings read by `fit_to_page`
+type FitKey = (Option<f32>, Option<f32>, f32, ImageFitOption, ResampleFilter);
+
+impl PreviewPipeline {
+    pub fn stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    /// Same result as `generate_gem_art_preview_with_settings`, reusing whatever stages
+    /// the previous call left valid.
+    pub fn generate(&mut self, image_data: &str, selected_colors: &[Color], settings: &GenerationSettings) -> Result<(String, Vec<GemCount>, GemArtData), String> {
+        self.generate_with_hooks(image_data, selected_colors, settings, &GenerationHooks::default())
+    }
+
+    /// `generate` with progress reporting and cancellation. A cancelled call leaves the
+    /// stages it finished cached for the next one.
+    pub fn generate_with_hooks(&mut self, image_data: &str, selected_colors: &[Color], settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<(String, Vec<GemCount>, GemArtData), String> {
+        let (counts, gem_art_data) = self.generate_pattern_with_hooks(image_data, selected_colors, settings, hooks)?;
+        if self.preview_url.is_none() {
+            hooks.checkpoint("Drawing preview", 0.9)?;
+            self.preview_url = Some(preview_data_url(&gem_art_data)?);
+            self.stats.renders += 1;
+        }
+        hooks.report("Done", 1.0);
+        Ok((self.preview_url.clone().unwrap(), counts, gem_art_data))
+    }
+
+    /// Like `generate`, but stops at the pattern and leaves drawing it to the caller,
+    /// e.g. with `render_preview_image`.
+    pub fn generate_pattern(&mut self, image_data: &str, selected_colors: &[Color], settings: &GenerationSettings) -> Result<(Vec<GemCount>, GemArtData), String> {
+        self.generate_pattern_with_hooks(image_data, selected_colors, settings, &GenerationHooks::default())
+    }
+
+    /// `generate_pattern` with progress reporting and cancellation. Progress stops short
+    /// of 1.0, leaving the rest for drawing the preview.
+    pub fn generate_pattern_with_hooks(&mut self, image_data: &str, selected_colors: &[Color], settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<(Vec<GemCount>, GemArtData), String> {
+        self.update_gem_image(image_data, settings, hooks)?;
+        let (_, resized_img, layout, lab_grid) = self.gem_image.as_ref().unwrap();
+
+        let pattern_key = (selected_colors.to_vec(), settings.clone());
+        if self.pattern.as_ref() != Some(&pattern_key) {
+            self.pattern = None;
+            hooks.checkpoint("Mapping colors", 0.4)?;
+            let pattern = map_gem_grid(resized_img, lab_grid, layout, selected_colors, settings, hooks)?;
+            self.stats.mappings += 1;
+            self.gem_art = Some(build_gem_art_data(pattern, layout));
+            self.preview_url = None;
+            self.pattern = Some(pattern_key);
+        }
+        Ok(self.gem_art.clone().unwrap())
+    }
+
+    /// Same result as `analyze_palette`, reusing the gem-resolution image of the previous
+    /// call when the image and the settings it depends on are unchanged. Cancellation is
+    /// checked, and progress reported, through `hooks`.
+    pub fn analyze_palette_with_hooks(&mut self, image_data: &str, selected_colors: &[Color], settings: &GenerationSettings, top_k: usize, hooks: &GenerationHooks) -> Result<PaletteReport, String> {
+        self.update_gem_image(image_data, settings, hooks)?;
+        let (_, resized_img, layout, lab_grid) = self.gem_image.as_ref().unwrap();
+        hooks.checkpoint("Analyzing palette", 0.4)?;
+        let report = palette_report(resized_img, layout, lab_grid, selected_colors, settings, top_k, &hooks.part(0.4, 1.0))?;
+        hooks.report("Done", 1.0);
+        Ok(report)
+    }
+
+    /// Same result as `auto_select_colors`, reusing the gem-resolution image like
+    /// `analyze_palette_with_hooks`.
+    pub fn auto_select_colors_with_hooks(&mut self, image_data: &str, allowed_colors: &[Color], num_colors: usize, settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<Vec<String>, String> {
+        let (palette, _) = build_palette(allowed_colors)?;
+        self.update_gem_image(image_data, settings, hooks)?;
+        let (_, resized_img, layout, _) = self.gem_image.as_ref().unwrap();
+        hooks.checkpoint("Clustering colors", 0.4)?;
+        let chosen = select_colors(resized_img, layout, &palette, num_colors, settings)?;
+        hooks.report("Done", 1.0);
+        Ok(chosen)
+    }
+
+    // Brings every stage up to the gem-resolution image in line with the arguments
+    fn update_gem_image(&mut self, image_data: &str, settings: &GenerationSettings, hooks: &GenerationHooks) -> Result<(), String> {
+        hooks.checkpoint("Decoding image", 0.0)?;
+        if self.decoded.as_ref().is_none_or(|(data, _)| data != image_data) {
+            self.prepared = None;
+            self.decoded = Some((image_data.to_string(), decode_image_data(image_data)?));
+            self.stats.decodes += 1;
+        }
+
+        hooks.checkpoint("Preparing image", 0.1)?;
+        let prepare_key = (settings.adjustments.clone(), settings.denoise_strength, settings.denoise_radius, settings.sharpen_strength, settings.sharpen_radius);
+        if self.prepared.as_ref().is_none_or(|(key, _)| *key != prepare_key) {
+            self.fitted = None;
+            let (_, decoded) = self.decoded.as_ref().unwrap();
+            self.prepared = Some((prepare_key, prepare_decoded_image(decoded.clone(), settings, &hooks.part(0.1, 0.2))?));
+            self.stats.preparations += 1;
+        }
+        let (_, img) = self.prepared.as_ref().unwrap();
+
+        hooks.checkpoint("Fitting to page", 0.2)?;
+        let fit_key = (settings.custom_width_mm, settings.custom_height_mm, settings.margin_mm, settings.fit_option.clone(), settings.resample_filter);
+        if self.fitted.as_ref().is_none_or(|(key, _)| *key != fit_key) {
+            self.gem_image = None;
+            self.fitted = Some((fit_key, fit_to_page(img, settings)?));
+            self.stats.fits += 1;
+        }
+        let (_, fit) = self.fitted.as_ref().unwrap();
+
+        hooks.checkpoint("Resizing to gems", 0.3)?;
+        if self.gem_image.as_ref().is_none_or(|(gem_size_mm, ..)| *gem_size_mm != settings.gem_size_mm) {
+            self.pattern = None;
+            let (resized_img, layout) = downsample_to_gems(img, fit, settings)?;
+            let lab_grid = image_to_lab_grid(&resized_img);
+            self.gem_image = Some((settings.gem_size_mm, resized_img, layout, lab_grid));
+            self.stats.downsamples += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Counts and letters for a mapped pattern.
+fn build_gem_art_data(pattern: MappedPattern, layout: &GemLayout) -> (Vec<GemCount>, GemArtData) {
+    let MappedPattern { palette: filtered_dmc_colors, gem_grid, background, confetti_cells_changed, cell_errors: errors, quality } = pattern;
+    let GemLayout { num_gems_x, num_gems_y, gem_size_px, a4_width_px, a4_height_px, margin_px, .. } = *layout;
+
     let mut color_counts: HashMap<String, (u32, String)> = HashMap::new();
-    for &closest_color_index in &gem_grid {
+    for &closest_color_index in gem_grid.iter().filter(|&&i| i != EMPTY_CELL) {
         let color_info = &filtered_dmc_colors[closest_color_index];
         let entry = color_counts.entry(color_info.floss.clone()).or_insert((0, color_info.hex.clone()));
         entry.0 += 1;
     }
 
     let mut sorted_counts: Vec<_> = color_counts.into_iter().map(|(floss, (count, hex))| GemCount { floss, count, hex: expand_shorthand_hex(&hex) }).collect();
-    sorted_counts.sort_by(|a, b| b.count.cmp(&a.count));
+    sorted_counts.sort_by_key(|c| std::cmp::Reverse(c.count));
 
     let letter_map: HashMap<String, String> = sorted_counts
         .iter()
@@ -278,144 +1194,567 @@ pub fn generate_gem_art_preview(image_data: &str, selected_colors: &Vec<Color>,
         .map(|(i, gem_count)| (gem_count.floss.clone(), to_excel_column(i + 1)))
         .collect();
 
-    let gem_pixels_on_final_image = (gem_size_mm * pixels_per_mm).round() as u32;
-    let gem_art_width_px = num_gems_x * gem_pixels_on_final_image;
-    let gem_art_height_px = num_gems_y * gem_pixels_on_final_image;
-
-    let mut final_image = DynamicImage::new_rgba8(a4_width_px, a4_height_px);
-    for x in 0..a4_width_px {
-        for y in 0..a4_height_px {
-            final_image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
-        }
-    }
-
-    let available_width_px = a4_width_px - (2 * margin_px);
-    let available_height_px = a4_height_px - (2 * margin_px);
-    let offset_x = (available_width_px - gem_art_width_px) / 2;
-    let offset_y = (available_height_px - gem_art_height_px) / 2;
-    let paste_x = margin_px + offset_x;
-    let paste_y = margin_px + offset_y;
-
-    for gx in 0..num_gems_x {
-        for gy in 0..num_gems_y {
-            let closest_color_index = gem_grid[(gx * num_gems_y + gy) as usize];
-            let color_info = &filtered_dmc_colors[closest_color_index];
-            let gem_rgba = Rgba([color_info.r, color_info.g, color_info.b, 255]);
-
-            for px in 0..gem_pixels_on_final_image {
-                for py in 0..gem_pixels_on_final_image {
-                    final_image.put_pixel(
-                        paste_x + gx * gem_pixels_on_final_image + px,
-                        paste_y + gy * gem_pixels_on_final_image + py,
-                        gem_rgba,
-                    );
-                }
-            }
-        }
-    }
-
-    let mut buf = Vec::new();
-    final_image.write_to(&mut std::io::Cursor::new(&mut buf), image::ImageOutputFormat::Png).map_err(|e| e.to_string())?;
-    let encoded_data = general_purpose::STANDARD.encode(&buf);
-    let image_data_url = format!("data:image/png;base64,{}", encoded_data);
-
     let gem_art_data = GemArtData {
         gem_grid,
         letter_map,
         num_gems_x,
         num_gems_y,
-        gem_pixels_on_final_image,
+        gem_pixels_on_final_image: gem_size_px,
         a4_width_px,
         a4_height_px,
         margin_px,
         filtered_dmc_colors,
+        confetti_cells_changed,
+        background_mask: background,
+        cell_errors: errors,
+        quality,
     };
 
-    Ok((image_data_url, sorted_counts, gem_art_data))
+    (sorted_counts, gem_art_data)
 }
 
-pub fn generate_gem_art_final(gem_art_data: &GemArtData) -> Result<String, String> {
-    let GemArtData {
-        gem_grid,
-        letter_map,
-        num_gems_x,
-        num_gems_y,
-        gem_pixels_on_final_image,
-        a4_width_px,
-        a4_height_px,
-        margin_px,
-        filtered_dmc_colors,
-    } = gem_art_data;
+/// The preview as a PNG data URL.
+pub fn preview_data_url(data: &GemArtData) -> Result<String, String> {
+    let preview = DynamicImage::ImageRgba8(render_preview_image(data));
+    let mut buf = Vec::new();
+    preview.write_to(&mut std::io::Cursor::new(&mut buf), image::ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    let encoded_data = general_purpose::STANDARD.encode(&buf);
+    Ok(format!("data:image/png;base64,{}", encoded_data))
+}
+
+/// Draws the pattern at `preview_layout` scale, ready to hand to a canvas as RGBA
+/// pixels. The 300 DPI page is only built by `generate_gem_art_final`.
+pub fn render_preview_image(data: &GemArtData) -> RgbaImage {
+    let PreviewLayout { width, height, origin_x, origin_y, gem_px } = data.preview_layout();
+    let mut image = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+    let row_bytes = width as usize * 4;
+    let cell_bytes = gem_px as usize * 4;
+    let pixels: &mut [u8] = &mut image;
+    for gx in 0..data.num_gems_x {
+        for gy in 0..data.num_gems_y {
+            let closest_color_index = data.gem_grid[(gx * data.num_gems_y + gy) as usize];
+            if closest_color_index == EMPTY_CELL {
+                continue;
+            }
+            let color_info = &data.filtered_dmc_colors[closest_color_index];
+            let gem_rgba = [color_info.r, color_info.g, color_info.b, 255];
+            let left = (origin_x + gx * gem_px) as usize * 4;
+            for y in origin_y + gy * gem_px..origin_y + (gy + 1) * gem_px {
+                let row_start = y as usize * row_bytes + left;
+                for pixel in pixels[row_start..row_start + cell_bytes].chunks_exact_mut(4) {
+                    pixel.copy_from_slice(&gem_rgba);
+                }
+            }
+        }
+    }
+    image
+}
 
-    let gem_art_width_px = num_gems_x * gem_pixels_on_final_image;
-    let gem_art_height_px = num_gems_y * gem_pixels_on_final_image;
+/// Clusters the gem-resolution image in Lab and snaps each cluster centre to the closest
+/// not-yet-chosen color from `allowed_colors`, using the metric from `settings`.
+/// Returns the chosen floss numbers, largest cluster first.
+pub fn auto_select_colors(image_data: &str, allowed_colors: &[Color], num_colors: usize, settings: &GenerationSettings) -> Result<Vec<String>, String> {
+    PreviewPipeline::default().auto_select_colors_with_hooks(image_data, allowed_colors, num_colors, settings, &GenerationHooks::default())
+}
 
-    let mut final_image = DynamicImage::new_rgba8(*a4_width_px, *a4_height_px);
-    for x in 0..*a4_width_px {
-        for y in 0..*a4_height_px {
-            final_image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+fn select_colors(resized_img: &DynamicImage, layout: &GemLayout, palette: &[DmcColorPrecomputed], num_colors: usize, settings: &GenerationSettings) -> Result<Vec<String>, String> {
+    let lab_grid = image_to_lab_grid(resized_img);
+    let importance = importance_grid(settings, layout)?;
+    // Empty cells get no gem and background cells get flattened anyway, so neither
+    // competes for colors
+    let empty = empty_cells(resized_img, settings)?;
+    let background = background_mask(settings, &lab_grid, layout.num_gems_x, layout.num_gems_y)?;
+    let weights: Vec<f32> = (0..lab_grid.len())
+        .map(|i| {
+            if empty[i] || background.get(i).copied().unwrap_or(false) {
+                0.0
+            } else {
+                importance_weight(importance.as_deref(), i)
+            }
+        })
+        .collect();
+
+    let k = num_colors.min(palette.len());
+    if k == 0 {
+        return Ok(Vec::new());
+    }
+
+    let metric = settings.color_metric;
+    let metric_palette: Vec<[f32; 3]> = palette
+        .iter()
+        .map(|c| to_metric_space(metric, [c.lab_l, c.lab_a, c.lab_b]))
+        .collect();
+    let mut used = vec![false; palette.len()];
+    let mut chosen = Vec::with_capacity(k);
+    for centroid in kmeans_lab(&lab_grid, &weights, k) {
+        let query = to_metric_space(metric, centroid);
+        let best = (0..palette.len())
+            .filter(|&i| !used[i])
+            .min_by(|&a, &b| {
+                metric_distance(metric, query, metric_palette[a]).total_cmp(&metric_distance(metric, query, metric_palette[b]))
+            });
+        if let Some(i) = best {
+            used[i] = true;
+            chosen.push(palette[i].floss.clone());
         }
     }
+    Ok(chosen)
+}
 
-    let available_width_px = a4_width_px - (2 * margin_px);
-    let available_height_px = a4_height_px - (2 * margin_px);
-    let offset_x = (available_width_px - gem_art_width_px) / 2;
-    let offset_y = (available_height_px - gem_art_height_px) / 2;
-    let paste_x = margin_px + offset_x;
-    let paste_y = margin_px + offset_y;
+/// Reports how much each selected floss lowers the nearest-color error of the image,
+/// and the `top_k` unselected DMC flosses that would lower the mean error most if added.
+/// Errors are measured in the metric from `settings` over the cells that get a gem from
+/// the image, so empty and background cells are left out.
+pub fn analyze_palette(image_data: &str, selected_colors: &[Color], settings: &GenerationSettings, top_k: usize) -> Result<PaletteReport, String> {
+    PreviewPipeline::default().analyze_palette_with_hooks(image_data, selected_colors, settings, top_k, &GenerationHooks::default())
+}
 
-    let font_data = include_bytes!("../static/DejaVuSans.ttf");
-    let font = Font::try_from_bytes(font_data as &[_]).unwrap();
+fn palette_report(resized_img: &DynamicImage, layout: &GemLayout, lab_grid: &[[f32; 3]], selected_colors: &[Color], settings: &GenerationSettings, top_k: usize, hooks: &GenerationHooks) -> Result<PaletteReport, String> {
+    let (palette, _) = build_palette(selected_colors)?;
+    let empty = empty_cells(resized_img, settings)?;
+    let background = background_mask(settings, lab_grid, layout.num_gems_x, layout.num_gems_y)?;
 
-    for gx in 0..*num_gems_x {
-        for gy in 0..*num_gems_y {
-            let closest_color_index = gem_grid[(gx * num_gems_y + gy) as usize];
-            let color_info = &filtered_dmc_colors[closest_color_index];
-            let gem_rgba = Rgba([color_info.r, color_info.g, color_info.b, 255]);
-
-            for px in 0..*gem_pixels_on_final_image {
-                for py in 0..*gem_pixels_on_final_image {
-                    final_image.put_pixel(
-                        paste_x + gx * gem_pixels_on_final_image + px,
-                        paste_y + gy * gem_pixels_on_final_image + py,
-                        gem_rgba,
-                    );
+    let metric = settings.color_metric;
+    let cells: Vec<[f32; 3]> = lab_grid
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| !empty[i] && !background.get(i).copied().unwrap_or(false))
+        .map(|(_, &lab)| to_metric_space(metric, lab))
+        .collect();
+    let metric_palette: Vec<[f32; 3]> = palette.iter().map(|c| to_metric_space(metric, [c.lab_l, c.lab_a, c.lab_b])).collect();
+
+    // Closest floss per cell, with its distance and the runner-up's distance
+    let closest: Vec<(usize, f32, f32)> = cells
+        .par_iter()
+        .map(|&cell| {
+            let mut best = (0, f32::INFINITY, f32::INFINITY);
+            for (i, &color) in metric_palette.iter().enumerate() {
+                let d = metric_distance(metric, cell, color);
+                if d < best.1 {
+                    best = (i, d, best.1);
+                } else if d < best.2 {
+                    best.2 = d;
                 }
             }
+            best
+        })
+        .collect();
+    let cell_count = closest.len().max(1) as f32;
+    let mean_max = |distances: &mut dyn Iterator<Item = f32>| {
+        let (sum, max) = distances.fold((0.0f32, 0.0f32), |(sum, max), d| (sum + d, max.max(d)));
+        (sum / cell_count, max)
+    };
+    let (mean_delta_e, max_delta_e) = mean_max(&mut closest.iter().map(|&(_, d, _)| d));
 
-            let center_x = (paste_x + gx * gem_pixels_on_final_image + gem_pixels_on_final_image / 2) as i32;
-            let center_y = (paste_y + gy * gem_pixels_on_final_image + gem_pixels_on_final_image / 2) as i32;
-            let radius = ((*gem_pixels_on_final_image / 2) - 2) as i32;
+    let mut contributions: Vec<ColorContribution> = palette
+        .iter()
+        .enumerate()
+        .map(|(i, color)| {
+            // Without a runner-up there is nothing to fall back on
+            let increase = (palette.len() > 1).then(|| {
+                let (mean, max) = mean_max(&mut closest.iter().map(|&(best, d, runner_up)| if best == i { runner_up } else { d }));
+                (mean - mean_delta_e, max - max_delta_e)
+            });
+            ColorContribution {
+                floss: color.floss.clone(),
+                hex: expand_shorthand_hex(&color.hex),
+                gems: closest.iter().filter(|&&(best, _, _)| best == i).count() as u32,
+                mean_delta_e_increase: increase.map(|(mean, _)| mean),
+                max_delta_e_increase: increase.map(|(_, max)| max),
+            }
+        })
+        .collect();
+    let increase_or_infinite = |increase: Option<f32>| increase.unwrap_or(f32::INFINITY);
+    contributions.sort_by(|a, b| increase_or_infinite(b.mean_delta_e_increase).total_cmp(&increase_or_infinite(a.mean_delta_e_increase)));
 
-            let blended_rgba = Rgba([color_info.blended_r, color_info.blended_g, color_info.blended_b, 255]);
-            draw_hollow_circle_mut(&mut final_image, (center_x, center_y), radius, blended_rgba);
+    let (all_dmc_colors, _) = DMC_COLORS_DATA.get_or_init(|| init_dmc_colors_data().expect("Failed to initialize DMC colors data"));
+    let candidates: Vec<&DmcColorPrecomputed> = all_dmc_colors
+        .iter()
+        .filter(|candidate| !palette.iter().any(|c| c.floss.trim() == candidate.floss.trim()))
+        .collect();
+    let mut suggestions: Vec<ColorSuggestion> = hooks
+        .par_map("Analyzing palette", candidates.len(), |i| {
+            let candidate = candidates[i];
+            let color = to_metric_space(metric, [candidate.lab_l, candidate.lab_a, candidate.lab_b]);
+            let distances: Vec<f32> = cells.iter().map(|&cell| metric_distance(metric, cell, color)).collect();
+            let gems = distances.iter().zip(&closest).filter(|&(&d, &(_, best, _))| d < best).count() as u32;
+            let (mean, max) = mean_max(&mut distances.iter().zip(&closest).map(|(&d, &(_, best, _))| d.min(best)));
+            (mean < mean_delta_e).then(|| ColorSuggestion {
+                floss: candidate.floss.clone(),
+                hex: expand_shorthand_hex(&candidate.hex),
+                gems,
+                mean_delta_e_decrease: mean_delta_e - mean,
+                max_delta_e_decrease: max_delta_e - max,
+            })
+        })?
+        .into_iter()
+        .flatten()
+        .collect();
+    suggestions.sort_by(|a, b| b.mean_delta_e_decrease.total_cmp(&a.mean_delta_e_decrease));
+    suggestions.truncate(top_k);
 
-            let letter = letter_map.get(&color_info.floss).unwrap();
-            let scale = Scale::uniform(*gem_pixels_on_final_image as f32 * 0.6);
-            let v_metrics = font.v_metrics(scale);
-            let glyphs: Vec<_> = font.layout(&letter, scale, rusttype::Point { x: 0.0, y: v_metrics.ascent }).collect();
-            let glyphs_width = glyphs.iter().map(|g| g.pixel_bounding_box().unwrap().width() as f32).sum::<f32>();
-            let text_x = center_x - (glyphs_width / 2.0) as i32;
-            let text_y = center_y - (v_metrics.ascent - v_metrics.descent) as i32 / 2;
-            draw_text_mut(&mut final_image, blended_rgba, text_x, text_y, scale, &font, &letter);
+    Ok(PaletteReport { mean_delta_e, max_delta_e, contributions, suggestions })
+}
+
+// Shares of the color limit tried by `auto_tune`.
+const TUNING_COLOR_FRACTIONS: [f32; 4] = [1.0, 0.75, 0.5, 0.25];
+
+/// Searches gem size, fit and palette size within `constraints` and returns the
+/// configuration with the lowest score. The page becomes the canvas; every other setting
+/// comes from `settings`. Colors are picked from `allowed_colors` the same way as
+/// `auto_select_colors`. Candidates are evaluated on a copy of the image downscaled to
+/// about two pixels per scoring sample, which keeps the search fast.
+pub fn auto_tune(image_data: &str, allowed_colors: &[Color], settings: &GenerationSettings, constraints: &TuningConstraints) -> Result<TunedConfiguration, String> {
+    auto_tune_with_hooks(image_data, allowed_colors, settings, constraints, &GenerationHooks::default())
+}
+
+/// `auto_tune` with progress reporting and cancellation, checked between candidates and
+/// while each one is mapped.
+pub fn auto_tune_with_hooks(image_data: &str, allowed_colors: &[Color], settings: &GenerationSettings, constraints: &TuningConstraints, hooks: &GenerationHooks) -> Result<TunedConfiguration, String> {
+    hooks.checkpoint("Preparing image", 0.0)?;
+    let (palette, _) = build_palette(allowed_colors)?;
+    let finest_gem_mm = constraints.gem_sizes_mm.iter().copied().filter(|&s| s > 0.0).fold(f32::INFINITY, f32::min);
+    if !finest_gem_mm.is_finite() {
+        return Err("No gem sizes to try.".to_string());
+    }
+    // Every candidate is scored against the source sampled at this spacing
+    let reference_mm = finest_gem_mm / 2.0;
+
+    let img = prepare_source_image(image_data, settings, &hooks.part(0.0, 0.1))?;
+    let max_side = (2.0 * constraints.canvas_width_mm.max(constraints.canvas_height_mm) / reference_mm).ceil() as u32;
+    let img = if img.width().max(img.height()) > max_side { img.resize(max_side, max_side, FilterType::Triangle) } else { img };
+
+    let color_limit = constraints.max_colors.min(palette.len());
+    let mut color_counts: Vec<usize> = TUNING_COLOR_FRACTIONS
+        .iter()
+        .map(|share| ((color_limit as f32 * share).round() as usize).max(1))
+        .collect();
+    color_counts.dedup();
+    // Unmapped background cells don't count as gems, so the grid size alone can't rule a candidate out
+    let may_leave_background_empty = settings.background_mode != BackgroundMode::Off && settings.background_floss.is_none();
+    let cost = |gems: u32, colors: usize| constraints.cost_per_gem * gems as f32 + constraints.cost_per_color * colors as f32;
+
+    // Each candidate gets an equal share of the progress after preparing the image
+    let candidates = constraints.gem_sizes_mm.iter().filter(|&&s| s > 0.0).count() * 2 * color_counts.len();
+    let candidate_hooks = |done: usize| hooks.part(0.1 + 0.9 * done as f32 / candidates as f32, 0.1 + 0.9 * (done + 1) as f32 / candidates as f32);
+    let mut done = 0;
+
+    let mut best: Option<TunedConfiguration> = None;
+    for &gem_size_mm in constraints.gem_sizes_mm.iter().filter(|&&s| s > 0.0) {
+        for fit_option in [ImageFitOption::Fit, ImageFitOption::Crop] {
+            hooks.checkpoint("Trying settings", 0.1 + 0.9 * done as f32 / candidates as f32)?;
+            // Skipped candidates still count as done
+            let first = done;
+            done += color_counts.len();
+            let candidate_settings = GenerationSettings {
+                gem_size_mm,
+                fit_option,
+                custom_width_mm: Some(constraints.canvas_width_mm),
+                custom_height_mm: Some(constraints.canvas_height_mm),
+                ..settings.clone()
+            };
+            let Ok((resized_img, layout)) = resize_to_gem_grid(&img, &candidate_settings) else {
+                continue;
+            };
+            let filled = empty_cells(&resized_img, &candidate_settings)?.iter().filter(|&&is_empty| !is_empty).count() as u32;
+            if !may_leave_background_empty && constraints.max_gems.is_some_and(|max| filled > max) {
+                continue;
+            }
+            let lab_grid = image_to_lab_grid(&resized_img);
+
+            for (i, &num_colors) in color_counts.iter().enumerate() {
+                let candidate_hooks = candidate_hooks(first + i);
+                candidate_hooks.checkpoint("Trying settings", 0.0)?;
+                let flosses = select_colors(&resized_img, &layout, &palette, num_colors, &candidate_settings)?;
+                let colors: Vec<Color> = allowed_colors
+                    .iter()
+                    .filter(|c| flosses.iter().any(|floss| floss.trim() == c.floss_number.trim()))
+                    .cloned()
+                    .collect();
+                let pattern = map_gem_grid(&resized_img, &lab_grid, &layout, &colors, &candidate_settings, &candidate_hooks)?;
+                let used: HashSet<usize> = pattern.gem_grid.iter().copied().filter(|&i| i != EMPTY_CELL).collect();
+                let total_gems = pattern.gem_grid.iter().filter(|&&i| i != EMPTY_CELL).count() as u32;
+                let candidate_cost = cost(total_gems, used.len());
+                if used.len() > constraints.max_colors
+                    || constraints.max_gems.is_some_and(|max| total_gems > max)
+                    || constraints.max_cost.is_some_and(|max| candidate_cost > max)
+                {
+                    continue;
+                }
+
+                let scale = ((gem_size_mm / reference_mm).round() as u32).max(1);
+                let score = reference_delta_e(&img, &layout, &pattern, settings.color_metric, scale);
+                if best.as_ref().is_none_or(|b| score < b.score) {
+                    best = Some(TunedConfiguration {
+                        settings: candidate_settings.clone(),
+                        flosses,
+                        total_gems,
+                        colors_used: used.len(),
+                        cost: candidate_cost,
+                        score,
+                    });
+                }
+            }
         }
     }
+    hooks.report("Done", 1.0);
+    best.ok_or_else(|| "No configuration meets the constraints.".to_string())
+}
+
+/// Mean deltaE between a pattern and its source resampled to `scale` x `scale` samples
+/// per gem, skipping empty cells.
+fn reference_delta_e(img: &DynamicImage, layout: &GemLayout, pattern: &MappedPattern, metric: ColorMetric, scale: u32) -> f32 {
+    let (width, height) = img.dimensions();
+    let (fx, fy, fw, fh) = layout.source_fraction;
+    let source_rect = (fx * width as f32, fy * height as f32, fw * width as f32, fh * height as f32);
+    let reference = area_average_resize(img, source_rect, layout.num_gems_x * scale, layout.num_gems_y * scale);
+    let palette_labs: Vec<[f32; 3]> = pattern.palette.iter().map(|c| [c.lab_l, c.lab_a, c.lab_b]).collect();
 
+    let (sum, count) = (0..reference.width())
+        .into_par_iter()
+        .map(|x| {
+            (0..reference.height())
+                .filter_map(|y| {
+                    let index = pattern.gem_grid[((x / scale) * layout.num_gems_y + y / scale) as usize];
+                    (index != EMPTY_CELL).then(|| lab_distance(metric, pixel_to_lab(reference.get_pixel(x, y)), palette_labs[index]))
+                })
+                .fold((0.0f32, 0usize), |(sum, count), d| (sum + d, count + 1))
+        })
+        .reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+    if count == 0 { 0.0 } else { sum / count as f32 }
+}
+
+const KMEANS_MAX_ITERATIONS: usize = 20;
+
+fn squared_lab_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let dl = a[0] - b[0];
+    let da = a[1] - b[1];
+    let db = a[2] - b[2];
+    dl * dl + da * da + db * db
+}
+
+fn closest_index(lab: [f32; 3], centroids: &[[f32; 3]]) -> usize {
+    let mut best = 0;
+    let mut best_dist = f32::INFINITY;
+    for (i, c) in centroids.iter().enumerate() {
+        let d = squared_lab_distance(lab, *c);
+        if d < best_dist {
+            best_dist = d;
+            best = i;
+        }
+    }
+    best
+}
+
+/// Deterministic weighted k-means in Lab, seeded with farthest-point initialisation.
+/// Returns `k` centroids sorted by total cluster weight (largest first), or none when
+/// `points` is empty or carries no weight. With fewer distinct points than `k`, some
+/// centroids repeat.
+fn kmeans_lab(points: &[[f32; 3]], weights: &[f32], k: usize) -> Vec<[f32; 3]> {
+    if points.is_empty() {
+        return Vec::new();
+    }
+
+    let n: f32 = weights.iter().sum();
+    if n <= 0.0 {
+        return Vec::new();
+    }
+    let mean = points.iter().zip(weights).fold([0.0f32; 3], |acc, (p, &w)| {
+        [acc[0] + p[0] * w / n, acc[1] + p[1] * w / n, acc[2] + p[2] * w / n]
+    });
+    let mut centroids = vec![points[closest_index(mean, points)]];
+    let mut min_dist: Vec<f32> = points.iter().map(|p| squared_lab_distance(*p, centroids[0])).collect();
+    while centroids.len() < k {
+        let (farthest, _) = min_dist
+            .iter()
+            .zip(weights)
+            .enumerate()
+            .fold((0, f32::NEG_INFINITY), |best, (i, (&d, &w))| if d * w > best.1 { (i, d * w) } else { best });
+        let next = points[farthest];
+        centroids.push(next);
+        for (d, p) in min_dist.iter_mut().zip(points) {
+            *d = d.min(squared_lab_distance(*p, next));
+        }
+    }
+
+    let mut sizes = vec![0.0f32; k];
+    for _ in 0..KMEANS_MAX_ITERATIONS {
+        let assignments: Vec<usize> = points.par_iter().map(|p| closest_index(*p, &centroids)).collect();
+        let mut sums = vec![[0.0f32; 3]; k];
+        sizes = vec![0.0f32; k];
+        for ((p, &w), &c) in points.iter().zip(weights).zip(&assignments) {
+            sums[c][0] += p[0] * w;
+            sums[c][1] += p[1] * w;
+            sums[c][2] += p[2] * w;
+            sizes[c] += w;
+        }
+        let mut moved = false;
+        for c in 0..k {
+            // Empty clusters keep their previous centre
+            if sizes[c] == 0.0 {
+                continue;
+            }
+            let count = sizes[c];
+            let updated = [sums[c][0] / count, sums[c][1] / count, sums[c][2] / count];
+            if squared_lab_distance(updated, centroids[c]) > 1e-4 {
+                moved = true;
+            }
+            centroids[c] = updated;
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    let mut order: Vec<usize> = (0..k).collect();
+    order.sort_by(|&a, &b| sizes[b].total_cmp(&sizes[a]));
+    order.into_iter().map(|c| centroids[c]).collect()
+}
+
+// Pixel rows held in memory at once while rendering the print page
+const CHART_BAND_ROWS: u32 = 256;
+
+pub fn generate_gem_art_final(gem_art_data: &GemArtData) -> Result<String, String> {
+    generate_gem_art_final_with_hooks(gem_art_data, &GenerationHooks::default())
+}
+
+/// `generate_gem_art_final` reporting progress to, and stopping when cancelled
+/// through, `hooks`. Cancellation is checked between bands.
+pub fn generate_gem_art_final_with_hooks(gem_art_data: &GemArtData, hooks: &GenerationHooks) -> Result<String, String> {
     let mut buf = Vec::new();
-    final_image.write_to(&mut std::io::Cursor::new(&mut buf), image::ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    write_gem_art_png_with_hooks(gem_art_data, &mut buf, hooks)?;
     let encoded_data = general_purpose::STANDARD.encode(&buf);
     let image_data_url = format!("data:image/png;base64,{}", encoded_data);
 
     Ok(image_data_url)
 }
 
-pub fn generate_gem_art(image_data: &str, selected_colors: &Vec<Color>, margin_mm: f32, fit_option: &ImageFitOption, mapping_mode: &ColorMappingMode, mapping_weight: f32, custom_width_mm: Option<f32>, custom_height_mm: Option<f32>, gem_size_mm: f32) -> Result<(String, Vec<GemCount>), String> {
+/// Streams the 300 DPI page into `writer` as a PNG, one band at a time, so memory use
+/// does not grow with the canvas size.
+pub fn write_gem_art_png<W: std::io::Write>(gem_art_data: &GemArtData, writer: W) -> Result<(), String> {
+    write_gem_art_png_with_hooks(gem_art_data, writer, &GenerationHooks::default())
+}
+
+/// `write_gem_art_png` with progress reporting and cancellation between bands. The
+/// PNG is left unfinished when cancelled.
+pub fn write_gem_art_png_with_hooks<W: std::io::Write>(gem_art_data: &GemArtData, writer: W, hooks: &GenerationHooks) -> Result<(), String> {
+    let mut encoder = png::Encoder::new(writer, gem_art_data.a4_width_px, gem_art_data.a4_height_px);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Default);
+    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+    let mut png_writer = encoder.write_header().map_err(|e| e.to_string())?;
+    let mut stream = png_writer.stream_writer().map_err(|e| e.to_string())?;
+    let page_height = gem_art_data.a4_height_px.max(1) as f32;
+    render_gem_art_bands(gem_art_data, CHART_BAND_ROWS, |top, band| {
+        hooks.checkpoint("Drawing chart", top as f32 / page_height)?;
+        std::io::Write::write_all(&mut stream, band.as_raw()).map_err(|e| e.to_string())
+    })?;
+    stream.finish().map_err(|e| e.to_string())?;
+    hooks.report("Done", 1.0);
+    Ok(())
+}
+
+/// Renders the print page top to bottom in bands of at most `band_rows` pixel rows,
+/// passing each band and its first row to `sink`. Stacked together the bands are
+/// exactly the full page.
+pub fn render_gem_art_bands(
+    gem_art_data: &GemArtData,
+    band_rows: u32,
+    mut sink: impl FnMut(u32, &RgbaImage) -> Result<(), String>,
+) -> Result<(), String> {
+    let GemArtData { num_gems_x, num_gems_y, gem_pixels_on_final_image, a4_width_px, a4_height_px, .. } = *gem_art_data;
+    let (paste_x, paste_y) = gem_art_data.grid_origin();
+    let gem_size = gem_pixels_on_final_image.max(1);
+    let band_rows = band_rows.max(1);
+
+    let font_data = include_bytes!("../static/DejaVuSans.ttf");
+    let font = Font::try_from_bytes(font_data as &[_]).ok_or("Failed to load chart font")?;
+
+    let mut top = 0;
+    while top < a4_height_px {
+        let rows = band_rows.min(a4_height_px - top);
+        let mut band = RgbaImage::from_pixel(a4_width_px, rows, Rgba([255, 255, 255, 255]));
+        // Letters may spill past their cell, so the gem rows either side are drawn too
+        let first_gy = (top.saturating_sub(paste_y) / gem_size).saturating_sub(1);
+        let end_gy = ((top + rows).saturating_sub(paste_y) / gem_size + 2).min(num_gems_y);
+        // Cells are drawn in the same column-major order as a full-page render, so
+        // overlapping letters and outlines stack up the same way
+        for gx in 0..num_gems_x {
+            for gy in first_gy..end_gy {
+                draw_chart_cell(&mut band, gem_art_data, &font, gx, gy, (paste_x as i32, paste_y as i32 - top as i32));
+            }
+        }
+        sink(top, &band)?;
+        top += rows;
+    }
+    Ok(())
+}
+
+/// Draws one gem (fill, outline and letter) with the grid's top-left corner at `origin`,
+/// clipped to `image`.
+fn draw_chart_cell(image: &mut RgbaImage, gem_art_data: &GemArtData, font: &Font, gx: u32, gy: u32, origin: (i32, i32)) {
+    let GemArtData { gem_grid, letter_map, num_gems_y, gem_pixels_on_final_image, filtered_dmc_colors, .. } = gem_art_data;
+    let closest_color_index = gem_grid[(gx * num_gems_y + gy) as usize];
+    if closest_color_index == EMPTY_CELL {
+        return;
+    }
+    let color_info = &filtered_dmc_colors[closest_color_index];
+    let gem_rgba = Rgba([color_info.r, color_info.g, color_info.b, 255]);
+    let gem_size = *gem_pixels_on_final_image as i32;
+    let left = origin.0 + gx as i32 * gem_size;
+    let top = origin.1 + gy as i32 * gem_size;
+
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    for y in top.max(0)..(top + gem_size).min(height) {
+        for x in left.max(0)..(left + gem_size).min(width) {
+            image.put_pixel(x as u32, y as u32, gem_rgba);
+        }
+    }
+
+    let center_x = left + gem_size / 2;
+    let center_y = top + gem_size / 2;
+    let radius = gem_size / 2 - 2;
+
+    let blended_rgba = Rgba([color_info.blended_r, color_info.blended_g, color_info.blended_b, 255]);
+    draw_hollow_circle_mut(image, (center_x, center_y), radius, blended_rgba);
+
+    let letter = letter_map.get(&color_info.floss).unwrap();
+    let scale = Scale::uniform(gem_size as f32 * 0.6);
+    let v_metrics = font.v_metrics(scale);
+    let glyphs: Vec<_> = font.layout(letter, scale, rusttype::Point { x: 0.0, y: v_metrics.ascent }).collect();
+    let glyphs_width = glyphs.iter().map(|g| g.pixel_bounding_box().unwrap().width() as f32).sum::<f32>();
+    let text_x = center_x - (glyphs_width / 2.0) as i32;
+    let text_y = center_y - (v_metrics.ascent - v_metrics.descent) as i32 / 2;
+    draw_text_mut(image, blended_rgba, text_x, text_y, scale, font, letter);
+}
+
+/// Renders the per-cell errors as a one-pixel-per-gem heatmap (see `heatmap_color`),
+/// with empty cells left transparent.
+pub fn generate_error_heatmap(gem_art_data: &GemArtData) -> Result<String, String> {
+    let GemArtData { gem_grid, cell_errors, num_gems_x, num_gems_y, .. } = gem_art_data;
+    let heatmap = RgbaImage::from_fn(*num_gems_x, *num_gems_y, |gx, gy| {
+        let cell = (gx * num_gems_y + gy) as usize;
+        if gem_grid[cell] == EMPTY_CELL {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let [r, g, b] = heatmap_color(cell_errors[cell]);
+        Rgba([r, g, b, 255])
+    });
+
+    let mut buf = Vec::new();
+    DynamicImage::ImageRgba8(heatmap).write_to(&mut std::io::Cursor::new(&mut buf), image::ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(format!("data:image/png;base64,{}", general_purpose::STANDARD.encode(&buf)))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn generate_gem_art(image_data: &str, selected_colors: &[Color], margin_mm: f32, fit_option: &ImageFitOption, mapping_mode: &ColorMappingMode, mapping_weight: f32, custom_width_mm: Option<f32>, custom_height_mm: Option<f32>, gem_size_mm: f32) -> Result<(String, Vec<GemCount>), String> {
     let (_preview_image_data, sorted_counts, gem_art_data) = generate_gem_art_preview(image_data, selected_colors, margin_mm, fit_option, mapping_mode, mapping_weight, custom_width_mm, custom_height_mm, gem_size_mm)?;
     let final_image_data = generate_gem_art_final(&gem_art_data)?;
     Ok((final_image_data, sorted_counts))
 }
 
-pub fn generate_text_image(gem_counts: &Vec<GemCount>) -> Result<String, String> {
+pub fn generate_text_image(gem_counts: &[GemCount]) -> Result<String, String> {
     let a4_width_mm = 210.0;
     let a4_height_mm = 297.0;
     let margin_mm = 10.0;
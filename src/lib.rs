@@ -4,9 +4,25 @@ pub mod dmc_colors;
 pub mod models;
 pub mod utils;
 pub mod image_processing;
+pub mod dithering;
+pub mod color_distance;
+pub mod palette_index;
+pub mod color_lut;
+pub mod cleanup;
+pub mod adjustments;
+pub mod lightness;
+pub mod background;
+pub mod shapes;
+pub mod quality;
+pub mod progress;
+pub mod worker;
 pub mod components;
 
 #[wasm_bindgen(start)]
 pub fn run_app() {
+    // The generation worker loads this module too, and has no page to render into
+    if web_sys::window().is_none() {
+        return;
+    }
     yew::Renderer::<components::App>::new().render();
 }
\ No newline at end of file
@@ -0,0 +1,204 @@
+use crate::models::ColorMappingMode;
+
+// Share of cells ignored at each end of the L* range by the percentile stretch, so a
+// few specular highlights or deep shadows don't squash everything else.
+const PERCENTILE_CLIP: f32 = 0.01;
+// One histogram bin per L* unit.
+const HISTOGRAM_BINS: usize = 101;
+// CLAHE bin limit as a multiple of the mean bin count of a tile.
+const CLAHE_CLIP_LIMIT: f32 = 3.0;
+const CLAHE_MAX_TILES: u32 = 8;
+// Smallest tile side in gems, so small patterns don't get noisy local histograms.
+const CLAHE_MIN_TILE_SIZE: u32 = 16;
+
+/// Linear mapping of image L* onto the palette's L* range.
+#[derive(Clone, Copy, Debug)]
+pub struct LightnessStretch {
+    pub img_l_min: f32,
+    pub img_l_max: f32,
+    pub pal_l_min: f32,
+    pub pal_l_max: f32,
+}
+
+impl LightnessStretch {
+    pub fn apply(&self, l: f32) -> f32 {
+        let scaled = self.pal_l_min + (l - self.img_l_min) * (self.pal_l_max - self.pal_l_min) / (self.img_l_max - self.img_l_min);
+        scaled.max(self.pal_l_min).min(self.pal_l_max)
+    }
+}
+
+/// Monotonic L* transfer curve sampled at every integer L*.
+#[derive(Clone, Debug)]
+pub struct LightnessCurve {
+    lut: Vec<f32>,
+}
+
+impl LightnessCurve {
+    /// Builds the curve that sends each histogram quantile of the image to the same
+    /// quantile of the palette's lightness distribution (`sorted_palette_ls` ascending).
+    fn from_histogram(histogram: &[f32], sorted_palette_ls: &[f32]) -> Self {
+        let total: f32 = histogram.iter().sum();
+        let mut below = 0.0;
+        let lut = histogram
+            .iter()
+            .map(|&count| {
+                // Midpoint CDF, so a single populated bin lands in the middle of the palette
+                let quantile = if total > 0.0 { (below + count / 2.0) / total } else { 0.5 };
+                below += count;
+                palette_quantile(sorted_palette_ls, quantile)
+            })
+            .collect();
+        LightnessCurve { lut }
+    }
+
+    pub fn apply(&self, l: f32) -> f32 {
+        let x = l.clamp(0.0, (HISTOGRAM_BINS - 1) as f32);
+        let i = (x.floor() as usize).min(HISTOGRAM_BINS - 2);
+        let t = x - i as f32;
+        self.lut[i] + (self.lut[i + 1] - self.lut[i]) * t
+    }
+}
+
+/// Remaps image L* towards the palette's lightness range before matching. Tiled maps
+/// depend on the cell, so every lookup takes the column-major cell index.
+#[derive(Clone, Debug)]
+pub enum LightnessMap {
+    Linear(LightnessStretch),
+    Curve(LightnessCurve),
+    Tiled {
+        curves: Vec<LightnessCurve>,
+        tiles_x: u32,
+        tiles_y: u32,
+        num_gems_x: u32,
+        num_gems_y: u32,
+    },
+}
+
+impl LightnessMap {
+    pub fn apply(&self, cell: usize, l: f32) -> f32 {
+        match self {
+            LightnessMap::Linear(stretch) => stretch.apply(l),
+            LightnessMap::Curve(curve) => curve.apply(l),
+            LightnessMap::Tiled { curves, tiles_x, tiles_y, num_gems_x, num_gems_y } => {
+                let gx = (cell / *num_gems_y as usize) as u32;
+                let gy = (cell % *num_gems_y as usize) as u32;
+                // Bilinear blend between the four nearest tile centres
+                let (x0, x1, tx) = tile_neighbours(gx, *num_gems_x, *tiles_x);
+                let (y0, y1, ty) = tile_neighbours(gy, *num_gems_y, *tiles_y);
+                let at = |x: u32, y: u32| curves[(y * tiles_x + x) as usize].apply(l);
+                let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+                let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+                top * (1.0 - ty) + bottom * ty
+            }
+        }
+    }
+}
+
+/// Builds the lightness map for a mapping mode from the gem grid's L* values
+/// (column-major, `gx * num_gems_y + gy`), leaving out cells flagged in `excluded`
+/// (empty excludes nothing). Returns `None` for `Nearest`, or when the image or
+/// palette has no lightness range to map.
+pub fn build_lightness_map(
+    mode: &ColorMappingMode,
+    lab_grid: &[[f32; 3]],
+    num_gems_x: u32,
+    num_gems_y: u32,
+    palette_ls: &[f32],
+    excluded: &[bool],
+) -> Option<LightnessMap> {
+    let is_included = |i: usize| !excluded.get(i).copied().unwrap_or(false);
+    let image_ls = || lab_grid.iter().enumerate().filter(|(i, _)| is_included(*i)).map(|(_, lab)| lab[0]);
+    let (pal_l_min, pal_l_max) = min_max(palette_ls.iter().copied());
+    let (img_l_min, img_l_max) = min_max(image_ls());
+    if !(img_l_max > img_l_min && pal_l_max > pal_l_min) {
+        return None;
+    }
+
+    let mut sorted_palette_ls = palette_ls.to_vec();
+    sorted_palette_ls.sort_by(f32::total_cmp);
+
+    match mode {
+        ColorMappingMode::Nearest => None,
+        ColorMappingMode::AdaptiveLightnessStretch | ColorMappingMode::AdaptiveLightnessWeighted => {
+            Some(LightnessMap::Linear(LightnessStretch { img_l_min, img_l_max, pal_l_min, pal_l_max }))
+        }
+        ColorMappingMode::PercentileStretch => {
+            let mut ls: Vec<f32> = image_ls().collect();
+            ls.sort_by(f32::total_cmp);
+            let last = ls.len() - 1;
+            let low = ls[(last as f32 * PERCENTILE_CLIP).round() as usize];
+            let high = ls[(last as f32 * (1.0 - PERCENTILE_CLIP)).round() as usize];
+            let (img_l_min, img_l_max) = if high > low { (low, high) } else { (img_l_min, img_l_max) };
+            Some(LightnessMap::Linear(LightnessStretch { img_l_min, img_l_max, pal_l_min, pal_l_max }))
+        }
+        ColorMappingMode::HistogramEqualization => {
+            let histogram = lightness_histogram(image_ls());
+            Some(LightnessMap::Curve(LightnessCurve::from_histogram(&histogram, &sorted_palette_ls)))
+        }
+        ColorMappingMode::Clahe => {
+            let tiles_x = (num_gems_x / CLAHE_MIN_TILE_SIZE).clamp(1, CLAHE_MAX_TILES);
+            let tiles_y = (num_gems_y / CLAHE_MIN_TILE_SIZE).clamp(1, CLAHE_MAX_TILES);
+            let mut curves = Vec::with_capacity((tiles_x * tiles_y) as usize);
+            for ty in 0..tiles_y {
+                let (y_start, y_end) = tile_span(ty, tiles_y, num_gems_y);
+                for tx in 0..tiles_x {
+                    let (x_start, x_end) = tile_span(tx, tiles_x, num_gems_x);
+                    let cells = (x_start..x_end)
+                        .flat_map(|gx| (y_start..y_end).map(move |gy| (gx * num_gems_y + gy) as usize));
+                    let mut histogram = lightness_histogram(cells.filter(|&i| is_included(i)).map(|i| lab_grid[i][0]));
+                    clip_histogram(&mut histogram, CLAHE_CLIP_LIMIT);
+                    curves.push(LightnessCurve::from_histogram(&histogram, &sorted_palette_ls));
+                }
+            }
+            Some(LightnessMap::Tiled { curves, tiles_x, tiles_y, num_gems_x, num_gems_y })
+        }
+    }
+}
+
+fn min_max(values: impl Iterator<Item = f32>) -> (f32, f32) {
+    values.fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)))
+}
+
+fn lightness_histogram(ls: impl Iterator<Item = f32>) -> Vec<f32> {
+    let mut histogram = vec![0.0f32; HISTOGRAM_BINS];
+    for l in ls {
+        histogram[l.round().clamp(0.0, (HISTOGRAM_BINS - 1) as f32) as usize] += 1.0;
+    }
+    histogram
+}
+
+/// Caps every bin at `limit` times the mean bin count and spreads the excess evenly,
+/// which bounds how much contrast CLAHE can add to flat tiles.
+fn clip_histogram(histogram: &mut [f32], limit: f32) {
+    let total: f32 = histogram.iter().sum();
+    let cap = limit * total / histogram.len() as f32;
+    let mut excess = 0.0;
+    for count in histogram.iter_mut() {
+        if *count > cap {
+            excess += *count - cap;
+            *count = cap;
+        }
+    }
+    let share = excess / histogram.len() as f32;
+    histogram.iter_mut().for_each(|count| *count += share);
+}
+
+/// Palette L* at a quantile, treating each palette color as an equal share of the range.
+fn palette_quantile(sorted: &[f32], quantile: f32) -> f32 {
+    let position = (quantile * sorted.len() as f32 - 0.5).clamp(0.0, (sorted.len() - 1) as f32);
+    let i = position.floor() as usize;
+    let j = (i + 1).min(sorted.len() - 1);
+    sorted[i] + (sorted[j] - sorted[i]) * (position - i as f32)
+}
+
+fn tile_span(tile: u32, tiles: u32, cells: u32) -> (u32, u32) {
+    (tile * cells / tiles, (tile + 1) * cells / tiles)
+}
+
+/// The two tiles whose centres surround a cell, and the blend factor towards the second.
+fn tile_neighbours(cell: u32, cells: u32, tiles: u32) -> (u32, u32, f32) {
+    let position = ((cell as f32 + 0.5) * tiles as f32 / cells as f32 - 0.5).clamp(0.0, (tiles - 1) as f32);
+    let first = position.floor() as u32;
+    let second = (first + 1).min(tiles - 1);
+    (first, second, position - first as f32)
+}
@@ -1,4 +1,8 @@
 use serde::{Deserialize, Serialize};
+use image::imageops::FilterType;
+
+/// Gem grid value for a cell that gets no gem, e.g. a transparent area or an unmapped background.
+pub const EMPTY_CELL: usize = usize::MAX;
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct GemCount {
@@ -7,17 +11,329 @@ pub struct GemCount {
     pub hex: String,
 }
 
-#[derive(Clone, PartialEq)]
+/// What one selected floss contributes to the nearest-color mapping of an image.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ColorContribution {
+    pub floss: String,
+    pub hex: String,
+    /// Gems mapped to this floss.
+    pub gems: u32,
+    /// Rise in mean deltaE if the floss were removed, `None` for the only floss, which
+    /// can't be.
+    pub mean_delta_e_increase: Option<f32>,
+    /// Rise in max deltaE if the floss were removed, `None` for the only floss.
+    pub max_delta_e_increase: Option<f32>,
+}
+
+/// An unselected floss and how much adding it would lower the mapping error.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ColorSuggestion {
+    pub floss: String,
+    pub hex: String,
+    /// Gems that would switch to this floss.
+    pub gems: u32,
+    pub mean_delta_e_decrease: f32,
+    pub max_delta_e_decrease: f32,
+}
+
+/// Result of `analyze_palette`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PaletteReport {
+    /// Mean and max deltaE of the nearest-color mapping with the current selection.
+    pub mean_delta_e: f32,
+    pub max_delta_e: f32,
+    /// One entry per selected floss, largest mean increase first.
+    pub contributions: Vec<ColorContribution>,
+    /// Unselected flosses that lower the error, largest mean decrease first.
+    pub suggestions: Vec<ColorSuggestion>,
+}
+
+/// How closely a generated pattern follows the resized source image.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct QualityMetrics {
+    /// DeltaE between each source cell and its floss, in the selected metric, over the
+    /// cells that get a gem.
+    pub mean_delta_e: f32,
+    pub p95_delta_e: f32,
+    pub max_delta_e: f32,
+    /// Structural similarity of the blurred lightness, 1.0 for a perfect match.
+    pub ssim: f32,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum ImageFitOption {
     Fit,
     Crop,
 }
 
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum ColorMappingMode {
     Nearest,
     AdaptiveLightnessStretch,
     AdaptiveLightnessWeighted,
+    /// Like the weighted stretch, but ignores the darkest and brightest 1% of cells.
+    PercentileStretch,
+    /// Matches the image's L* histogram to the palette's lightness distribution.
+    HistogramEqualization,
+    /// Contrast-limited histogram equalization computed per tile of the gem grid.
+    Clahe,
+}
+
+/// How the source image is resampled down to one pixel per gem.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ResampleFilter {
+    Nearest,
+    /// Averages every source pixel covering a gem cell in linear RGB.
+    AreaAverage,
+    Triangle,
+    CatmullRom,
+    Lanczos3,
+}
+
+impl ResampleFilter {
+    /// The matching `image` crate filter, or `None` for area averaging.
+    pub fn filter_type(&self) -> Option<FilterType> {
+        match self {
+            ResampleFilter::Nearest => Some(FilterType::Nearest),
+            ResampleFilter::AreaAverage => None,
+            ResampleFilter::Triangle => Some(FilterType::Triangle),
+            ResampleFilter::CatmullRom => Some(FilterType::CatmullRom),
+            ResampleFilter::Lanczos3 => Some(FilterType::Lanczos3),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum ColorMetric {
+    Cie76,
+    Cie94,
+    Ciede2000,
+    Oklab,
+    Redmean,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum DitheringMode {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Stucki,
+    Bayer,
+    BlueNoise,
+}
+
+/// Color corrections applied to the decoded image before it is fitted to the page.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ImageAdjustments {
+    /// Offset added to every channel, -1.0 to 1.0.
+    pub brightness: f32,
+    /// -1.0 (flat gray) to 1.0 (double contrast).
+    pub contrast: f32,
+    /// -1.0 (grayscale) to 1.0 (double saturation).
+    pub saturation: f32,
+    /// Values above 1.0 brighten midtones, below 1.0 darken them.
+    pub gamma: f32,
+    /// -1.0 (cooler) to 1.0 (warmer).
+    pub temperature: f32,
+    /// -1.0 (greener) to 1.0 (more magenta).
+    pub tint: f32,
+    pub auto_white_balance: bool,
+}
+
+impl ImageAdjustments {
+    pub fn is_identity(&self) -> bool {
+        *self == ImageAdjustments::default()
+    }
+}
+
+impl Default for ImageAdjustments {
+    fn default() -> Self {
+        ImageAdjustments {
+            brightness: 0.0,
+            contrast: 0.0,
+            saturation: 0.0,
+            gamma: 1.0,
+            temperature: 0.0,
+            tint: 0.0,
+            auto_white_balance: false,
+        }
+    }
+}
+
+/// How background cells are detected before they are flattened to one floss.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum BackgroundMode {
+    Off,
+    /// Flood fill inwards from the borders of the image.
+    FloodFillFromBorders,
+    /// Every cell close to a chosen key color.
+    ColorKey,
+}
+
+/// Outline of the canvas. Cells whose centres fall outside it get no gem.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum CanvasShape {
+    Rectangle,
+    Circle,
+    Oval,
+    Heart,
+    /// Uses the black and white mask in `GenerationSettings::custom_shape_mask`.
+    Custom,
+}
+
+/// Part of the image restricted to its own set of flosses. Shapes are given relative
+/// to the source image, so regions stay put when gem size, margins or fit change.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PaletteRegion {
+    pub name: String,
+    /// Data URL of a mask covering the source image; bright areas belong to the region.
+    pub mask: Option<String>,
+    /// Polygon vertices as (x, y) fractions of the source image width and height.
+    pub polygon: Option<Vec<(f32, f32)>>,
+    /// Floss numbers allowed inside the region. If none of them are in the selection,
+    /// the region falls back to the full selection.
+    pub allowed_flosses: Vec<String>,
+}
+
+/// All parameters that control a single gem art generation run.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct GenerationSettings {
+    pub margin_mm: f32,
+    pub fit_option: ImageFitOption,
+    pub mapping_mode: ColorMappingMode,
+    pub mapping_weight: f32,
+    pub custom_width_mm: Option<f32>,
+    pub custom_height_mm: Option<f32>,
+    pub gem_size_mm: f32,
+    pub adjustments: ImageAdjustments,
+    /// Median denoise blend, 0.0 (off) to 1.0.
+    pub denoise_strength: f32,
+    /// Median neighbourhood radius in source pixels.
+    pub denoise_radius: u32,
+    /// Unsharp mask amount, 0.0 (off) upwards.
+    pub sharpen_strength: f32,
+    /// Unsharp mask blur sigma in source pixels.
+    pub sharpen_radius: f32,
+    /// Defaults to `Nearest`, which keeps results the same as before the filter could be
+    /// chosen. The app starts on `AreaAverage` instead, as it holds up better on photos,
+    /// so set that to get the same chart as the app.
+    pub resample_filter: ResampleFilter,
+    pub color_metric: ColorMetric,
+    pub dithering_mode: DitheringMode,
+    /// Scales the diffused error (or ordered threshold) between 0.0 and 1.0.
+    pub dither_strength: f32,
+    /// Only dither in smooth areas so edges stay crisp.
+    pub adaptive_dithering: bool,
+    /// Reuse a cached sRGB lookup table for undithered nearest-color matching. Results
+    /// are the same either way (see `ColorLut`); the table just skips repeated searches.
+    pub color_lut: bool,
+    /// Colors used on fewer gems than this are dropped and remapped (0 disables).
+    pub min_gems_per_color: u32,
+    /// Largest connected patch of gems treated as confetti (0 disables the cleanup).
+    pub confetti_max_size: u32,
+    /// Maximum color difference allowed when merging a confetti patch into its surroundings.
+    pub confetti_max_delta_e: f32,
+    /// Grayscale image data URL covering the source image; brighter areas get more
+    /// accurate colors during mapping, dithering and palette reduction.
+    pub importance_mask: Option<String>,
+    /// Areas restricted to their own flosses; later regions take precedence where they overlap.
+    pub palette_regions: Vec<PaletteRegion>,
+    pub background_mode: BackgroundMode,
+    /// Hex color treated as background in `ColorKey` mode.
+    pub background_key_color: String,
+    /// Largest color difference (in the selected metric) still counted as background.
+    pub background_tolerance: f32,
+    /// Floss for background cells, added to the palette if needed; `None` leaves them empty.
+    pub background_floss: Option<String>,
+    /// Cells whose opacity (0.0 to 1.0) falls below this get no gem; 0.0 maps every cell.
+    pub alpha_threshold: f32,
+    pub canvas_shape: CanvasShape,
+    /// Image data URL stretched over the gem grid for `CanvasShape::Custom`; white areas
+    /// get gems. Without a mask the custom shape is the full rectangle.
+    pub custom_shape_mask: Option<String>,
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        GenerationSettings {
+            margin_mm: 30.0,
+            fit_option: ImageFitOption::Fit,
+            mapping_mode: ColorMappingMode::Nearest,
+            mapping_weight: 0.0,
+            custom_width_mm: Some(210.0),
+            custom_height_mm: Some(297.0),
+            gem_size_mm: 2.7,
+            adjustments: ImageAdjustments::default(),
+            denoise_strength: 0.0,
+            denoise_radius: 2,
+            sharpen_strength: 0.0,
+            sharpen_radius: 2.0,
+            resample_filter: ResampleFilter::Nearest,
+            color_metric: ColorMetric::Cie76,
+            dithering_mode: DitheringMode::None,
+            dither_strength: 1.0,
+            adaptive_dithering: false,
+            color_lut: true,
+            min_gems_per_color: 0,
+            confetti_max_size: 0,
+            confetti_max_delta_e: 10.0,
+            importance_mask: None,
+            palette_regions: Vec::new(),
+            background_mode: BackgroundMode::Off,
+            background_key_color: "#ffffff".to_string(),
+            background_tolerance: 10.0,
+            background_floss: None,
+            alpha_threshold: 0.5,
+            canvas_shape: CanvasShape::Rectangle,
+            custom_shape_mask: None,
+        }
+    }
+}
+
+/// Limits for `auto_tune`. `None` leaves a limit off.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TuningConstraints {
+    pub max_colors: usize,
+    pub max_gems: Option<u32>,
+    /// Upper bound on `cost_per_gem * gems + cost_per_color * colors`.
+    pub max_cost: Option<f32>,
+    pub cost_per_gem: f32,
+    pub cost_per_color: f32,
+    /// Canvas the pattern has to fit, margins included.
+    pub canvas_width_mm: f32,
+    pub canvas_height_mm: f32,
+    /// Gem sizes to try.
+    pub gem_sizes_mm: Vec<f32>,
+}
+
+impl Default for TuningConstraints {
+    fn default() -> Self {
+        TuningConstraints {
+            max_colors: 25,
+            max_gems: None,
+            max_cost: None,
+            cost_per_gem: 0.0,
+            cost_per_color: 0.0,
+            canvas_width_mm: 300.0,
+            canvas_height_mm: 400.0,
+            gem_sizes_mm: vec![2.5, 2.7, 2.8, 3.0, 3.5, 4.0, 5.0],
+        }
+    }
+}
+
+/// Best configuration found by `auto_tune`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TunedConfiguration {
+    /// The input settings with gem size, fit and page size replaced.
+    pub settings: GenerationSettings,
+    /// Flosses to select, largest cluster first.
+    pub flosses: Vec<String>,
+    pub total_gems: u32,
+    pub colors_used: usize,
+    pub cost: f32,
+    /// Mean deltaE against the source sampled at a resolution shared by all candidates,
+    /// so coarser grids pay for the detail they lose. Lower is better.
+    pub score: f32,
 }
 
 #[derive(Clone, PartialEq, Default, Deserialize, Serialize)]
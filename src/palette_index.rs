@@ -0,0 +1,79 @@
+// Added to every pruning bound to absorb f32 rounding, so a candidate is only skipped
+// when it loses by a clear margin and ties are still resolved by palette order.
+const BOUND_SLACK: f32 = 1e-3;
+
+/// Palette entries sorted by L*, for the blended lightness/color score of the adaptive
+/// mapping modes:
+///
+/// `score(i) = w * |target_l - L_i| + (1 - w) * color_distance(i)`
+///
+/// With `color_distance(i) >= k * |query_l - L_i|` (see `lightness_bound_factor`), the
+/// score is bounded below by a convex function of `L_i`. The search walks outwards from
+/// its minimum and stops in each direction once the bound exceeds the best score found,
+/// which gives exactly the result of a full scan, including the lowest-index tie-break.
+#[derive(Clone, Debug)]
+pub struct LightnessIndex {
+    // Palette indices in ascending L* order (ties by index)
+    order: Vec<usize>,
+    sorted_ls: Vec<f32>,
+}
+
+impl LightnessIndex {
+    pub fn new(palette_ls: &[f32]) -> Self {
+        let mut order: Vec<usize> = (0..palette_ls.len()).collect();
+        order.sort_by(|&a, &b| palette_ls[a].total_cmp(&palette_ls[b]).then(a.cmp(&b)));
+        let sorted_ls = order.iter().map(|&i| palette_ls[i]).collect();
+        LightnessIndex { order, sorted_ls }
+    }
+
+    /// Lowest-scoring palette entry passing `allowed`, or `None` if there is none.
+    /// `target_l` is the remapped L* the lightness term aims for, `query_l` the L* of the
+    /// color itself, and `bound_factor` the metric's `k`.
+    pub fn nearest(
+        &self,
+        target_l: f32,
+        query_l: f32,
+        weight: f32,
+        bound_factor: f32,
+        allowed: impl Fn(usize) -> bool,
+        color_distance: impl Fn(usize) -> f32,
+    ) -> Option<usize> {
+        let lightness_delta = |l: f32| if weight > 0.0 { (target_l - l).abs() } else { 0.0 };
+        let bound = |l: f32| weight * lightness_delta(l) + (1.0 - weight) * (bound_factor * (query_l - l).abs());
+        // The bound is smallest at whichever kink has the steeper slope
+        let anchor = if weight >= (1.0 - weight) * bound_factor { target_l } else { query_l };
+        let split = self.sorted_ls.partition_point(|&l| l < anchor);
+
+        let mut best: Option<(f32, usize)> = None;
+        let mut visit = |position: usize| -> bool {
+            let l = self.sorted_ls[position];
+            if best.is_some_and(|(score, _)| bound(l) > score + BOUND_SLACK) {
+                return false;
+            }
+            let i = self.order[position];
+            if allowed(i) {
+                let score = weight * lightness_delta(l) + (1.0 - weight) * color_distance(i);
+                if best.is_none_or(|(best_score, best_i)| score < best_score || (score == best_score && i < best_i)) {
+                    best = Some((score, i));
+                }
+            }
+            true
+        };
+
+        // Alternate sides so a good candidate is found early on both
+        let (mut up, mut down) = (split, split);
+        let (mut up_open, mut down_open) = (up < self.order.len(), down > 0);
+        while up_open || down_open {
+            if up_open {
+                up_open = visit(up);
+                up += 1;
+                up_open &= up < self.order.len();
+            }
+            if down_open {
+                down -= 1;
+                down_open = visit(down) && down > 0;
+            }
+        }
+        best.map(|(_, i)| i)
+    }
+}
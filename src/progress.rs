@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use rayon::prelude::*;
+
+/// Error returned by a generation stopped through its `CancellationToken`.
+pub const CANCELLED_ERROR: &str = "Generation cancelled";
+
+/// Whether `error` comes from a cancelled generation rather than a failed one.
+pub fn is_cancelled_error(error: &str) -> bool {
+    error == CANCELLED_ERROR
+}
+
+/// Asks a running generation to stop at its next checkpoint. Clones share the same
+/// flag, so one can be handed to the thread doing the work.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Receives the current stage and the fraction of the whole call done so far (0.0 to 1.0).
+pub type ProgressSink<'a> = &'a dyn Fn(&str, f32);
+
+// Checkpoints per loop: often enough for a smooth bar and a quick stop, rarely enough
+// not to flood a worker's message channel with progress updates
+const LOOP_CHECKPOINTS: usize = 20;
+
+/// Progress reporting and cancellation for one generation call.
+#[derive(Clone, Copy)]
+pub struct GenerationHooks<'a> {
+    progress: Option<ProgressSink<'a>>,
+    cancellation: Option<&'a CancellationToken>,
+    // Part of the whole call that fractions passed to these hooks are relative to
+    span: (f32, f32),
+}
+
+impl Default for GenerationHooks<'_> {
+    fn default() -> Self {
+        GenerationHooks { progress: None, cancellation: None, span: (0.0, 1.0) }
+    }
+}
+
+impl<'a> GenerationHooks<'a> {
+    pub fn with_progress(mut self, progress: ProgressSink<'a>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: &'a CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Fails with `CANCELLED_ERROR` if cancellation was requested, otherwise reports
+    /// `stage` at `fraction`.
+    pub fn checkpoint(&self, stage: &str, fraction: f32) -> Result<(), String> {
+        if self.cancellation.is_some_and(CancellationToken::is_cancelled) {
+            return Err(CANCELLED_ERROR.to_string());
+        }
+        self.report(stage, fraction);
+        Ok(())
+    }
+
+    /// Reports `stage` at `fraction` without checking for cancellation, for work that
+    /// has already finished.
+    pub fn report(&self, stage: &str, fraction: f32) {
+        if let Some(progress) = self.progress {
+            let (start, end) = self.span;
+            progress(stage, start + fraction.clamp(0.0, 1.0) * (end - start));
+        }
+    }
+
+    /// Hooks for a step covering `start..end` of the work these hooks report on. The
+    /// step reports its own fractions from 0.0 to 1.0.
+    pub fn part(&self, start: f32, end: f32) -> Self {
+        let (outer_start, outer_end) = self.span;
+        let width = outer_end - outer_start;
+        GenerationHooks { span: (outer_start + start * width, outer_start + end * width), ..*self }
+    }
+
+    /// `checkpoint` at `done / total` for a loop calling it on every iteration; only every
+    /// `total / LOOP_CHECKPOINTS`-th iteration actually checks and reports.
+    pub fn loop_checkpoint(&self, stage: &str, done: usize, total: usize) -> Result<(), String> {
+        if done.is_multiple_of(loop_chunk_len(total)) {
+            self.checkpoint(stage, done as f32 / total as f32)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Maps every index in `0..len` through `f` in parallel, in chunks with a
+    /// `checkpoint` before each.
+    pub fn par_map<T: Send>(&self, stage: &str, len: usize, f: impl Fn(usize) -> T + Sync + Send) -> Result<Vec<T>, String> {
+        let mut results = Vec::with_capacity(len);
+        for start in (0..len).step_by(loop_chunk_len(len)) {
+            self.checkpoint(stage, start as f32 / len as f32)?;
+            let end = (start + loop_chunk_len(len)).min(len);
+            results.par_extend((start..end).into_par_iter().map(&f));
+        }
+        Ok(results)
+    }
+}
+
+fn loop_chunk_len(total: usize) -> usize {
+    total.div_ceil(LOOP_CHECKPOINTS).max(1)
+}
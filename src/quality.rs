@@ -0,0 +1,122 @@
+use crate::color_distance::lab_distance;
+use crate::models::{ColorMetric, QualityMetrics, EMPTY_CELL};
+
+// Blur applied to both images before SSIM, roughly how a pattern reads from a step back.
+const VIEWING_BLUR_SIGMA: f32 = 1.0;
+// Gaussian window of the SSIM statistics, as in the original SSIM paper.
+const SSIM_WINDOW_SIGMA: f32 = 1.5;
+// SSIM stabilising constants for values in 0..1.
+const SSIM_C1: f32 = 0.01 * 0.01;
+const SSIM_C2: f32 = 0.03 * 0.03;
+/// DeltaE shown at the hot end of the error heatmap; larger errors are clamped.
+pub const HEATMAP_MAX_DELTA_E: f32 = 20.0;
+
+/// Color difference between each source cell and its assigned floss, in `metric`.
+/// Empty cells get 0.0.
+pub fn cell_errors(lab_grid: &[[f32; 3]], gem_grid: &[usize], palette_labs: &[[f32; 3]], metric: ColorMetric) -> Vec<f32> {
+    lab_grid
+        .iter()
+        .zip(gem_grid)
+        .map(|(&lab, &index)| if index == EMPTY_CELL { 0.0 } else { lab_distance(metric, lab, palette_labs[index]) })
+        .collect()
+}
+
+/// Summarises per-cell errors (from `cell_errors`) over the non-empty cells and computes
+/// the SSIM of the pattern's lightness against the source's, both blurred first so
+/// dithering that averages out at a distance isn't punished. All grids are
+/// column-major, `gx * num_gems_y + gy`.
+pub fn quality_metrics(
+    lab_grid: &[[f32; 3]],
+    gem_grid: &[usize],
+    palette_labs: &[[f32; 3]],
+    errors: &[f32],
+    num_gems_x: u32,
+    num_gems_y: u32,
+) -> QualityMetrics {
+    let mut filled: Vec<f32> = errors.iter().zip(gem_grid).filter(|(_, &index)| index != EMPTY_CELL).map(|(&e, _)| e).collect();
+    if filled.is_empty() {
+        return QualityMetrics { mean_delta_e: 0.0, p95_delta_e: 0.0, max_delta_e: 0.0, ssim: 1.0 };
+    }
+    filled.sort_by(f32::total_cmp);
+    let mean_delta_e = filled.iter().sum::<f32>() / filled.len() as f32;
+    // Nearest-rank percentile
+    let p95_delta_e = filled[((0.95 * filled.len() as f32).ceil() as usize).clamp(1, filled.len()) - 1];
+    let max_delta_e = filled[filled.len() - 1];
+
+    // Lightness in 0..1; empty cells take the source value so they don't count as errors
+    let source: Vec<f32> = lab_grid.iter().map(|lab| lab[0] / 100.0).collect();
+    let pattern: Vec<f32> = gem_grid
+        .iter()
+        .zip(&source)
+        .map(|(&index, &l)| if index == EMPTY_CELL { l } else { palette_labs[index][0] / 100.0 })
+        .collect();
+    let source = blur_grid(&source, num_gems_x, num_gems_y, VIEWING_BLUR_SIGMA);
+    let pattern = blur_grid(&pattern, num_gems_x, num_gems_y, VIEWING_BLUR_SIGMA);
+    let ssim_map = ssim_map(&source, &pattern, num_gems_x, num_gems_y);
+    let ssim = ssim_map.iter().zip(gem_grid).filter(|(_, &index)| index != EMPTY_CELL).map(|(&s, _)| s).sum::<f32>() / filled.len() as f32;
+
+    QualityMetrics { mean_delta_e, p95_delta_e, max_delta_e, ssim }
+}
+
+/// Heatmap color for a deltaE: blue at 0, through green and yellow, to red at
+/// `HEATMAP_MAX_DELTA_E` and above.
+pub fn heatmap_color(delta_e: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 4] = [[40.0, 60.0, 200.0], [40.0, 180.0, 80.0], [240.0, 220.0, 40.0], [220.0, 30.0, 30.0]];
+    let t = (delta_e / HEATMAP_MAX_DELTA_E).clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (t.floor() as usize).min(STOPS.len() - 2);
+    let f = t - i as f32;
+    let channel = |c: usize| (STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * f).round() as u8;
+    [channel(0), channel(1), channel(2)]
+}
+
+fn ssim_map(x: &[f32], y: &[f32], num_gems_x: u32, num_gems_y: u32) -> Vec<f32> {
+    let blur = |values: Vec<f32>| blur_grid(&values, num_gems_x, num_gems_y, SSIM_WINDOW_SIGMA);
+    let mu_x = blur(x.to_vec());
+    let mu_y = blur(y.to_vec());
+    let xx = blur(x.iter().map(|v| v * v).collect());
+    let yy = blur(y.iter().map(|v| v * v).collect());
+    let xy = blur(x.iter().zip(y).map(|(a, b)| a * b).collect());
+    (0..x.len())
+        .map(|i| {
+            let var_x = xx[i] - mu_x[i] * mu_x[i];
+            let var_y = yy[i] - mu_y[i] * mu_y[i];
+            let cov = xy[i] - mu_x[i] * mu_y[i];
+            ((2.0 * mu_x[i] * mu_y[i] + SSIM_C1) * (2.0 * cov + SSIM_C2))
+                / ((mu_x[i] * mu_x[i] + mu_y[i] * mu_y[i] + SSIM_C1) * (var_x + var_y + SSIM_C2))
+        })
+        .collect()
+}
+
+/// Separable Gaussian blur of a column-major grid, clamping at the edges.
+fn blur_grid(values: &[f32], num_gems_x: u32, num_gems_y: u32, sigma: f32) -> Vec<f32> {
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as i64;
+    let (nx, ny) = (num_gems_x as i64, num_gems_y as i64);
+    let pass = |input: &[f32], step_x: i64, step_y: i64| -> Vec<f32> {
+        (0..nx)
+            .flat_map(|gx| (0..ny).map(move |gy| (gx, gy)))
+            .map(|(gx, gy)| {
+                kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(k, weight)| {
+                        let offset = k as i64 - radius;
+                        let x = (gx + offset * step_x).clamp(0, nx - 1);
+                        let y = (gy + offset * step_y).clamp(0, ny - 1);
+                        weight * input[(x * ny + y) as usize]
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    pass(&pass(values, 0, 1), 1, 0)
+}
+
+/// Normalised Gaussian kernel reaching out to 3 sigma.
+pub fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (3.0 * sigma).ceil() as i32;
+    let mut kernel: Vec<f32> = (-radius..=radius).map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|k| *k /= total);
+    kernel
+}
@@ -0,0 +1,33 @@
+use crate::models::CanvasShape;
+
+// Bounds of the implicit heart (x² + y² - 1)³ - x²y³ <= 0, which spans
+// x in -1.14..1.14 and y in -1.0..1.24 with y pointing up.
+const HEART_HALF_WIDTH: f32 = 1.14;
+const HEART_HALF_HEIGHT: f32 = 1.12;
+const HEART_CENTRE_Y: f32 = 0.12;
+
+/// Flags the gem cells (column-major, `gx * num_gems_y + gy`) whose centres fall inside
+/// `shape`. The circle and heart keep their proportions and are centred on the grid,
+/// while the oval stretches to fill it. Returns `None` for shapes without a built-in
+/// outline (`Rectangle` and `Custom`).
+pub fn shape_cells(shape: CanvasShape, num_gems_x: u32, num_gems_y: u32) -> Option<Vec<bool>> {
+    let half_x = num_gems_x as f32 / 2.0;
+    let half_y = num_gems_y as f32 / 2.0;
+    let half_side = half_x.min(half_y);
+    let inside: Box<dyn Fn(f32, f32) -> bool> = match shape {
+        CanvasShape::Rectangle | CanvasShape::Custom => return None,
+        CanvasShape::Circle => Box::new(move |dx, dy| dx * dx + dy * dy <= half_side * half_side),
+        CanvasShape::Oval => Box::new(move |dx, dy| (dx / half_x).powi(2) + (dy / half_y).powi(2) <= 1.0),
+        CanvasShape::Heart => Box::new(move |dx, dy| {
+            let x = dx / half_side * HEART_HALF_WIDTH;
+            let y = HEART_CENTRE_Y - dy / half_side * HEART_HALF_HEIGHT;
+            (x * x + y * y - 1.0).powi(3) - x * x * y.powi(3) <= 0.0
+        }),
+    };
+    Some(
+        (0..num_gems_x)
+            .flat_map(|gx| (0..num_gems_y).map(move |gy| (gx, gy)))
+            .map(|(gx, gy)| inside(gx as f32 + 0.5 - half_x, gy as f32 + 0.5 - half_y))
+            .collect(),
+    )
+}
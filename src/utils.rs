@@ -21,4 +21,22 @@ pub fn expand_shorthand_hex(hex: &str) -> String {
     } else {
         hex.to_string()
     }
-}
\ No newline at end of file
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Parses `#rrggbb` or `#rgb` (the `#` is optional) into RGB components.
+pub fn parse_hex_color(hex: &str) -> Option<(u8, u8, u8)> {
+    let hex = expand_shorthand_hex(hex.trim());
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
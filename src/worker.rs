@@ -0,0 +1,592 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::{Rc, Weak};
+use gloo_timers::callback::Timeout;
+use serde::{Serialize, Deserialize};
+use wasm_bindgen::prelude::*;
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent, Worker, WorkerOptions, WorkerType};
+use crate::image_processing::{auto_tune_with_hooks, image_dimensions, render_preview_image, write_gem_art_png_with_hooks, GemArtData, PreviewPipeline};
+use crate::progress::{CancellationToken, GenerationHooks};
+use crate::models::{Color, GemCount, GenerationSettings, PaletteReport, TunedConfiguration, TuningConstraints};
+
+// Module worker that loads this crate's wasm and forwards messages to `handle_worker_message`
+const WORKER_SCRIPT: &str = "./generation_worker.js";
+// Longest a job may go without any message from the worker before it is presumed stuck,
+// plus `STALL_MS_PER_MEGAPIXEL` for each megapixel of its image. Jobs report progress at
+// every stage and loop checkpoint, so the longest silence is one full-resolution step
+// with no checkpoint inside. The worst is the denoise median filter at its largest
+// radius (8), at about 0.55 s per megapixel natively on one thread; the fit to the page
+// resamples to a fixed print size and stays within the base time.
+const WORKER_STALL_TIMEOUT_MS: u32 = 60_000;
+// About ten times the native cost of that median filter, for single-threaded wasm on a
+// slow device
+const STALL_MS_PER_MEGAPIXEL: u64 = 5_000;
+// Pixels assumed per character of a data URL whose header can't be read; compressed
+// photos rarely take less than a tenth of a byte per pixel
+const PIXELS_PER_ENCODED_CHAR: u64 = 10;
+
+/// A job for the generation worker. Sent as JSON.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum WorkerRequest {
+    Preview { job: u32, image_data: String, colors: Vec<Color>, settings: GenerationSettings },
+    Final { job: u32, gem_art_data: GemArtData },
+    AnalyzePalette { job: u32, image_data: String, colors: Vec<Color>, settings: GenerationSettings, suggestions: usize },
+    AutoTune { job: u32, image_data: String, allowed_colors: Vec<Color>, settings: GenerationSettings, constraints: TuningConstraints },
+    AutoSelect { job: u32, image_data: String, allowed_colors: Vec<Color>, num_colors: usize, settings: GenerationSettings },
+}
+
+/// A message back from the generation worker. Sent as JSON, with the preview pixels of
+/// `Preview` and the PNG file of `Final` travelling alongside as a transferred buffer.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum WorkerResponse {
+    Progress { job: u32, stage: String, fraction: f32 },
+    Preview { job: u32, counts: Vec<GemCount>, gem_art_data: GemArtData, width: u32, height: u32 },
+    PreviewFailed { job: u32, error: String },
+    Final { job: u32 },
+    FinalFailed { job: u32, error: String },
+    PaletteReport { job: u32, report: PaletteReport },
+    PaletteAnalysisFailed { job: u32, error: String },
+    Tuned { job: u32, configuration: TunedConfiguration },
+    TuneFailed { job: u32, error: String },
+    AutoSelected { job: u32, flosses: Vec<String> },
+    AutoSelectFailed { job: u32, error: String },
+}
+
+impl WorkerRequest {
+    pub fn job(&self) -> u32 {
+        match self {
+            WorkerRequest::Preview { job, .. }
+            | WorkerRequest::Final { job, .. }
+            | WorkerRequest::AnalyzePalette { job, .. }
+            | WorkerRequest::AutoTune { job, .. }
+            | WorkerRequest::AutoSelect { job, .. } => *job,
+        }
+    }
+}
+
+impl WorkerResponse {
+    pub fn job(&self) -> u32 {
+        match self {
+            WorkerResponse::Progress { job, .. }
+            | WorkerResponse::Preview { job, .. }
+            | WorkerResponse::PreviewFailed { job, .. }
+            | WorkerResponse::Final { job, .. }
+            | WorkerResponse::FinalFailed { job, .. }
+            | WorkerResponse::PaletteReport { job, .. }
+            | WorkerResponse::PaletteAnalysisFailed { job, .. }
+            | WorkerResponse::Tuned { job, .. }
+            | WorkerResponse::TuneFailed { job, .. }
+            | WorkerResponse::AutoSelected { job, .. }
+            | WorkerResponse::AutoSelectFailed { job, .. } => *job,
+        }
+    }
+
+    /// Whether this is the last message of its job.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, WorkerResponse::Progress { .. })
+    }
+}
+
+/// How long `request` may go without a message before its worker is presumed stuck.
+pub fn stall_timeout_ms(request: &WorkerRequest) -> u32 {
+    let pixels = match request {
+        WorkerRequest::Preview { image_data, .. }
+        | WorkerRequest::AnalyzePalette { image_data, .. }
+        | WorkerRequest::AutoTune { image_data, .. }
+        | WorkerRequest::AutoSelect { image_data, .. } => image_dimensions(image_data)
+            .map_or(image_data.len() as u64 * PIXELS_PER_ENCODED_CHAR, |(width, height)| width as u64 * height as u64),
+        WorkerRequest::Final { gem_art_data, .. } => gem_art_data.a4_width_px as u64 * gem_art_data.a4_height_px as u64,
+    };
+    let allowance = pixels.div_ceil(1_000_000) * STALL_MS_PER_MEGAPIXEL;
+    (WORKER_STALL_TIMEOUT_MS as u64 + allowance).min(u32::MAX as u64) as u32
+}
+
+/// Runs one job, reporting through `post` and stopping early once `cancellation` is
+/// cancelled. The pixels of a `Preview`, as RGBA rows of `width` pixels, and the PNG
+/// file of a `Final` are passed as the second argument.
+pub fn handle_request(pipeline: &mut PreviewPipeline, request: WorkerRequest, cancellation: &CancellationToken, post: &mut dyn FnMut(WorkerResponse, Option<Vec<u8>>)) {
+    let post = RefCell::new(post);
+    let job = request.job();
+    let progress = |stage: &str, fraction: f32| (post.borrow_mut())(WorkerResponse::Progress { job, stage: stage.to_string(), fraction }, None);
+    let hooks = GenerationHooks::default().with_progress(&progress).with_cancellation(cancellation);
+    let (response, payload) = match request {
+        WorkerRequest::Preview { image_data, colors, settings, .. } => {
+            match pipeline.generate_pattern_with_hooks(&image_data, &colors, &settings, &hooks) {
+                Ok((counts, gem_art_data)) => {
+                    hooks.report("Drawing preview", 0.9);
+                    let pixels = render_preview_image(&gem_art_data);
+                    let (width, height) = pixels.dimensions();
+                    (WorkerResponse::Preview { job, counts, gem_art_data, width, height }, Some(pixels.into_raw()))
+                }
+                Err(error) => (WorkerResponse::PreviewFailed { job, error }, None),
+            }
+        }
+        WorkerRequest::Final { gem_art_data, .. } => {
+            let mut png = Vec::new();
+            match write_gem_art_png_with_hooks(&gem_art_data, &mut png, &hooks) {
+                Ok(()) => (WorkerResponse::Final { job }, Some(png)),
+                Err(error) => (WorkerResponse::FinalFailed { job, error }, None),
+            }
+        }
+        WorkerRequest::AnalyzePalette { image_data, colors, settings, suggestions, .. } => {
+            match pipeline.analyze_palette_with_hooks(&image_data, &colors, &settings, suggestions, &hooks) {
+                Ok(report) => (WorkerResponse::PaletteReport { job, report }, None),
+                Err(error) => (WorkerResponse::PaletteAnalysisFailed { job, error }, None),
+            }
+        }
+        WorkerRequest::AutoTune { image_data, allowed_colors, settings, constraints, .. } => {
+            match auto_tune_with_hooks(&image_data, &allowed_colors, &settings, &constraints, &hooks) {
+                Ok(configuration) => (WorkerResponse::Tuned { job, configuration }, None),
+                Err(error) => (WorkerResponse::TuneFailed { job, error }, None),
+            }
+        }
+        WorkerRequest::AutoSelect { image_data, allowed_colors, num_colors, settings, .. } => {
+            match pipeline.auto_select_colors_with_hooks(&image_data, &allowed_colors, num_colors, &settings, &hooks) {
+                Ok(flosses) => (WorkerResponse::AutoSelected { job, flosses }, None),
+                Err(error) => (WorkerResponse::AutoSelectFailed { job, error }, None),
+            }
+        }
+    };
+    (post.borrow_mut())(response, payload);
+}
+
+thread_local! {
+    // Kept between jobs so the worker can reuse decoded and resized images
+    static WORKER_PIPELINE: RefCell<PreviewPipeline> = RefCell::new(PreviewPipeline::default());
+}
+
+/// Entry point of the worker script: runs the JSON-encoded `WorkerRequest` in `json`
+/// and posts the responses back to the page. `cancel`, if present, is the page's shared
+/// cancellation flag (see `GenerationWorker`).
+#[wasm_bindgen]
+pub fn handle_worker_message(message: JsValue) {
+    let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+    let Some(Ok(request)) = js_sys::Reflect::get(&message, &JsValue::from_str("json"))
+        .ok()
+        .and_then(|json| json.as_string())
+        .map(|json| serde_json::from_str::<WorkerRequest>(&json))
+    else {
+        return;
+    };
+    let cancel_flag = js_sys::Reflect::get(&message, &JsValue::from_str("cancel"))
+        .ok()
+        .filter(|flag| flag.is_instance_of::<js_sys::Int32Array>())
+        .map(|flag| flag.unchecked_into::<js_sys::Int32Array>());
+    let cancellation = CancellationToken::new();
+    WORKER_PIPELINE.with(|pipeline| {
+        handle_request(&mut pipeline.borrow_mut(), request, &cancellation, &mut |response, payload| {
+            let message = encode_response(&response, payload.as_deref());
+            let posted = match js_sys::Reflect::get(&message, &JsValue::from_str("payload")) {
+                Ok(payload) if !payload.is_undefined() => {
+                    let buffer = payload.unchecked_into::<js_sys::Uint8Array>().buffer();
+                    scope.post_message_with_transfer(&message, &js_sys::Array::of1(&buffer))
+                }
+                _ => scope.post_message(&message),
+            };
+            if let Err(e) = posted {
+                log::error!("Failed to post worker response: {:?}", e);
+            }
+            // The job reports progress at every checkpoint, so the flag is polled here and
+            // the cancellation takes effect at the next checkpoint
+            if cancel_flag.as_ref().is_some_and(|flag| js_sys::Atomics::load(flag, 0) == Ok(1)) {
+                cancellation.cancel();
+            }
+        });
+    });
+}
+
+fn encode_response(response: &WorkerResponse, payload: Option<&[u8]>) -> JsValue {
+    let message = js_sys::Object::new();
+    let json = serde_json::to_string(response).unwrap_or_default();
+    let _ = js_sys::Reflect::set(&message, &JsValue::from_str("json"), &JsValue::from_str(&json));
+    if let Some(payload) = payload {
+        let _ = js_sys::Reflect::set(&message, &JsValue::from_str("payload"), &js_sys::Uint8Array::from(payload));
+    }
+    message.into()
+}
+
+// Set by the worker script instead of `json` when it can't run a job
+fn failure_field(message: &JsValue, field: &str) -> Option<String> {
+    js_sys::Reflect::get(message, &JsValue::from_str(field)).ok()?.as_string()
+}
+
+// The payload stays in JS memory, so a chart can become a download without a copy
+fn decode_response(message: &JsValue) -> Option<(WorkerResponse, Option<js_sys::Uint8Array>)> {
+    let json = js_sys::Reflect::get(message, &JsValue::from_str("json")).ok()?.as_string()?;
+    let response = serde_json::from_str(&json)
+        .map_err(|e| log::error!("Failed to decode worker response: {}", e))
+        .ok()?;
+    let payload = js_sys::Reflect::get(message, &JsValue::from_str("payload"))
+        .ok()
+        .filter(|payload| payload.is_instance_of::<js_sys::Uint8Array>())
+        .map(|payload| payload.unchecked_into::<js_sys::Uint8Array>());
+    Some((response, payload))
+}
+
+type ResponseHandler = Rc<dyn Fn(WorkerResponse, Option<js_sys::Uint8Array>)>;
+
+// A request with the handler its responses go to
+struct Job {
+    request: WorkerRequest,
+    on_response: ResponseHandler,
+}
+
+struct RunningWorker {
+    worker: Worker,
+    // Kept alive for as long as the worker can call them
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(JsValue)>,
+}
+
+impl Drop for RunningWorker {
+    fn drop(&mut self) {
+        self.worker.set_onmessage(None);
+        self.worker.set_onerror(None);
+        self.worker.terminate();
+    }
+}
+
+/// Why the running worker has to be given up on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum WorkerFailure {
+    // The script or its wasm failed to load
+    Unavailable,
+    // A job panicked, which leaves the wasm instance unusable
+    Crashed,
+    // A job went its `stall_timeout_ms` without a message
+    Stalled,
+}
+
+struct WorkerState {
+    // `None` when workers are unavailable, in which case jobs run on the calling thread
+    running: Option<RunningWorker>,
+    // Whether the running worker has answered yet, which shows its script loaded
+    worker_answered: bool,
+    fallback_pipeline: PreviewPipeline,
+    in_flight: Option<Job>,
+    // Whether the job in flight was cancelled; its responses are dropped
+    in_flight_cancelled: bool,
+    // Shared with the worker so it can notice a cancellation mid-job. Needs a
+    // cross-origin isolated page; without one a cancelled job's worker is replaced.
+    cancel_flag: Option<js_sys::Int32Array>,
+    // Replaced on every message of the job in flight; fires if the worker goes quiet
+    watchdog: Option<Timeout>,
+    // `stall_timeout_ms` of the job in flight
+    stall_timeout_ms: u32,
+    queue: VecDeque<Job>,
+    next_job: u32,
+}
+
+/// Page-side handle of the generation worker, shared by everything on the page that
+/// generates; clones refer to the same worker. Jobs run one at a time, each reporting to
+/// the handler it was queued with. A new preview replaces any preview still waiting, and
+/// cancels one already running. A cancelled job that is running stops at its next
+/// checkpoint when the page is cross-origin isolated, so a `SharedArrayBuffer` flag can
+/// reach the busy worker and the worker keeps its caches. Otherwise the worker is
+/// terminated and replaced, as a busy worker can't be reached at all. Responses of
+/// cancelled jobs are never delivered. A job whose worker crashes or stops responding
+/// fails, and the next job gets a fresh worker.
+#[derive(Clone)]
+pub struct GenerationWorker {
+    state: Rc<RefCell<WorkerState>>,
+}
+
+impl PartialEq for GenerationWorker {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl GenerationWorker {
+    pub fn new() -> Self {
+        let state = Rc::new(RefCell::new(WorkerState {
+            running: None,
+            worker_answered: false,
+            fallback_pipeline: PreviewPipeline::default(),
+            in_flight: None,
+            in_flight_cancelled: false,
+            cancel_flag: shared_cancel_flag(),
+            watchdog: None,
+            stall_timeout_ms: WORKER_STALL_TIMEOUT_MS,
+            queue: VecDeque::new(),
+            next_job: 0,
+        }));
+        let running = spawn_worker(Rc::downgrade(&state));
+        if let Err(e) = &running {
+            log::warn!("Generation worker unavailable, generating on the page instead: {:?}", e);
+        }
+        state.borrow_mut().running = running.ok();
+        GenerationWorker { state }
+    }
+
+    /// Queues a preview, cancelling any earlier preview. Returns its job number.
+    pub fn generate_preview(&self, image_data: &str, colors: &[Color], settings: &GenerationSettings, on_response: impl Fn(WorkerResponse, Option<js_sys::Uint8Array>) + 'static) -> u32 {
+        self.cancel_preview();
+        let job = self.next_job();
+        self.submit(WorkerRequest::Preview { job, image_data: image_data.to_string(), colors: colors.to_vec(), settings: settings.clone() }, on_response);
+        job
+    }
+
+    /// Queues the 300 DPI chart for `gem_art_data`. Returns its job number.
+    pub fn generate_final(&self, gem_art_data: &GemArtData, on_response: impl Fn(WorkerResponse, Option<js_sys::Uint8Array>) + 'static) -> u32 {
+        let job = self.next_job();
+        self.submit(WorkerRequest::Final { job, gem_art_data: gem_art_data.clone() }, on_response);
+        job
+    }
+
+    /// Queues an `analyze_palette` report with up to `suggestions` suggested flosses.
+    /// Returns its job number.
+    pub fn analyze_palette(&self, image_data: &str, colors: &[Color], settings: &GenerationSettings, suggestions: usize, on_response: impl Fn(WorkerResponse, Option<js_sys::Uint8Array>) + 'static) -> u32 {
+        let job = self.next_job();
+        self.submit(WorkerRequest::AnalyzePalette { job, image_data: image_data.to_string(), colors: colors.to_vec(), settings: settings.clone(), suggestions }, on_response);
+        job
+    }
+
+    /// Queues an `auto_tune` search. Returns its job number.
+    pub fn auto_tune(&self, image_data: &str, allowed_colors: &[Color], settings: &GenerationSettings, constraints: &TuningConstraints, on_response: impl Fn(WorkerResponse, Option<js_sys::Uint8Array>) + 'static) -> u32 {
+        let job = self.next_job();
+        self.submit(WorkerRequest::AutoTune { job, image_data: image_data.to_string(), allowed_colors: allowed_colors.to_vec(), settings: settings.clone(), constraints: constraints.clone() }, on_response);
+        job
+    }
+
+    /// Queues an `auto_select_colors` run. Returns its job number.
+    pub fn auto_select_colors(&self, image_data: &str, allowed_colors: &[Color], num_colors: usize, settings: &GenerationSettings, on_response: impl Fn(WorkerResponse, Option<js_sys::Uint8Array>) + 'static) -> u32 {
+        let job = self.next_job();
+        self.submit(WorkerRequest::AutoSelect { job, image_data: image_data.to_string(), allowed_colors: allowed_colors.to_vec(), num_colors, settings: settings.clone() }, on_response);
+        job
+    }
+
+    /// Drops a waiting preview and cancels a running one.
+    pub fn cancel_preview(&self) {
+        self.cancel_where(|request| matches!(request, WorkerRequest::Preview { .. }));
+    }
+
+    /// Drops job `job` if it is waiting and cancels it if it is running.
+    pub fn cancel(&self, job: u32) {
+        self.cancel_where(|request| request.job() == job);
+    }
+
+    fn cancel_where(&self, matches: impl Fn(&WorkerRequest) -> bool) {
+        let mut state = self.state.borrow_mut();
+        state.queue.retain(|queued| !matches(&queued.request));
+        if !state.in_flight.as_ref().is_some_and(|running| matches(&running.request)) {
+            return;
+        }
+        state.in_flight_cancelled = true;
+        // A busy worker can't read messages, but it does poll this flag
+        if let Some(flag) = &state.cancel_flag {
+            let _ = js_sys::Atomics::store(flag, 0, 1);
+            return;
+        }
+        if state.running.is_some() {
+            // Without the flag the only way to stop the worker is to replace it, which
+            // costs its caches but keeps the jobs behind this one from waiting on it
+            state.in_flight = None;
+            state.watchdog = None;
+            state.running = None;
+            state.running = spawn_worker(Rc::downgrade(&self.state)).ok();
+            state.worker_answered = false;
+            drop(state);
+            start_next(&self.state);
+        }
+    }
+
+    fn next_job(&self) -> u32 {
+        let mut state = self.state.borrow_mut();
+        state.next_job += 1;
+        state.next_job
+    }
+
+    fn submit(&self, request: WorkerRequest, on_response: impl Fn(WorkerResponse, Option<js_sys::Uint8Array>) + 'static) {
+        self.state.borrow_mut().queue.push_back(Job { request, on_response: Rc::new(on_response) });
+        start_next(&self.state);
+    }
+}
+
+impl Default for GenerationWorker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A one-element flag in shared memory, if this page may share memory with workers.
+fn shared_cancel_flag() -> Option<js_sys::Int32Array> {
+    let isolated = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("crossOriginIsolated")).ok()?;
+    if isolated.as_bool() != Some(true) {
+        return None;
+    }
+    Some(js_sys::Int32Array::new(&js_sys::SharedArrayBuffer::new(4)))
+}
+
+fn spawn_worker(state: Weak<RefCell<WorkerState>>) -> Result<RunningWorker, JsValue> {
+    let options = WorkerOptions::new();
+    options.set_type(WorkerType::Module);
+    let worker = Worker::new_with_options(WORKER_SCRIPT, &options)?;
+
+    // Both handlers defer their work, since it may replace the worker (and with it the
+    // closure that is running)
+    let message_state = state.clone();
+    let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let Some(state) = message_state.upgrade() else {
+            return;
+        };
+        let data = event.data();
+        if let Some((response, payload)) = decode_response(&data) {
+            wasm_bindgen_futures::spawn_local(async move { deliver(&state, response, payload) });
+        } else if let Some(error) = failure_field(&data, "crashed") {
+            log::error!("Generation worker crashed: {}", error);
+            // Only a worker whose script loaded can report a crash
+            state.borrow_mut().worker_answered = true;
+            wasm_bindgen_futures::spawn_local(async move { recover_from_failure(&state, WorkerFailure::Crashed) });
+        } else if let Some(error) = failure_field(&data, "unavailable") {
+            log::warn!("Generation worker could not load: {}", error);
+            wasm_bindgen_futures::spawn_local(async move { recover_from_failure(&state, WorkerFailure::Unavailable) });
+        }
+    }) as Box<dyn FnMut(MessageEvent)>);
+    worker.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+    // Errors the worker script doesn't catch itself, such as the script failing to load
+    let on_error = Closure::wrap(Box::new(move |_: JsValue| {
+        if let Some(state) = state.upgrade() {
+            let failure = if state.borrow().worker_answered { WorkerFailure::Crashed } else { WorkerFailure::Unavailable };
+            wasm_bindgen_futures::spawn_local(async move { recover_from_failure(&state, failure) });
+        }
+    }) as Box<dyn FnMut(JsValue)>);
+    worker.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+    Ok(RunningWorker { worker, _on_message: on_message, _on_error: on_error })
+}
+
+/// Restarts the stall timer of `job`, which is in flight.
+fn arm_watchdog(state: &Rc<RefCell<WorkerState>>, job: u32) {
+    let weak = Rc::downgrade(state);
+    let timeout_ms = state.borrow().stall_timeout_ms;
+    let watchdog = Timeout::new(timeout_ms, move || {
+        let Some(state) = weak.upgrade() else {
+            return;
+        };
+        // Deferred like the worker's handlers, since recovery drops this timer
+        wasm_bindgen_futures::spawn_local(async move {
+            // The job may have finished while this was waiting to run
+            if state.borrow().in_flight.as_ref().map(|running| running.request.job()) == Some(job) {
+                recover_from_failure(&state, WorkerFailure::Stalled);
+            }
+        });
+    });
+    state.borrow_mut().watchdog = Some(watchdog);
+}
+
+/// Gives up on the running worker: fails its job and carries on with a fresh worker, or
+/// on the page if workers can't load at all.
+fn recover_from_failure(state: &Rc<RefCell<WorkerState>>, failure: WorkerFailure) {
+    if state.borrow().running.is_none() {
+        return;
+    }
+    let (failed, answered) = {
+        let mut guard = state.borrow_mut();
+        guard.watchdog = None;
+        let failed = guard.in_flight.take().filter(|_| !guard.in_flight_cancelled);
+        (failed, guard.worker_answered)
+    };
+    if failure == WorkerFailure::Unavailable || !answered {
+        // The script never ran (missing, or no module worker support), so generate on the
+        // page from now on
+        log::warn!("Generation worker failed to start, generating on the page instead");
+        let mut guard = state.borrow_mut();
+        guard.running = None;
+        if let Some(job) = failed {
+            guard.queue.push_front(job);
+        }
+        drop(guard);
+        start_next(state);
+        return;
+    }
+    // Terminates the failed worker before starting its replacement
+    state.borrow_mut().running = None;
+    let running = spawn_worker(Rc::downgrade(state)).ok();
+    {
+        let mut guard = state.borrow_mut();
+        guard.running = running;
+        guard.worker_answered = false;
+    }
+    let Some(Job { request, on_response }) = failed else {
+        return start_next(state);
+    };
+    let what = if failure == WorkerFailure::Stalled { "stopped responding" } else { "crashed" };
+    let response = match request {
+        WorkerRequest::Preview { job, .. } => WorkerResponse::PreviewFailed { job, error: format!("Preview generation {}", what) },
+        WorkerRequest::Final { job, .. } => WorkerResponse::FinalFailed { job, error: format!("Chart generation {}", what) },
+        WorkerRequest::AnalyzePalette { job, .. } => WorkerResponse::PaletteAnalysisFailed { job, error: format!("Palette analysis {}", what) },
+        WorkerRequest::AutoTune { job, .. } => WorkerResponse::TuneFailed { job, error: format!("Auto-tune {}", what) },
+        WorkerRequest::AutoSelect { job, .. } => WorkerResponse::AutoSelectFailed { job, error: format!("Auto-select {}", what) },
+    };
+    on_response(response, None);
+    start_next(state);
+}
+
+fn deliver(state: &Rc<RefCell<WorkerState>>, response: WorkerResponse, payload: Option<js_sys::Uint8Array>) {
+    state.borrow_mut().worker_answered = true;
+    let Some(on_response) = state.borrow().in_flight.as_ref()
+        .filter(|running| running.request.job() == response.job())
+        .map(|running| running.on_response.clone())
+    else {
+        return;
+    };
+    let cancelled = state.borrow().in_flight_cancelled;
+    if response.is_finished() {
+        {
+            let mut guard = state.borrow_mut();
+            guard.in_flight = None;
+            guard.watchdog = None;
+        }
+        if !cancelled {
+            on_response(response, payload);
+        }
+        start_next(state);
+    } else {
+        arm_watchdog(state, response.job());
+        if !cancelled {
+            on_response(response, payload);
+        }
+    }
+}
+
+fn start_next(state: &Rc<RefCell<WorkerState>>) {
+    let mut guard = state.borrow_mut();
+    if guard.in_flight.is_some() {
+        return;
+    }
+    let Some(job) = guard.queue.pop_front() else {
+        return;
+    };
+    match &guard.running {
+        Some(running) => {
+            let message = js_sys::Object::new();
+            let json = serde_json::to_string(&job.request).unwrap_or_default();
+            let _ = js_sys::Reflect::set(&message, &JsValue::from_str("json"), &JsValue::from_str(&json));
+            if let Some(flag) = &guard.cancel_flag {
+                let _ = js_sys::Atomics::store(flag, 0, 0);
+                let _ = js_sys::Reflect::set(&message, &JsValue::from_str("cancel"), flag);
+            }
+            if let Err(e) = running.worker.post_message(&message) {
+                log::error!("Failed to post worker request: {:?}", e);
+            }
+            let number = job.request.job();
+            guard.stall_timeout_ms = stall_timeout_ms(&job.request);
+            guard.in_flight = Some(job);
+            guard.in_flight_cancelled = false;
+            drop(guard);
+            arm_watchdog(state, number);
+        }
+        None => {
+            let mut pipeline = std::mem::take(&mut guard.fallback_pipeline);
+            drop(guard);
+            let Job { request, on_response } = job;
+            handle_request(&mut pipeline, request, &CancellationToken::new(), &mut |response, payload| {
+                on_response(response, payload.map(|bytes| js_sys::Uint8Array::from(&bytes[..])))
+            });
+            state.borrow_mut().fallback_pipeline = pipeline;
+            start_next(state);
+        }
+    }
+}
@@ -1,11 +1,19 @@
-use yew_project::image_processing::{generate_gem_art, generate_text_image};
+use yew_project::image_processing::{GemArtData, PreviewLayout, PreviewPipeline, PipelineStats, generate_gem_art, generate_gem_art_final, render_gem_art_bands, write_gem_art_png, generate_error_heatmap, generate_gem_art_preview_with_settings, generate_gem_art_pattern_with_settings, generate_gem_art_preview_with_hooks, generate_gem_art_final_with_hooks, render_preview_image, image_dimensions, auto_select_colors, auto_tune, analyze_palette, generate_text_image, denoise_image, sharpen_image};
 use yew_project::utils::to_excel_column;
-use yew_project::models::{ImageFitOption, GemCount, Color, ColorMappingMode};
+use yew_project::models::{ImageFitOption, GemCount, Color, ColorMappingMode, ColorMetric, DitheringMode, GenerationSettings, DmcColorPrecomputed, ResampleFilter, ImageAdjustments, PaletteRegion, BackgroundMode, CanvasShape, TuningConstraints, EMPTY_CELL};
+use yew_project::color_distance::{is_euclidean, lab_distance, lightness_bound_factor, metric_distance, to_metric_space};
+use yew_project::color_lut::ColorLut;
+use yew_project::palette_index::LightnessIndex;
+use yew_project::worker::{handle_request, stall_timeout_ms, WorkerRequest, WorkerResponse};
+use yew_project::progress::{CancellationToken, GenerationHooks, is_cancelled_error};
+use yew_project::cleanup::remove_confetti;
+use yew_project::adjustments::apply_adjustments;
 use std::time::Instant;
 use base64::Engine;
-use image::{DynamicImage, Rgba, GenericImage};
+use image::{DynamicImage, Rgba, RgbaImage, GenericImage, GenericImageView};
 use std::io::Cursor;
 use base64::engine::general_purpose;
+use palette::{IntoColor, Lab, Srgb};
 
 #[test]
 fn test_to_excel_column() {
@@ -18,7 +26,7 @@ fn test_to_excel_column() {
 
 #[test]
 fn test_generate_gem_art_performance_and_correctness() {
-    let img_path = "C:\\Users\\marin\\Documents\\Rust\\gem_art_creator\\test_images\\test_source.JPG";
+    let img_path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_images/test_source.JPG");
     let img = image::open(img_path).expect("Failed to open image");
     let mut buf = Vec::new();
     img.write_to(&mut std::io::Cursor::new(&mut buf), image::ImageOutputFormat::Png).expect("Failed to write image to buffer");
@@ -155,6 +163,7 @@ fn test_generate_gem_art_output_verification() {
 }
 
 #[test]
+#[allow(clippy::unnecessary_literal_unwrap)]
 fn test_generate_gem_art_fit_option() {
     let colors = vec![
         Color { floss_number: "B5200".to_string(), hex: "FFFFFF".to_string(), r: 255, g: 255, b: 255, value: "#FFFFFF".to_string() },
@@ -211,6 +220,7 @@ fn test_generate_gem_art_fit_option() {
 }
 
 #[test]
+#[allow(clippy::unnecessary_literal_unwrap)]
 fn test_generate_gem_art_crop_option() {
     let colors = vec![
         Color { floss_number: "B5200".to_string(), hex: "FFFFFF".to_string(), r: 255, g: 255, b: 255, value: "#FFFFFF".to_string() },
@@ -267,6 +277,7 @@ fn test_generate_gem_art_crop_option() {
 }
 
 #[test]
+#[allow(clippy::unnecessary_literal_unwrap, clippy::unnecessary_cast)]
 fn test_generate_gem_art_margin_application() {
     let colors = vec![
         Color { floss_number: "B5200".to_string(), hex: "FFFFFF".to_string(), r: 255, g: 255, b: 255, value: "#FFFFFF".to_string() },
@@ -280,8 +291,13 @@ fn test_generate_gem_art_margin_application() {
     let custom_width_mm = Some(100.0);
     let custom_height_mm = Some(100.0);
 
-    // Create a dummy 100x100px image
+    // Create a dummy 100x100px opaque image (transparent cells would get no gem)
     let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            img.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+        }
+    }
     img.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
     let mut buf = Vec::new();
     img.write_to(&mut Cursor::new(&mut buf), image::ImageOutputFormat::Png).expect("Failed to write image to buffer");
@@ -446,3 +462,1462 @@ fn test_get_dmc_colors_correctness() {
     assert_eq!(color_b5200.g, 255, "DMC B5200 green mismatch");
     assert_eq!(color_b5200.b, 255, "DMC B5200 blue mismatch");
 }
+
+fn encode_image_data_url(img: &DynamicImage) -> String {
+    let mut buf = Vec::new();
+    img.write_to(&mut Cursor::new(&mut buf), image::ImageOutputFormat::Png).expect("Failed to write image to buffer");
+    format!("data:image/png;base64,{}", general_purpose::STANDARD.encode(&buf))
+}
+
+fn black_and_white_colors() -> Vec<Color> {
+    vec![
+        Color { floss_number: "B5200".to_string(), hex: "FFFFFF".to_string(), r: 255, g: 255, b: 255, value: "#FFFFFF".to_string() },
+        Color { floss_number: "310".to_string(), hex: "000000".to_string(), r: 0, g: 0, b: 0, value: "#000000".to_string() },
+    ]
+}
+
+#[test]
+fn test_dithering_modes_mix_colors_on_flat_gray() {
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            img.put_pixel(x, y, Rgba([128, 128, 128, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = black_and_white_colors();
+
+    let base_settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(27.0),
+        custom_height_mm: Some(27.0),
+        ..GenerationSettings::default()
+    };
+
+    let (_, undithered_counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &base_settings).unwrap();
+    assert_eq!(undithered_counts.len(), 1, "Flat gray without dithering should map to a single color");
+
+    for mode in [DitheringMode::FloydSteinberg, DitheringMode::Atkinson, DitheringMode::Stucki, DitheringMode::Bayer, DitheringMode::BlueNoise] {
+        let settings = GenerationSettings { dithering_mode: mode, ..base_settings.clone() };
+        let (_, counts, gem_art_data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+        assert_eq!(counts.len(), 2, "{:?} should mix black and white on mid gray", mode);
+        let total: u32 = counts.iter().map(|c| c.count).sum();
+        assert_eq!(total, gem_art_data.num_gems_x * gem_art_data.num_gems_y, "{:?} total gem count mismatch", mode);
+        assert!(counts[1].count * 4 > total / 2, "{:?} minority color should cover a sizeable share", mode);
+    }
+
+    let zero_strength = GenerationSettings { dithering_mode: DitheringMode::FloydSteinberg, dither_strength: 0.0, ..base_settings };
+    let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &zero_strength).unwrap();
+    assert_eq!(counts, undithered_counts, "Zero strength should match the undithered result");
+}
+
+#[test]
+fn test_adaptive_dithering_keeps_edges_crisp() {
+    // Dark gray left half, light gray right half: flat regions either side of a hard edge.
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let v = if x < 50 { 64 } else { 192 };
+            img.put_pixel(x, y, Rgba([v, v, v, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = black_and_white_colors();
+
+    let base_settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(27.0),
+        custom_height_mm: Some(27.0),
+        ..GenerationSettings::default()
+    };
+    let adaptive_settings = GenerationSettings {
+        dithering_mode: DitheringMode::FloydSteinberg,
+        adaptive_dithering: true,
+        ..base_settings.clone()
+    };
+    let (_, _, plain) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &base_settings).unwrap();
+    let (_, _, adaptive) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &adaptive_settings).unwrap();
+
+    assert_ne!(plain.gem_grid, adaptive.gem_grid, "Flat areas should still be dithered");
+
+    let ny = plain.num_gems_y;
+    let mut edge_cells = 0;
+    for gx in 0..plain.num_gems_x - 1 {
+        for gy in 0..ny {
+            let left = (gx * ny + gy) as usize;
+            let right = ((gx + 1) * ny + gy) as usize;
+            if plain.gem_grid[left] != plain.gem_grid[right] {
+                edge_cells += 1;
+                assert_eq!(adaptive.gem_grid[left], plain.gem_grid[left], "Edge cell ({}, {}) should not be dithered", gx, gy);
+                assert_eq!(adaptive.gem_grid[right], plain.gem_grid[right], "Edge cell ({}, {}) should not be dithered", gx + 1, gy);
+            }
+        }
+    }
+    assert!(edge_cells > 0, "Test image should contain an edge");
+}
+
+#[test]
+fn test_color_metrics_distance_values() {
+    // Reference pair from Sharma, Wu & Dalal's CIEDE2000 test data
+    let de2000 = lab_distance(ColorMetric::Ciede2000, [50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485]);
+    assert!((de2000 - 2.0425).abs() < 1e-3, "CIEDE2000 mismatch: {}", de2000);
+
+    let de76 = lab_distance(ColorMetric::Cie76, [50.0, 0.0, 0.0], [53.0, 4.0, 0.0]);
+    assert!((de76 - 5.0).abs() < 1e-4, "CIE76 mismatch: {}", de76);
+
+    for metric in [ColorMetric::Cie76, ColorMetric::Cie94, ColorMetric::Ciede2000, ColorMetric::Oklab, ColorMetric::Redmean] {
+        let same = lab_distance(metric, [40.0, 20.0, 20.0], [40.0, 20.0, 20.0]);
+        assert!(same.abs() < 1e-3, "{:?} distance to itself should be zero, got {}", metric, same);
+        // Same hue, different chroma: the CIE94 hue term must not go NaN
+        let d = lab_distance(metric, [50.0, 10.0, 10.0], [60.0, 20.0, 20.0]);
+        assert!(d.is_finite() && d > 0.0, "{:?} distance should be positive and finite, got {}", metric, d);
+    }
+}
+
+#[test]
+fn test_color_metrics_apply_to_nearest_and_adaptive_modes() {
+    let mut img = DynamicImage::new_rgba8(1, 1);
+    img.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+    let image_data_url = encode_image_data_url(&img);
+    let colors = vec![
+        Color { floss_number: "666".to_string(), hex: "E31D42".to_string(), r: 227, g: 29, b: 66, value: "#E31D42".to_string() },
+        Color { floss_number: "310".to_string(), hex: "000000".to_string(), r: 0, g: 0, b: 0, value: "#000000".to_string() },
+        Color { floss_number: "B5200".to_string(), hex: "FFFFFF".to_string(), r: 255, g: 255, b: 255, value: "#FFFFFF".to_string() },
+        Color { floss_number: "995".to_string(), hex: "2699C7".to_string(), r: 38, g: 150, b: 182, value: "#2699C7".to_string() },
+    ];
+
+    for metric in [ColorMetric::Cie76, ColorMetric::Cie94, ColorMetric::Ciede2000, ColorMetric::Oklab, ColorMetric::Redmean] {
+        for (mode, weight) in [(ColorMappingMode::Nearest, 0.0), (ColorMappingMode::AdaptiveLightnessWeighted, 0.25)] {
+            let settings = GenerationSettings {
+                margin_mm: 0.0,
+                custom_width_mm: Some(2.7),
+                custom_height_mm: Some(2.7),
+                mapping_mode: mode.clone(),
+                mapping_weight: weight,
+                color_metric: metric,
+                ..GenerationSettings::default()
+            };
+            let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+            assert_eq!(counts.len(), 1);
+            assert_eq!(counts[0].floss, "666", "{:?} / {:?} should map pure red to DMC 666", metric, mode);
+        }
+    }
+}
+
+fn all_dmc_colors() -> Vec<Color> {
+    yew_project::dmc_colors::get_dmc_colors().iter().map(|c| Color {
+        value: format!("#{}", c.hex),
+        floss_number: c.floss.clone(),
+        r: c.r,
+        g: c.g,
+        b: c.b,
+        hex: c.hex.clone(),
+    }).collect()
+}
+
+#[test]
+fn test_auto_select_colors_picks_one_floss_per_region() {
+    // Four flat quadrants: red, green, blue and white
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let pixel = match (x < 50, y < 50) {
+                (true, true) => Rgba([220, 30, 40, 255]),
+                (false, true) => Rgba([30, 160, 60, 255]),
+                (true, false) => Rgba([30, 60, 200, 255]),
+                (false, false) => Rgba([255, 255, 255, 255]),
+            };
+            img.put_pixel(x, y, pixel);
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let allowed = all_dmc_colors();
+    let settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(54.0),
+        custom_height_mm: Some(54.0),
+        ..GenerationSettings::default()
+    };
+
+    let chosen = auto_select_colors(&image_data_url, &allowed, 4, &settings).unwrap();
+    assert_eq!(chosen.len(), 4, "Expected exactly four flosses");
+    let mut unique = chosen.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), 4, "Chosen flosses should be distinct");
+
+    // Generating with only the chosen flosses should use every one of them on equal areas
+    let selected: Vec<Color> = allowed.iter().filter(|c| chosen.iter().any(|f| f.trim() == c.floss_number.trim())).cloned().collect();
+    let (_, counts, gem_art_data) = generate_gem_art_preview_with_settings(&image_data_url, &selected, &settings).unwrap();
+    assert_eq!(counts.len(), 4);
+    let total = gem_art_data.num_gems_x * gem_art_data.num_gems_y;
+    assert_eq!(counts.iter().map(|c| c.count).sum::<u32>(), total);
+    for count in &counts {
+        assert!(count.count * 5 > total, "Each quadrant should map to its own floss, got {:?}", counts);
+    }
+
+    // Requests beyond the allowed set are capped, and zero selects nothing
+    let two = &allowed[..2];
+    assert_eq!(auto_select_colors(&image_data_url, two, 10, &settings).unwrap().len(), 2);
+    assert!(auto_select_colors(&image_data_url, &allowed, 0, &settings).unwrap().is_empty());
+}
+
+#[test]
+fn test_min_gems_per_color_remaps_rare_colors() {
+    // White canvas with a small red patch and a larger black block
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let pixel = if (45..60).contains(&x) && (45..60).contains(&y) {
+                Rgba([220, 20, 30, 255])
+            } else if x < 40 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            };
+            img.put_pixel(x, y, pixel);
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let mut colors = black_and_white_colors();
+    colors.push(Color { floss_number: "666".to_string(), hex: "E31D42".to_string(), r: 227, g: 29, b: 66, value: "#E31D42".to_string() });
+
+    let base_settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(27.0),
+        custom_height_mm: Some(27.0),
+        ..GenerationSettings::default()
+    };
+    let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &base_settings).unwrap();
+    let red = counts.iter().find(|c| c.floss == "666").expect("Red patch should be mapped without a threshold");
+    assert!(red.count < 10, "Red patch should be small, got {}", red.count);
+
+    let settings = GenerationSettings { min_gems_per_color: 10, ..base_settings };
+    let (_, counts, gem_art_data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+    assert_eq!(counts.len(), 2, "Red should be dropped, got {:?}", counts);
+    assert!(counts.iter().all(|c| c.count >= 10));
+    assert_eq!(counts.iter().map(|c| c.count).sum::<u32>(), gem_art_data.num_gems_x * gem_art_data.num_gems_y);
+    assert!(!gem_art_data.letter_map.contains_key("666"), "Letter map should not list dropped colors");
+    assert_eq!(gem_art_data.letter_map.len(), 2);
+}
+
+fn test_palette_entry(floss: &str, lab: [f32; 3]) -> DmcColorPrecomputed {
+    DmcColorPrecomputed {
+        floss: floss.to_string(),
+        dmc_name: floss.to_string(),
+        r: 0,
+        g: 0,
+        b: 0,
+        hex: "000000".to_string(),
+        lab_l: lab[0],
+        lab_a: lab[1],
+        lab_b: lab[2],
+        blended_r: 0,
+        blended_g: 0,
+        blended_b: 0,
+    }
+}
+
+#[test]
+fn test_remove_confetti_merges_small_components() {
+    let palette = vec![
+        test_palette_entry("light", [70.0, 0.0, 0.0]),
+        test_palette_entry("close", [66.0, 0.0, 0.0]),
+        test_palette_entry("far", [10.0, 0.0, 0.0]),
+    ];
+    // 5x5 grid of color 0 with an isolated "close" gem, an isolated "far" gem and a
+    // two-gem "close" patch. Index is gx * num_gems_y + gy.
+    let mut grid = vec![0usize; 25];
+    grid[2 * 5 + 2] = 1;
+    grid[0] = 2;
+    grid[4 * 5 + 3] = 1;
+    grid[4 * 5 + 4] = 1;
+
+    let mut isolated_only = grid.clone();
+    let changed = remove_confetti(&mut isolated_only, 5, 5, &palette, ColorMetric::Cie76, 1, 10.0);
+    assert_eq!(changed, 1, "Only the close isolated gem fits both size and budget");
+    assert_eq!(isolated_only[2 * 5 + 2], 0);
+    assert_eq!(isolated_only[0], 2, "Merging the far gem would exceed the deltaE budget");
+    assert_eq!(isolated_only[4 * 5 + 3], 1, "Two-gem patch is above the size limit");
+
+    let mut patches = grid.clone();
+    let changed = remove_confetti(&mut patches, 5, 5, &palette, ColorMetric::Cie76, 2, 100.0);
+    assert_eq!(changed, 4);
+    assert!(patches.iter().all(|&c| c == 0));
+
+    let mut disabled = grid.clone();
+    assert_eq!(remove_confetti(&mut disabled, 5, 5, &palette, ColorMetric::Cie76, 0, 100.0), 0);
+    assert_eq!(disabled, grid);
+}
+
+fn gray_colors(flosses: &[&str]) -> Vec<Color> {
+    flosses.iter().map(|f| Color { floss_number: f.to_string(), hex: String::new(), r: 0, g: 0, b: 0, value: String::new() }).collect()
+}
+
+#[test]
+fn test_area_average_resampling_averages_in_linear_light() {
+    // One-pixel black/white checkerboard: every gem covers an even mix of both
+    let mut img = DynamicImage::new_rgba8(120, 120);
+    for x in 0..120 {
+        for y in 0..120 {
+            let v = if (x + y) % 2 == 0 { 0 } else { 255 };
+            img.put_pixel(x, y, Rgba([v, v, v, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    // 318 is the closest gray to a 50% linear-light mix (sRGB ~188);
+    // a naive sRGB average (127) would land on 414 instead.
+    let colors = gray_colors(&["310", "B5200", "318", "414", "168"]);
+
+    let settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(27.0),
+        custom_height_mm: Some(27.0),
+        resample_filter: ResampleFilter::AreaAverage,
+        ..GenerationSettings::default()
+    };
+    let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+    assert_eq!(counts.len(), 1, "Area averaging should give a uniform result, got {:?}", counts);
+    assert_eq!(counts[0].floss, "318");
+
+    for filter in [ResampleFilter::Nearest, ResampleFilter::Triangle, ResampleFilter::CatmullRom, ResampleFilter::Lanczos3] {
+        for fit_option in [ImageFitOption::Fit, ImageFitOption::Crop] {
+            let settings = GenerationSettings { resample_filter: filter, fit_option: fit_option.clone(), ..settings.clone() };
+            let (_, counts, gem_art_data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+            assert_eq!(counts.iter().map(|c| c.count).sum::<u32>(), gem_art_data.num_gems_x * gem_art_data.num_gems_y, "{:?} / {:?}", filter, fit_option);
+        }
+    }
+}
+
+#[test]
+fn test_area_average_resampling_respects_crop() {
+    // Landscape image: red left third, green middle, blue right third.
+    // Cropping onto a square canvas should keep only the green middle.
+    let mut img = DynamicImage::new_rgba8(300, 100);
+    for x in 0..300 {
+        for y in 0..100 {
+            let pixel = if x < 100 { Rgba([220, 20, 30, 255]) } else if x < 200 { Rgba([30, 160, 60, 255]) } else { Rgba([30, 60, 200, 255]) };
+            img.put_pixel(x, y, pixel);
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(27.0),
+        custom_height_mm: Some(27.0),
+        fit_option: ImageFitOption::Crop,
+        resample_filter: ResampleFilter::AreaAverage,
+        ..GenerationSettings::default()
+    };
+    let colors = gray_colors(&["666", "699", "797"]);
+    let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+    assert_eq!(counts.len(), 1, "Only the green middle should remain, got {:?}", counts);
+    assert_eq!(counts[0].floss, "699");
+}
+
+#[test]
+fn test_image_adjustments() {
+    let mut img = DynamicImage::new_rgba8(4, 4);
+    for x in 0..4 {
+        for y in 0..4 {
+            img.put_pixel(x, y, Rgba([100, 120, 180, 255]));
+        }
+    }
+
+    let unchanged = apply_adjustments(img.clone(), &ImageAdjustments::default());
+    assert_eq!(unchanged.to_rgba8(), img.to_rgba8(), "Default adjustments should leave the image untouched");
+
+    let brighter = apply_adjustments(img.clone(), &ImageAdjustments { brightness: 0.2, ..ImageAdjustments::default() });
+    let p = brighter.get_pixel(0, 0);
+    assert!(p[0] > 100 && p[1] > 120 && p[2] > 180, "Brightness should raise every channel, got {:?}", p);
+
+    let gray = apply_adjustments(img.clone(), &ImageAdjustments { saturation: -1.0, ..ImageAdjustments::default() });
+    let p = gray.get_pixel(0, 0);
+    assert_eq!(p[0], p[1]);
+    assert_eq!(p[1], p[2]);
+
+    let balanced = apply_adjustments(img.clone(), &ImageAdjustments { auto_white_balance: true, ..ImageAdjustments::default() });
+    let p = balanced.get_pixel(0, 0);
+    let spread = p[0].max(p[1]).max(p[2]) - p[0].min(p[1]).min(p[2]);
+    assert!(spread <= 2, "Gray-world white balance should neutralise a uniform cast, got {:?}", p);
+    assert_eq!(p[3], 255, "Alpha should be preserved");
+
+    let warmer = apply_adjustments(img.clone(), &ImageAdjustments { temperature: 1.0, ..ImageAdjustments::default() });
+    let p = warmer.get_pixel(0, 0);
+    assert!(p[0] > 100 && p[2] < 180, "Warmer should add red and remove blue, got {:?}", p);
+
+    let lighter_midtones = apply_adjustments(img.clone(), &ImageAdjustments { gamma: 2.0, ..ImageAdjustments::default() });
+    assert!(lighter_midtones.get_pixel(0, 0)[0] > 100);
+}
+
+#[test]
+fn test_generation_settings_serialize_with_adjustments() {
+    let settings = GenerationSettings {
+        adjustments: ImageAdjustments { contrast: 0.3, tint: -0.2, auto_white_balance: true, ..ImageAdjustments::default() },
+        ..GenerationSettings::default()
+    };
+    let json = serde_json::to_string(&settings).unwrap();
+    let restored: GenerationSettings = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored, settings);
+}
+
+#[test]
+fn test_denoise_and_sharpen_filters() {
+    // Mid-gray field with a single white speck and a hard vertical edge at x = 10
+    let mut img = DynamicImage::new_rgba8(20, 20);
+    for x in 0..20 {
+        for y in 0..20 {
+            let v = if x < 10 { 80 } else { 160 };
+            img.put_pixel(x, y, Rgba([v, v, v, 255]));
+        }
+    }
+    img.put_pixel(4, 4, Rgba([255, 255, 255, 255]));
+
+    assert_eq!(denoise_image(img.clone(), 2, 0.0).to_rgba8(), img.to_rgba8(), "Zero strength should be a no-op");
+    assert_eq!(sharpen_image(img.clone(), 2.0, 0.0).to_rgba8(), img.to_rgba8(), "Zero strength should be a no-op");
+
+    let denoised = denoise_image(img.clone(), 1, 1.0);
+    assert_eq!(denoised.get_pixel(4, 4)[0], 80, "Median should remove an isolated speck");
+    assert_eq!(denoised.get_pixel(9, 10)[0], 80, "Median should keep the edge in place");
+    assert_eq!(denoised.get_pixel(10, 10)[0], 160, "Median should keep the edge in place");
+    assert_eq!(denoised.get_pixel(4, 4)[3], 255, "Alpha should be preserved");
+
+    let sharpened = sharpen_image(img.clone(), 1.5, 1.0);
+    assert!(sharpened.get_pixel(9, 15)[0] < 80, "Unsharp mask should darken the dark side of the edge");
+    assert!(sharpened.get_pixel(10, 15)[0] > 160, "Unsharp mask should lighten the light side of the edge");
+    assert_eq!(sharpened.get_pixel(0, 15)[0], 80, "Flat areas should be unchanged");
+}
+
+#[test]
+fn test_lightness_mapping_modes_spread_dark_images() {
+    // Dark, low-contrast gradient with a small specular highlight in one corner
+    let mut img = DynamicImage::new_rgba8(60, 60);
+    for x in 0..60 {
+        for y in 0..60 {
+            let v = 20 + (x * 40 / 59) as u8;
+            img.put_pixel(x, y, Rgba([v, v, v, 255]));
+        }
+    }
+    for x in 57..60 {
+        for y in 57..60 {
+            img.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = gray_colors(&["310", "3799", "413", "317", "414", "318", "415", "762"]);
+
+    let colors_used = |mode: ColorMappingMode| {
+        let settings = GenerationSettings {
+            margin_mm: 0.0,
+            custom_width_mm: Some(54.0),
+            custom_height_mm: Some(54.0),
+            mapping_mode: mode,
+            mapping_weight: 1.0,
+            resample_filter: ResampleFilter::AreaAverage,
+            ..GenerationSettings::default()
+        };
+        let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+        counts.len()
+    };
+
+    let min_max = colors_used(ColorMappingMode::AdaptiveLightnessWeighted);
+    let percentile = colors_used(ColorMappingMode::PercentileStretch);
+    let equalized = colors_used(ColorMappingMode::HistogramEqualization);
+    let clahe = colors_used(ColorMappingMode::Clahe);
+    assert!(percentile > min_max, "Percentile stretch should ignore the highlight ({} vs {} colors)", percentile, min_max);
+    assert!(equalized >= colors.len() - 1, "Equalization should spread across the palette, used {}", equalized);
+    assert!(clahe > min_max, "CLAHE should spread the gradient ({} vs {} colors)", clahe, min_max);
+}
+
+#[test]
+fn test_importance_mask_favors_accuracy() {
+    // Left half mid gray, right half a gradient, so dithering and lightness mapping both matter
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let v = if x < 50 { 128 } else { 60 + y as u8 };
+            img.put_pixel(x, y, Rgba([v, v, v, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = gray_colors(&["310", "3799", "413", "317", "414", "318", "415", "762"]);
+
+    // Masks are scaled to the image, so a tiny mask covers the whole source
+    let solid_mask = |v: u8| {
+        let mut mask = DynamicImage::new_rgba8(10, 10);
+        for x in 0..10 {
+            for y in 0..10 {
+                mask.put_pixel(x, y, Rgba([v, v, v, 255]));
+            }
+        }
+        encode_image_data_url(&mask)
+    };
+
+    let base_settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(54.0),
+        custom_height_mm: Some(54.0),
+        ..GenerationSettings::default()
+    };
+    let stylised = GenerationSettings {
+        mapping_mode: ColorMappingMode::AdaptiveLightnessWeighted,
+        mapping_weight: 1.0,
+        dithering_mode: DitheringMode::FloydSteinberg,
+        ..base_settings.clone()
+    };
+
+    let grid = |settings: &GenerationSettings| generate_gem_art_preview_with_settings(&image_data_url, &colors, settings).unwrap().2.gem_grid;
+    let nearest = grid(&base_settings);
+    let unmasked = grid(&stylised);
+    assert_ne!(unmasked, nearest, "Lightness mapping and dithering should change the result");
+
+    let fully_important = GenerationSettings { importance_mask: Some(solid_mask(255)), ..stylised.clone() };
+    assert_eq!(grid(&fully_important), nearest, "A white mask should give plain nearest-color matching");
+
+    let unimportant = GenerationSettings { importance_mask: Some(solid_mask(0)), ..stylised.clone() };
+    assert_eq!(grid(&unimportant), unmasked, "A black mask should leave the result unchanged");
+
+    let invalid = GenerationSettings { importance_mask: Some("data:image/png;base64,invalid".to_string()), ..stylised };
+    assert!(generate_gem_art_preview_with_settings(&image_data_url, &colors, &invalid).is_err());
+}
+
+#[test]
+fn test_palette_regions_restrict_colors_per_area() {
+    // Blue left half, red right half
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let color = if x < 50 { [19, 71, 125, 255] } else { [227, 29, 66, 255] };
+            img.put_pixel(x, y, Rgba(color));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = gray_colors(&["797", "666", "318"]);
+
+    // Left half restricted to gray, as a polygon and as an equivalent painted mask
+    let polygon_region = PaletteRegion {
+        name: "left".to_string(),
+        mask: None,
+        polygon: Some(vec![(0.0, 0.0), (0.5, 0.0), (0.5, 1.0), (0.0, 1.0)]),
+        allowed_flosses: vec!["318".to_string()],
+    };
+    let mut mask = DynamicImage::new_rgba8(4, 4);
+    for x in 0..2 {
+        for y in 0..4 {
+            mask.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+        }
+    }
+    let mask_region = PaletteRegion { mask: Some(encode_image_data_url(&mask)), polygon: None, ..polygon_region.clone() };
+
+    for region in [polygon_region, mask_region] {
+        // Regions are defined on the source image, so they hold across gem sizes
+        for gem_size_mm in [2.7, 5.4] {
+            let settings = GenerationSettings {
+                margin_mm: 0.0,
+                custom_width_mm: Some(54.0),
+                custom_height_mm: Some(54.0),
+                gem_size_mm,
+                palette_regions: vec![region.clone()],
+                ..GenerationSettings::default()
+            };
+            let (_, _, data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+            for gx in 0..data.num_gems_x {
+                for gy in 0..data.num_gems_y {
+                    let centre = (gx as f32 + 0.5) / data.num_gems_x as f32;
+                    if (centre - 0.5).abs() < 1.0 / data.num_gems_x as f32 {
+                        // Cell straddles the split
+                        continue;
+                    }
+                    let floss = data.filtered_dmc_colors[data.gem_grid[(gx * data.num_gems_y + gy) as usize]].floss.trim();
+                    let expected = if centre < 0.5 { "318" } else { "666" };
+                    assert_eq!(floss, expected, "Cell ({}, {}) at gem size {}", gx, gy, gem_size_mm);
+                }
+            }
+        }
+    }
+
+    // A region whose flosses aren't selected falls back to the full selection
+    let unselected = PaletteRegion {
+        name: "unselected".to_string(),
+        mask: None,
+        polygon: Some(vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]),
+        allowed_flosses: vec!["310".to_string()],
+    };
+    let base_settings = GenerationSettings { margin_mm: 0.0, custom_width_mm: Some(54.0), custom_height_mm: Some(54.0), ..GenerationSettings::default() };
+    let with_unselected = GenerationSettings { palette_regions: vec![unselected], ..base_settings.clone() };
+    let (_, expected_counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &base_settings).unwrap();
+    let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &with_unselected).unwrap();
+    assert_eq!(counts, expected_counts);
+}
+
+#[test]
+fn test_background_detection_flattens_to_one_floss() {
+    // White background around a red square that has an enclosed white hole
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let in_square = (20..80).contains(&x) && (20..80).contains(&y);
+            let in_hole = (40..60).contains(&x) && (40..60).contains(&y);
+            let color = if in_square && !in_hole { [227, 29, 66, 255] } else { [255, 255, 255, 255] };
+            img.put_pixel(x, y, Rgba(color));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = gray_colors(&["666", "318"]);
+    let base_settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(50.0),
+        custom_height_mm: Some(50.0),
+        gem_size_mm: 5.0,
+        background_floss: Some("B5200".to_string()),
+        ..GenerationSettings::default()
+    };
+    let floss_at = |data: &GemArtData, gx: u32, gy: u32| {
+        let index = data.gem_grid[(gx * data.num_gems_y + gy) as usize];
+        if index == EMPTY_CELL { None } else { Some(data.filtered_dmc_colors[index].floss.trim().to_string()) }
+    };
+
+    // Flood fill stops at the subject, so the enclosed hole is mapped normally
+    let flood_fill = GenerationSettings { background_mode: BackgroundMode::FloodFillFromBorders, ..base_settings.clone() };
+    let (_, counts, data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &flood_fill).unwrap();
+    assert_eq!(floss_at(&data, 0, 0).as_deref(), Some("B5200"));
+    assert_eq!(floss_at(&data, 9, 9).as_deref(), Some("B5200"));
+    assert_eq!(floss_at(&data, 2, 3).as_deref(), Some("666"));
+    assert!(!data.background_mask[(4 * data.num_gems_y + 5) as usize]);
+    let background_cells = data.background_mask.iter().filter(|&&b| b).count() as u32;
+    assert_eq!(background_cells, 10 * 10 - 6 * 6);
+    // The fill floss joins the palette, so the white hole still matches it
+    assert_eq!(counts.iter().find(|c| c.floss.trim() == "B5200").map(|c| c.count), Some(background_cells + 2 * 2));
+
+    // A color key also catches the enclosed hole
+    let color_key = GenerationSettings { background_mode: BackgroundMode::ColorKey, ..base_settings.clone() };
+    let (_, _, data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &color_key).unwrap();
+    assert_eq!(floss_at(&data, 4, 5).as_deref(), Some("B5200"));
+    assert_eq!(data.background_mask.iter().filter(|&&b| b).count(), 10 * 10 - 6 * 6 + 2 * 2);
+
+    // Without a fill floss the background is left empty and kept out of the counts
+    let unmapped = GenerationSettings { background_floss: None, ..flood_fill };
+    let (_, counts, data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &unmapped).unwrap();
+    assert_eq!(floss_at(&data, 0, 0), None);
+    assert_eq!(counts.iter().map(|c| c.count).sum::<u32>(), 6 * 6);
+
+    let unknown = GenerationSettings { background_floss: Some("not-a-floss".to_string()), ..color_key };
+    assert!(generate_gem_art_preview_with_settings(&image_data_url, &colors, &unknown).is_err());
+}
+
+#[test]
+fn test_min_gems_per_color_keeps_background_floss() {
+    // Red square inside a one-gem white border, so the background floss is the rarest color
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let in_square = (10..90).contains(&x) && (10..90).contains(&y);
+            let color = if in_square { [227, 29, 66, 255] } else { [255, 255, 255, 255] };
+            img.put_pixel(x, y, Rgba(color));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = gray_colors(&["666", "318"]);
+    let settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(50.0),
+        custom_height_mm: Some(50.0),
+        gem_size_mm: 5.0,
+        background_mode: BackgroundMode::FloodFillFromBorders,
+        background_floss: Some("B5200".to_string()),
+        min_gems_per_color: 50,
+        ..GenerationSettings::default()
+    };
+    let (_, counts, data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+    let background_cells = data.background_mask.iter().filter(|&&b| b).count() as u32;
+    assert_eq!(background_cells, 10 * 10 - 8 * 8);
+    for (cell, _) in data.gem_grid.iter().zip(&data.background_mask).filter(|(_, &is_background)| is_background) {
+        assert_eq!(data.filtered_dmc_colors[*cell].floss.trim(), "B5200");
+    }
+    assert_eq!(counts.iter().find(|c| c.floss.trim() == "B5200").map(|c| c.count), Some(background_cells));
+    assert_eq!(counts.iter().find(|c| c.floss.trim() == "666").map(|c| c.count), Some(8 * 8));
+}
+
+#[test]
+fn test_transparent_pixels_become_empty_cells() {
+    // Die-cut style design: an opaque square on a fully transparent canvas
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 20..80 {
+        for y in 20..80 {
+            img.put_pixel(x, y, Rgba([227, 29, 66, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = gray_colors(&["666", "310"]);
+    let settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(50.0),
+        custom_height_mm: Some(50.0),
+        gem_size_mm: 5.0,
+        dithering_mode: DitheringMode::FloydSteinberg,
+        ..GenerationSettings::default()
+    };
+
+    let (_, counts, data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+    for gx in 0..data.num_gems_x {
+        for gy in 0..data.num_gems_y {
+            let index = data.gem_grid[(gx * data.num_gems_y + gy) as usize];
+            if (2..8).contains(&gx) && (2..8).contains(&gy) {
+                // No error leaks in from the empty cells
+                assert_eq!(data.filtered_dmc_colors[index].floss.trim(), "666", "Cell ({}, {})", gx, gy);
+            } else {
+                assert_eq!(index, EMPTY_CELL, "Cell ({}, {})", gx, gy);
+            }
+        }
+    }
+    assert_eq!(counts.len(), 1);
+    assert_eq!(counts[0].count, 6 * 6);
+    assert!(generate_gem_art_final(&data).is_ok());
+
+    // A zero threshold maps every cell, including the black behind the transparency
+    let opaque = GenerationSettings { alpha_threshold: 0.0, ..settings };
+    let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &opaque).unwrap();
+    assert_eq!(counts.iter().map(|c| c.count).sum::<u32>(), 10 * 10);
+}
+
+#[test]
+fn test_canvas_shapes_leave_outside_cells_empty() {
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            img.put_pixel(x, y, Rgba([227, 29, 66, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let colors = gray_colors(&["666"]);
+    let base_settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(50.0),
+        custom_height_mm: Some(50.0),
+        gem_size_mm: 5.0,
+        ..GenerationSettings::default()
+    };
+    let filled = |shape: CanvasShape, custom_shape_mask: Option<String>| {
+        let settings = GenerationSettings { canvas_shape: shape, custom_shape_mask, ..base_settings.clone() };
+        let (_, counts, data) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &settings).unwrap();
+        let filled: Vec<bool> = data.gem_grid.iter().map(|&i| i != EMPTY_CELL).collect();
+        assert_eq!(counts.iter().map(|c| c.count).sum::<u32>() as usize, filled.iter().filter(|&&f| f).count());
+        assert!(generate_gem_art_final(&data).is_ok());
+        move |gx: usize, gy: usize| filled[gx * 10 + gy]
+    };
+
+    let rectangle = filled(CanvasShape::Rectangle, None);
+    assert!(rectangle(0, 0) && rectangle(9, 9));
+
+    for shape in [CanvasShape::Circle, CanvasShape::Oval] {
+        let cells = filled(shape, None);
+        assert!(!cells(0, 0) && !cells(9, 0) && !cells(0, 9) && !cells(9, 9));
+        assert!(cells(5, 5) && cells(0, 5) && cells(5, 0));
+    }
+
+    // Two lobes with a notch between them at the top, and a point at the bottom
+    let heart = filled(CanvasShape::Heart, None);
+    assert!(!heart(4, 0) && !heart(5, 0));
+    assert!(heart(2, 1) && heart(7, 1) && heart(5, 5));
+    assert!(!heart(0, 9) && !heart(9, 9));
+    for gx in 0..10 {
+        for gy in 0..10 {
+            assert_eq!(heart(gx, gy), heart(9 - gx, gy), "Heart is symmetric at ({}, {})", gx, gy);
+        }
+    }
+
+    // Custom masks are stretched over the grid: white on the left, black on the right
+    let mut mask = DynamicImage::new_rgba8(2, 1);
+    mask.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+    mask.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+    let custom = filled(CanvasShape::Custom, Some(encode_image_data_url(&mask)));
+    for gy in 0..10 {
+        assert!(custom(4, gy) && !custom(5, gy));
+    }
+    let without_mask = filled(CanvasShape::Custom, None);
+    assert!(without_mask(9, 9));
+}
+
+#[test]
+fn test_palette_advisor_reports_contributions_and_suggestions() {
+    // Blue left half, red right half
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let color = if x < 50 { [19, 71, 125, 255] } else { [227, 29, 66, 255] };
+            img.put_pixel(x, y, Rgba(color));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(50.0),
+        custom_height_mm: Some(50.0),
+        gem_size_mm: 5.0,
+        ..GenerationSettings::default()
+    };
+
+    let report = analyze_palette(&image_data_url, &gray_colors(&["797", "666", "318"]), &settings, 3).unwrap();
+    assert_eq!(report.contributions.len(), 3);
+    let contribution = |floss: &str| report.contributions.iter().find(|c| c.floss.trim() == floss).unwrap();
+    assert_eq!(contribution("797").gems, 50);
+    assert_eq!(contribution("666").gems, 50);
+    assert!(contribution("797").mean_delta_e_increase.unwrap() > 1.0);
+    assert!(contribution("797").max_delta_e_increase.unwrap() > 1.0);
+    // An unused floss can be dropped for free
+    assert_eq!(contribution("318").gems, 0);
+    assert_eq!(contribution("318").mean_delta_e_increase, Some(0.0));
+    assert_eq!(report.contributions.last().unwrap().floss.trim(), "318");
+    assert!(report.suggestions.len() <= 3);
+    assert!(report.suggestions.iter().all(|s| s.mean_delta_e_decrease <= report.mean_delta_e));
+
+    // Without the blue, the blue is the most helpful addition
+    let report = analyze_palette(&image_data_url, &gray_colors(&["666"]), &settings, 3).unwrap();
+    assert_eq!(report.contributions[0].mean_delta_e_increase, None);
+    assert_eq!(report.contributions[0].max_delta_e_increase, None);
+    assert_eq!(report.suggestions.len(), 3);
+    assert_eq!(report.suggestions[0].floss.trim(), "797");
+    assert_eq!(report.suggestions[0].gems, 50);
+    assert!(report.suggestions[0].mean_delta_e_decrease > report.suggestions[1].mean_delta_e_decrease);
+    assert!(report.suggestions.iter().all(|s| s.floss.trim() != "666"));
+}
+
+#[test]
+fn test_quality_metrics_and_error_heatmap() {
+    // Smooth horizontal gray ramp
+    let mut img = DynamicImage::new_rgba8(100, 100);
+    for x in 0..100 {
+        for y in 0..100 {
+            let v = (x * 255 / 99) as u8;
+            img.put_pixel(x, y, Rgba([v, v, v, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(50.0),
+        custom_height_mm: Some(50.0),
+        gem_size_mm: 5.0,
+        ..GenerationSettings::default()
+    };
+
+    let (_, _, coarse) = generate_gem_art_preview_with_settings(&image_data_url, &black_and_white_colors(), &settings).unwrap();
+    let fine_colors = gray_colors(&["B5200", "762", "415", "318", "414", "413", "310"]);
+    let (_, _, fine) = generate_gem_art_preview_with_settings(&image_data_url, &fine_colors, &settings).unwrap();
+
+    for data in [&coarse, &fine] {
+        let q = data.quality;
+        assert!(q.mean_delta_e <= q.p95_delta_e && q.p95_delta_e <= q.max_delta_e);
+        assert!(q.ssim > 0.0 && q.ssim <= 1.0 + 1e-4);
+        assert_eq!(data.cell_errors.len(), data.gem_grid.len());
+        let max_error = data.cell_errors.iter().cloned().fold(0.0f32, f32::max);
+        assert!((max_error - q.max_delta_e).abs() < 1e-4);
+    }
+    // More grays follow the ramp more closely
+    assert!(fine.quality.mean_delta_e < coarse.quality.mean_delta_e);
+    assert!(fine.quality.p95_delta_e < coarse.quality.p95_delta_e);
+    assert!(fine.quality.ssim > coarse.quality.ssim);
+
+    let heatmap_url = generate_error_heatmap(&fine).unwrap();
+    let heatmap_bytes = general_purpose::STANDARD.decode(heatmap_url.split(',').nth(1).unwrap()).unwrap();
+    let heatmap = image::load_from_memory(&heatmap_bytes).unwrap();
+    assert_eq!(heatmap.dimensions(), (fine.num_gems_x, fine.num_gems_y));
+}
+
+#[test]
+fn test_auto_tune_respects_constraints() {
+    // Color ramps with fine blue stripes, so smaller gems keep more detail
+    let mut img = DynamicImage::new_rgba8(200, 200);
+    for x in 0..200 {
+        for y in 0..200 {
+            let b = if (x / 6) % 2 == 0 { 200 } else { 40 };
+            img.put_pixel(x, y, Rgba([(x * 255 / 199) as u8, (y * 255 / 199) as u8, b, 255]));
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+    let allowed = gray_colors(&["B5200", "310", "666", "797", "704", "307", "996", "208", "3865", "3371"]);
+    let settings = GenerationSettings { margin_mm: 0.0, ..GenerationSettings::default() };
+    let constraints = TuningConstraints {
+        max_colors: 6,
+        max_gems: Some(800),
+        canvas_width_mm: 100.0,
+        canvas_height_mm: 100.0,
+        ..TuningConstraints::default()
+    };
+
+    let tuned = auto_tune(&image_data_url, &allowed, &settings, &constraints).unwrap();
+    assert!(tuned.total_gems <= 800);
+    assert!(tuned.colors_used <= 6 && tuned.flosses.len() <= 6);
+    // 3.5 mm is the smallest listed gem that keeps a 100 mm square under 800 gems
+    assert_eq!(tuned.settings.gem_size_mm, 3.5);
+    assert_eq!(tuned.settings.custom_width_mm, Some(100.0));
+    let colors: Vec<Color> = allowed.iter().filter(|c| tuned.flosses.contains(&c.floss_number)).cloned().collect();
+    let (_, counts, _) = generate_gem_art_preview_with_settings(&image_data_url, &colors, &tuned.settings).unwrap();
+    assert_eq!(counts.iter().map(|c| c.count).sum::<u32>(), tuned.total_gems);
+
+    // A per-color cost caps the palette size
+    let priced = TuningConstraints { cost_per_color: 10.0, max_cost: Some(30.0), ..constraints.clone() };
+    let tuned = auto_tune(&image_data_url, &allowed, &settings, &priced).unwrap();
+    assert!(tuned.colors_used <= 3);
+    assert!(tuned.cost <= 30.0);
+
+    let impossible = TuningConstraints { max_gems: Some(10), ..constraints };
+    assert!(auto_tune(&image_data_url, &allowed, &settings, &impossible).is_err());
+}
+
+#[test]
+fn test_auto_tune_in_worker_reports_progress_and_cancels() {
+    let mut img = DynamicImage::new_rgba8(80, 80);
+    for x in 0..80 {
+        for y in 0..80 {
+            img.put_pixel(x, y, Rgba([(x * 3) as u8, (y * 3) as u8, 120, 255]));
+        }
+    }
+    let image_data = encode_image_data_url(&img);
+    let allowed = gray_colors(&["B5200", "310", "666", "797", "704", "307"]);
+    let settings = GenerationSettings { margin_mm: 0.0, ..GenerationSettings::default() };
+    let constraints = TuningConstraints { max_colors: 4, canvas_width_mm: 60.0, canvas_height_mm: 60.0, ..TuningConstraints::default() };
+    let request = WorkerRequest::AutoTune { job: 3, image_data: image_data.clone(), allowed_colors: allowed.clone(), settings: settings.clone(), constraints: constraints.clone() };
+    let request: WorkerRequest = serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+
+    let mut pipeline = PreviewPipeline::default();
+    let mut responses = Vec::new();
+    handle_request(&mut pipeline, request.clone(), &CancellationToken::new(), &mut |response, _| responses.push(response));
+    match responses.pop() {
+        Some(WorkerResponse::Tuned { job: 3, configuration }) => assert_eq!(configuration, auto_tune(&image_data, &allowed, &settings, &constraints).unwrap()),
+        _ => panic!("Expected a tuned configuration"),
+    }
+    let fractions: Vec<f32> = responses.iter().map(|response| match response {
+        WorkerResponse::Progress { job: 3, fraction, .. } => *fraction,
+        _ => panic!("Expected progress"),
+    }).collect();
+    // Every candidate reports, in order
+    assert!(fractions.len() > constraints.gem_sizes_mm.len() * 2);
+    assert!(fractions.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    // Cancelled a few candidates in, the search stops there
+    let mut responses = Vec::new();
+    let cancellation = CancellationToken::new();
+    handle_request(&mut pipeline, request, &cancellation, &mut |response, _| {
+        responses.push(response);
+        if responses.len() == 5 {
+            cancellation.cancel();
+        }
+    });
+    assert_eq!(responses.len(), 6);
+    assert!(matches!(responses.last(), Some(WorkerResponse::TuneFailed { job: 3, error }) if is_cancelled_error(error)));
+}
+
+#[test]
+fn test_lightness_index_matches_full_scan() {
+    use palette::{IntoColor, Lab, Srgb};
+
+    let palette_labs: Vec<[f32; 3]> = yew_project::dmc_colors::get_dmc_colors()
+        .iter()
+        .map(|c| {
+            let lab: Lab = Srgb::new(c.r as f32 / 255.0, c.g as f32 / 255.0, c.b as f32 / 255.0).into_color();
+            [lab.l, lab.a, lab.b]
+        })
+        .collect();
+    let palette_ls: Vec<f32> = palette_labs.iter().map(|lab| lab[0]).collect();
+    let index = LightnessIndex::new(&palette_ls);
+
+    // Deterministic spread of queries over the Lab gamut
+    let queries: Vec<[f32; 3]> = (0..200u32)
+        .map(|i| {
+            let (x, y, z) = ((i * 37) % 101, (i * 53) % 97, (i * 71) % 89);
+            [x as f32, y as f32 * 2.0 - 97.0, z as f32 * 2.0 - 89.0]
+        })
+        .collect();
+
+    for metric in [ColorMetric::Cie76, ColorMetric::Cie94, ColorMetric::Ciede2000, ColorMetric::Oklab, ColorMetric::Redmean] {
+        for weight in [0.0, 0.2, 0.5, 0.8, 1.0] {
+            for (q, query) in queries.iter().enumerate() {
+                let target_l = (query[0] * 0.7 + 20.0).min(100.0);
+                // Every other query only allows a third of the palette, like a palette region
+                let allowed = |i: usize| q % 2 == 0 || i % 3 == q % 3;
+                let distance = |i: usize| lab_distance(metric, *query, palette_labs[i]);
+
+                let mut expected = None;
+                let mut best_score = f32::INFINITY;
+                for (i, lab) in palette_labs.iter().enumerate() {
+                    if !allowed(i) {
+                        continue;
+                    }
+                    let dl = if weight > 0.0 { (target_l - lab[0]).abs() } else { 0.0 };
+                    let score = weight * dl + (1.0 - weight) * distance(i);
+                    if expected.is_none() || score < best_score {
+                        best_score = score;
+                        expected = Some(i);
+                    }
+                }
+
+                let found = index.nearest(target_l, query[0], weight, lightness_bound_factor(metric), allowed, distance);
+                assert_eq!(found, expected, "{:?} weight {} query {:?}", metric, weight, query);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_color_lut_matches_direct_matching() {
+    // Random colors on the left, smooth ramps (including near-black) on the right
+    let mut img = DynamicImage::new_rgba8(60, 60);
+    let mut seed = 12345u32;
+    for x in 0..60 {
+        for y in 0..60 {
+            let pixel = if x < 30 {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let [r, g, b, _] = seed.to_le_bytes();
+                Rgba([r, g, b, 255])
+            } else {
+                Rgba([(x - 30) as u8 * 8, y as u8 * 4, (x + y) as u8 / 4, 255])
+            };
+            img.put_pixel(x, y, pixel);
+        }
+    }
+    let image_data_url = encode_image_data_url(&img);
+
+    let all_colors = all_dmc_colors();
+    let some_colors: Vec<Color> = all_colors.iter().step_by(7).cloned().collect();
+    let base_settings = GenerationSettings {
+        margin_mm: 0.0,
+        custom_width_mm: Some(150.0),
+        custom_height_mm: Some(150.0),
+        gem_size_mm: 2.5,
+        ..GenerationSettings::default()
+    };
+
+    for metric in [ColorMetric::Cie76, ColorMetric::Oklab, ColorMetric::Ciede2000] {
+        // Changing the selection must not reuse the previous table
+        for colors in [&all_colors, &some_colors] {
+            let direct = GenerationSettings { color_metric: metric, color_lut: false, ..base_settings.clone() };
+            let cached = GenerationSettings { color_lut: true, ..direct.clone() };
+            let (_, _, expected) = generate_gem_art_preview_with_settings(&image_data_url, colors, &direct).unwrap();
+            let (_, _, actual) = generate_gem_art_preview_with_settings(&image_data_url, colors, &cached).unwrap();
+            assert_eq!(actual.gem_grid, expected.gem_grid, "{:?} with {} colors", metric, colors.len());
+        }
+    }
+}
+
+#[test]
+fn test_color_lut_matches_direct_search() {
+    let rgb_to_lab = |rgb: [u8; 3]| {
+        let lab: Lab = Srgb::new(rgb[0] as f32 / 255.0, rgb[1] as f32 / 255.0, rgb[2] as f32 / 255.0).into_color();
+        [lab.l, lab.a, lab.b]
+    };
+    let dmc_labs: Vec<[f32; 3]> = yew_project::dmc_colors::get_dmc_colors().iter().map(|c| rgb_to_lab([c.r, c.g, c.b])).collect();
+    // A coarse palette, so that most buckets store a single entry for all their colors
+    let palette_labs: Vec<[f32; 3]> = dmc_labs.iter().step_by(23).copied().collect();
+
+    for metric in [ColorMetric::Cie76, ColorMetric::Cie94, ColorMetric::Ciede2000, ColorMetric::Oklab, ColorMetric::Redmean] {
+        // Only Euclidean metrics store whole buckets; the others look every color up
+        // directly, so a sparser sample covers them
+        let bucket_step = if is_euclidean(metric) { 5 } else { 97 };
+        let mut seed = 777u32;
+        let mut colors = Vec::new();
+        for bucket in (0..1u32 << 18).step_by(bucket_step) {
+            // One color per 4 x 4 x 4 bucket, at a pseudo-random offset inside it
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let offset = seed >> 26;
+            let channel = |shift: u32| (((bucket >> shift) & 63) << 2 | (offset >> (shift / 3)) & 3) as u8;
+            colors.push([channel(12), channel(6), channel(0)]);
+        }
+
+        let metric_palette: Vec<[f32; 3]> = palette_labs.iter().map(|&lab| to_metric_space(metric, lab)).collect();
+        let nearest = |lab: [f32; 3]| {
+            let query = to_metric_space(metric, lab);
+            (0..metric_palette.len())
+                .min_by(|&a, &b| metric_distance(metric, query, metric_palette[a]).total_cmp(&metric_distance(metric, query, metric_palette[b])))
+                .unwrap()
+        };
+        let mut lut = ColorLut::new();
+        lut.prepare(&colors, metric, &metric_palette, nearest, &GenerationHooks::default()).unwrap();
+        for &rgb in &colors {
+            assert_eq!(lut.get(rgb), nearest(rgb_to_lab(rgb)), "{:?} at {:?}", metric, rgb);
+        }
+
+        // Buckets were judged from one color each; every other color in them must agree
+        let whole_buckets: Vec<[u8; 3]> = colors
+            .iter()
+            .step_by(11)
+            .flat_map(|&[r, g, b]| (0..64u8).map(move |offset| [r & !3 | offset >> 4, g & !3 | (offset >> 2) & 3, b & !3 | offset & 3]))
+            .collect();
+        lut.prepare(&whole_buckets, metric, &metric_palette, nearest, &GenerationHooks::default()).unwrap();
+        for &rgb in &whole_buckets {
+            assert_eq!(lut.get(rgb), nearest(rgb_to_lab(rgb)), "{:?} at {:?}", metric, rgb);
+        }
+    }
+}
+
+#[test]
+fn test_preview_pipeline_only_reruns_changed_stages() {
+    let gradient = |shift: u32| {
+        let mut img = DynamicImage::new_rgba8(80, 60);
+        for x in 0..80 {
+            for y in 0..60 {
+                img.put_pixel(x, y, Rgba([(x * 3 + shift) as u8, (y * 4) as u8, 128, 255]));
+            }
+        }
+        encode_image_data_url(&img)
+    };
+    let first_image = gradient(0);
+    let second_image = gradient(40);
+    let colors = gray_colors(&["B5200", "762", "415", "318", "414", "317", "413", "310"]);
+    let fewer_colors = &colors[..6];
+    let settings = GenerationSettings { margin_mm: 0.0, custom_width_mm: Some(100.0), custom_height_mm: Some(80.0), gem_size_mm: 2.5, ..GenerationSettings::default() };
+    let larger_gems = GenerationSettings { gem_size_mm: 4.0, ..settings.clone() };
+    let brighter = GenerationSettings { adjustments: ImageAdjustments { brightness: 0.2, ..ImageAdjustments::default() }, ..larger_gems.clone() };
+
+    let mut pipeline = PreviewPipeline::default();
+    let mut expected_stats = PipelineStats::default();
+    let steps: [(&str, &[Color], &GenerationSettings, [u32; 6]); 6] = [
+        (&first_image, &colors, &settings, [1, 1, 1, 1, 1, 1]),
+        (&first_image, &colors, &settings, [0, 0, 0, 0, 0, 0]),
+        (&first_image, fewer_colors, &settings, [0, 0, 0, 0, 1, 1]),
+        (&first_image, fewer_colors, &larger_gems, [0, 0, 0, 1, 1, 1]),
+        (&first_image, fewer_colors, &brighter, [0, 1, 1, 1, 1, 1]),
+        (&second_image, fewer_colors, &brighter, [1, 1, 1, 1, 1, 1]),
+    ];
+    for (step, (image, colors, settings, reruns)) in steps.into_iter().enumerate() {
+        let (preview, mut counts, data) = pipeline.generate(image, colors, settings).unwrap();
+        let (fresh_preview, mut fresh_counts, fresh_data) = generate_gem_art_preview_with_settings(image, colors, settings).unwrap();
+        assert_eq!(preview, fresh_preview, "Step {}", step);
+        // Colors with equal counts may come in any order
+        counts.sort_by(|a, b| a.floss.cmp(&b.floss));
+        fresh_counts.sort_by(|a, b| a.floss.cmp(&b.floss));
+        assert_eq!(counts, fresh_counts, "Step {}", step);
+        assert_eq!(data.gem_grid, fresh_data.gem_grid, "Step {}", step);
+
+        expected_stats.decodes += reruns[0];
+        expected_stats.preparations += reruns[1];
+        expected_stats.fits += reruns[2];
+        expected_stats.downsamples += reruns[3];
+        expected_stats.mappings += reruns[4];
+        expected_stats.renders += reruns[5];
+        assert_eq!(pipeline.stats(), expected_stats, "Step {}", step);
+    }
+
+    // A failing stage doesn't leave stale results behind
+    let huge_margin = GenerationSettings { margin_mm: 60.0, ..brighter.clone() };
+    assert!(pipeline.generate(&second_image, fewer_colors, &huge_margin).is_err());
+    let (_, _, data) = pipeline.generate(&second_image, fewer_colors, &brighter).unwrap();
+    let (_, _, fresh_data) = generate_gem_art_preview_with_settings(&second_image, fewer_colors, &brighter).unwrap();
+    assert_eq!(data.gem_grid, fresh_data.gem_grid);
+}
+
+#[test]
+fn test_preview_renders_at_screen_scale() {
+    let mut img = DynamicImage::new_rgba8(80, 60);
+    for x in 0..80 {
+        for y in 0..60 {
+            let rgba = if x < 40 { [255, 255, 255, 255] } else { [0, 0, 0, 255] };
+            img.put_pixel(x, y, Rgba(rgba));
+        }
+    }
+    let image_data = encode_image_data_url(&img);
+    let settings = GenerationSettings { gem_size_mm: 2.5, ..GenerationSettings::default() };
+    let (preview, _, data) = generate_gem_art_preview_with_settings(&image_data, &black_and_white_colors(), &settings).unwrap();
+
+    let layout = data.preview_layout();
+    let PreviewLayout { width, height, origin_x, origin_y, gem_px } = layout;
+    assert!(width.max(height) <= 1300, "Preview should be screen sized, got {}x{}", width, height);
+    assert!(gem_px >= 1 && gem_px < data.gem_pixels_on_final_image);
+    let page_aspect = data.a4_width_px as f32 / data.a4_height_px as f32;
+    assert!((width as f32 / height as f32 - page_aspect).abs() < 0.02);
+    assert!(origin_x + data.num_gems_x * gem_px <= width && origin_y + data.num_gems_y * gem_px <= height);
+
+    let preview_bytes = general_purpose::STANDARD.decode(preview.trim_start_matches("data:image/png;base64,")).unwrap();
+    let preview_img = image::load_from_memory(&preview_bytes).unwrap();
+    assert_eq!(preview_img.dimensions(), (width, height));
+    for gx in 0..data.num_gems_x {
+        for gy in 0..data.num_gems_y {
+            let color = &data.filtered_dmc_colors[data.gem_grid[(gx * data.num_gems_y + gy) as usize]];
+            let pixel = preview_img.get_pixel(origin_x + gx * gem_px + gem_px / 2, origin_y + gy * gem_px + gem_px / 2);
+            assert_eq!(pixel, Rgba([color.r, color.g, color.b, 255]), "Cell ({}, {})", gx, gy);
+        }
+    }
+
+    // The print page keeps its full resolution
+    let final_image = generate_gem_art_final(&data).unwrap();
+    let final_bytes = general_purpose::STANDARD.decode(final_image.trim_start_matches("data:image/png;base64,")).unwrap();
+    let final_img = image::load_from_memory(&final_bytes).unwrap();
+    assert_eq!(final_img.dimensions(), (data.a4_width_px, data.a4_height_px));
+}
+
+#[test]
+fn test_banded_final_render_matches_full_page() {
+    // Enough distinct colors for two-letter labels, which are the widest
+    let mut img = DynamicImage::new_rgba8(40, 40);
+    let mut seed = 987u32;
+    for x in 0..40 {
+        for y in 0..40 {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let [r, g, b, _] = seed.to_le_bytes();
+            img.put_pixel(x, y, Rgba([r, g, b, 255]));
+        }
+    }
+    let settings = GenerationSettings { margin_mm: 5.0, custom_width_mm: Some(60.0), custom_height_mm: Some(50.0), gem_size_mm: 2.5, ..GenerationSettings::default() };
+    let (_, counts, data) = generate_gem_art_preview_with_settings(&encode_image_data_url(&img), &all_dmc_colors(), &settings).unwrap();
+    assert!(counts.len() > 26);
+
+    let render = |band_rows: u32| {
+        let mut page = RgbaImage::new(data.a4_width_px, data.a4_height_px);
+        let mut next_row = 0;
+        render_gem_art_bands(&data, band_rows, |top, band| {
+            assert_eq!(top, next_row);
+            assert!(band.height() <= band_rows && band.width() == data.a4_width_px);
+            page.copy_from(band, 0, top).unwrap();
+            next_row += band.height();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(next_row, data.a4_height_px);
+        page
+    };
+    let full_page = render(data.a4_height_px);
+    assert!(full_page == render(37), "Bands cutting through gems should join up seamlessly");
+    assert!(full_page == render(data.gem_pixels_on_final_image * 3));
+
+    let mut png_bytes = Vec::new();
+    write_gem_art_png(&data, &mut png_bytes).unwrap();
+    assert!(image::load_from_memory(&png_bytes).unwrap().to_rgba8() == full_page);
+    let final_image = generate_gem_art_final(&data).unwrap();
+    let final_bytes = general_purpose::STANDARD.decode(final_image.trim_start_matches("data:image/png;base64,")).unwrap();
+    assert_eq!(final_bytes, png_bytes);
+}
+
+#[test]
+fn test_pattern_generation_skips_preview_encoding() {
+    let mut img = DynamicImage::new_rgba8(60, 40);
+    for x in 0..60 {
+        for y in 0..40 {
+            img.put_pixel(x, y, Rgba([(x * 4) as u8, (y * 6) as u8, 90, 255]));
+        }
+    }
+    let image_data = encode_image_data_url(&img);
+    let colors = gray_colors(&["B5200", "762", "415", "318", "414", "317", "413", "310"]);
+    let settings = GenerationSettings { custom_width_mm: Some(100.0), custom_height_mm: Some(80.0), gem_size_mm: 2.5, ..GenerationSettings::default() };
+
+    let mut pipeline = PreviewPipeline::default();
+    let (counts, data) = pipeline.generate_pattern(&image_data, &colors, &settings).unwrap();
+    assert_eq!(pipeline.stats().renders, 0);
+    let (preview, generated_counts, generated_data) = pipeline.generate(&image_data, &colors, &settings).unwrap();
+    assert_eq!(pipeline.stats().mappings, 1, "The pattern should be reused");
+    assert_eq!(pipeline.stats().renders, 1);
+    assert_eq!(counts, generated_counts);
+    assert_eq!(data.gem_grid, generated_data.gem_grid);
+    let (_, fresh_data) = generate_gem_art_pattern_with_settings(&image_data, &colors, &settings).unwrap();
+    assert_eq!(data.gem_grid, fresh_data.gem_grid);
+
+    // The raw pixels are exactly what the data URL decodes to
+    let preview_bytes = general_purpose::STANDARD.decode(preview.trim_start_matches("data:image/png;base64,")).unwrap();
+    let decoded = image::load_from_memory(&preview_bytes).unwrap().to_rgba8();
+    assert!(decoded == render_preview_image(&data));
+}
+
+#[test]
+fn test_worker_jobs_report_progress_and_results() {
+    let mut img = DynamicImage::new_rgba8(60, 40);
+    for x in 0..60 {
+        for y in 0..40 {
+            img.put_pixel(x, y, Rgba([(x * 4) as u8, (y * 6) as u8, 90, 255]));
+        }
+    }
+    let image_data = encode_image_data_url(&img);
+    let colors = gray_colors(&["B5200", "762", "415", "318", "310"]);
+    let settings = GenerationSettings { custom_width_mm: Some(100.0), custom_height_mm: Some(80.0), gem_size_mm: 2.5, ..GenerationSettings::default() };
+
+    // Requests cross to the worker as JSON
+    let request = WorkerRequest::Preview { job: 7, image_data: image_data.clone(), colors: colors.clone(), settings: settings.clone() };
+    let request: WorkerRequest = serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+
+    let mut pipeline = PreviewPipeline::default();
+    let mut responses = Vec::new();
+    handle_request(&mut pipeline, request, &CancellationToken::new(), &mut |response, pixels| responses.push((response, pixels)));
+    assert!(responses.iter().all(|(response, _)| response.job() == 7));
+    assert!(responses[..responses.len() - 1].iter().all(|(response, pixels)| matches!(response, WorkerResponse::Progress { .. }) && pixels.is_none()));
+    let fractions: Vec<f32> = responses.iter().filter_map(|(response, _)| match response {
+        WorkerResponse::Progress { fraction, .. } => Some(*fraction),
+        _ => None,
+    }).collect();
+    assert!(!fractions.is_empty() && fractions.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    let (last, pixels) = responses.pop().unwrap();
+    let WorkerResponse::Preview { counts, gem_art_data, width, height, .. } = last else {
+        panic!("Expected a preview");
+    };
+    let (_, expected_counts, expected_data) = generate_gem_art_preview_with_settings(&image_data, &colors, &settings).unwrap();
+    assert_eq!(gem_art_data.gem_grid, expected_data.gem_grid);
+    assert_eq!(counts.iter().map(|c| c.count).sum::<u32>(), expected_counts.iter().map(|c| c.count).sum::<u32>());
+    assert!(pixels.unwrap() == render_preview_image(&expected_data).into_raw());
+    assert_eq!((width, height), (expected_data.preview_layout().width, expected_data.preview_layout().height));
+
+    let mut responses = Vec::new();
+    handle_request(&mut pipeline, WorkerRequest::Final { job: 8, gem_art_data: gem_art_data.clone() }, &CancellationToken::new(), &mut |response, png| responses.push((response, png)));
+    match responses.last() {
+        Some((WorkerResponse::Final { job: 8 }, Some(png))) => {
+            let expected = generate_gem_art_final(&gem_art_data).unwrap();
+            assert!(*png == general_purpose::STANDARD.decode(expected.split(',').nth(1).unwrap()).unwrap());
+        }
+        _ => panic!("Expected the final chart"),
+    }
+
+    // Palette analysis reuses the image the preview left in the pipeline
+    let mut responses = Vec::new();
+    let downsamples = pipeline.stats().downsamples;
+    let analysis = WorkerRequest::AnalyzePalette { job: 11, image_data: image_data.clone(), colors: colors.clone(), settings: settings.clone(), suggestions: 3 };
+    handle_request(&mut pipeline, analysis, &CancellationToken::new(), &mut |response, payload| responses.push((response, payload)));
+    assert_eq!(pipeline.stats().downsamples, downsamples);
+    match responses.pop() {
+        Some((WorkerResponse::PaletteReport { job: 11, report }, None)) => assert_eq!(report, analyze_palette(&image_data, &colors, &settings, 3).unwrap()),
+        _ => panic!("Expected a palette report"),
+    }
+    assert!(responses.iter().all(|(response, _)| matches!(response, WorkerResponse::Progress { job: 11, .. })));
+
+    // Every response crosses back as JSON, including the report of a lone, irreplaceable floss
+    let mut responses = Vec::new();
+    let lone_floss = WorkerRequest::AnalyzePalette { job: 12, image_data: image_data.clone(), colors: colors[..1].to_vec(), settings: settings.clone(), suggestions: 3 };
+    handle_request(&mut pipeline, lone_floss, &CancellationToken::new(), &mut |response, _| responses.push(response));
+    for response in &responses {
+        let decoded: WorkerResponse = serde_json::from_str(&serde_json::to_string(response).unwrap()).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), serde_json::to_string(response).unwrap());
+    }
+    assert!(matches!(responses.last(), Some(WorkerResponse::PaletteReport { job: 12, report }) if report.contributions[0].mean_delta_e_increase.is_none()));
+
+    // Auto-select also reuses the cached image
+    let mut responses = Vec::new();
+    let auto_select = WorkerRequest::AutoSelect { job: 13, image_data: image_data.clone(), allowed_colors: colors.clone(), num_colors: 3, settings: settings.clone() };
+    handle_request(&mut pipeline, auto_select, &CancellationToken::new(), &mut |response, _| responses.push(response));
+    assert_eq!(pipeline.stats().downsamples, downsamples);
+    match responses.last() {
+        Some(WorkerResponse::AutoSelected { job: 13, flosses }) => assert_eq!(*flosses, auto_select_colors(&image_data, &colors, 3, &settings).unwrap()),
+        _ => panic!("Expected auto-selected flosses"),
+    }
+
+    let mut responses = Vec::new();
+    let broken = WorkerRequest::Preview { job: 9, image_data: "data:image/png;base64,AAAA".to_string(), colors: colors.clone(), settings: settings.clone() };
+    handle_request(&mut pipeline, broken, &CancellationToken::new(), &mut |response, _| responses.push(response));
+    assert!(matches!(responses.last(), Some(WorkerResponse::PreviewFailed { job: 9, .. })));
+
+    // A job cancelled while it runs stops at its next checkpoint
+    let mut responses = Vec::new();
+    let cancellation = CancellationToken::new();
+    let changed = WorkerRequest::Preview { job: 10, image_data, colors, settings: GenerationSettings { gem_size_mm: 3.0, ..settings } };
+    handle_request(&mut pipeline, changed, &cancellation, &mut |response, _| {
+        responses.push(response);
+        cancellation.cancel();
+    });
+    assert_eq!(responses.len(), 2);
+    assert!(matches!(responses.last(), Some(WorkerResponse::PreviewFailed { job: 10, error }) if is_cancelled_error(error)));
+}
+
+#[test]
+fn test_stall_timeout_grows_with_image_size() {
+    // Noise keeps the PNG large, so only a prefix of it is read for the size
+    let mut state = 12345u32;
+    let mut noisy = image::RgbaImage::new(1500, 1000);
+    for pixel in noisy.pixels_mut() {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        *pixel = Rgba(state.to_le_bytes());
+    }
+    let large = encode_image_data_url(&DynamicImage::ImageRgba8(noisy));
+    assert!(large.len() > 1 << 21);
+    assert_eq!(image_dimensions(&large), Some((1500, 1000)));
+    let small = encode_image_data_url(&DynamicImage::new_rgba8(100, 100));
+    assert_eq!(image_dimensions(&small), Some((100, 100)));
+
+    let preview = |image_data: &str| WorkerRequest::Preview { job: 1, image_data: image_data.to_string(), colors: vec![], settings: GenerationSettings::default() };
+    let (small_ms, large_ms) = (stall_timeout_ms(&preview(&small)), stall_timeout_ms(&preview(&large)));
+    assert!(small_ms >= 60_000);
+    // 1.5 megapixels count as two, the small image as one
+    assert_eq!(large_ms - small_ms, 5_000);
+}
+
+#[test]
+fn test_generation_hooks_report_progress_and_cancel() {
+    let mut img = DynamicImage::new_rgba8(60, 40);
+    for x in 0..60 {
+        for y in 0..40 {
+            img.put_pixel(x, y, Rgba([(x * 4) as u8, (y * 6) as u8, 90, 255]));
+        }
+    }
+    let image_data = encode_image_data_url(&img);
+    let colors = gray_colors(&["B5200", "762", "415", "318", "310"]);
+    let settings = GenerationSettings { custom_width_mm: Some(100.0), custom_height_mm: Some(80.0), gem_size_mm: 2.5, ..GenerationSettings::default() };
+
+    let reports = std::cell::RefCell::new(Vec::<(String, f32)>::new());
+    let record = |stage: &str, fraction: f32| reports.borrow_mut().push((stage.to_string(), fraction));
+    let (preview, _, data) = generate_gem_art_preview_with_hooks(&image_data, &colors, &settings, &GenerationHooks::default().with_progress(&record)).unwrap();
+    let (expected_preview, _, _) = generate_gem_art_preview_with_settings(&image_data, &colors, &settings).unwrap();
+    assert_eq!(preview, expected_preview);
+    let fractions: Vec<f32> = reports.borrow().iter().map(|(_, fraction)| *fraction).collect();
+    assert!(fractions.len() > 3 && fractions.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", reports.borrow());
+    assert_eq!(fractions.last(), Some(&1.0));
+
+    // Cancelling part way through mapping keeps the earlier stages for the next call
+    let token = CancellationToken::new();
+    let cancel_while_mapping = |stage: &str, _: f32| {
+        if stage == "Matching colors" {
+            token.cancel();
+        }
+    };
+    let mut pipeline = PreviewPipeline::default();
+    let hooks = GenerationHooks::default().with_progress(&cancel_while_mapping).with_cancellation(&token);
+    let error = pipeline.generate_with_hooks(&image_data, &colors, &settings, &hooks).err().unwrap();
+    assert!(is_cancelled_error(&error));
+    assert_eq!((pipeline.stats().downsamples, pipeline.stats().mappings, pipeline.stats().renders), (1, 0, 0));
+    let (_, _, resumed) = pipeline.generate(&image_data, &colors, &settings).unwrap();
+    assert_eq!(pipeline.stats().downsamples, 1);
+    assert_eq!(resumed.gem_grid, data.gem_grid);
+
+    // The chart stops between bands, and ordinary errors are not mistaken for cancellation
+    reports.borrow_mut().clear();
+    let token = CancellationToken::new();
+    let cancel_after_first_band = |stage: &str, _: f32| {
+        record(stage, 0.0);
+        token.cancel();
+    };
+    let hooks = GenerationHooks::default().with_progress(&cancel_after_first_band).with_cancellation(&token);
+    assert!(generate_gem_art_final_with_hooks(&data, &hooks).is_err_and(|e| is_cancelled_error(&e)));
+    assert_eq!(reports.borrow().len(), 1);
+    let completed = generate_gem_art_final_with_hooks(&data, &GenerationHooks::default()).unwrap();
+    assert_eq!(completed, generate_gem_art_final(&data).unwrap());
+    assert!(!is_cancelled_error(&generate_gem_art_preview_with_settings("not an image", &colors, &settings).err().unwrap()));
+}
+
+#[test]
+fn test_cancelling_during_color_matching_stops_inside_the_loop() {
+    let mut img = DynamicImage::new_rgba8(80, 64);
+    for x in 0..80 {
+        for y in 0..64 {
+            img.put_pixel(x, y, Rgba([(x * 3) as u8, (y * 4) as u8, ((x + y) * 2) as u8, 255]));
+        }
+    }
+    let image_data = encode_image_data_url(&img);
+    let colors = all_dmc_colors();
+    let base_settings = GenerationSettings { margin_mm: 0.0, custom_width_mm: Some(100.0), custom_height_mm: Some(80.0), gem_size_mm: 2.5, ..GenerationSettings::default() };
+
+    // Through the lookup table, error diffusion, ordered dithering and a direct search
+    for (dithering_mode, color_lut) in [
+        (DitheringMode::None, true),
+        (DitheringMode::FloydSteinberg, true),
+        (DitheringMode::Bayer, true),
+        (DitheringMode::None, false),
+    ] {
+        let settings = GenerationSettings { dithering_mode, color_lut, ..base_settings.clone() };
+        let token = CancellationToken::new();
+        let reports = std::cell::RefCell::new(Vec::<f32>::new());
+        let cancel_part_way = |stage: &str, fraction: f32| {
+            reports.borrow_mut().push(fraction);
+            if stage == "Matching colors" && fraction > 0.5 {
+                token.cancel();
+            }
+        };
+        let hooks = GenerationHooks::default().with_progress(&cancel_part_way).with_cancellation(&token);
+        let error = generate_gem_art_preview_with_hooks(&image_data, &colors, &settings, &hooks).err().unwrap();
+        assert!(is_cancelled_error(&error), "{:?}: {}", dithering_mode, error);
+        let last = *reports.borrow().last().unwrap();
+        assert!(last > 0.5 && last < 0.7, "{:?} stopped at {} instead of inside color matching", dithering_mode, last);
+    }
+}
@@ -23,6 +23,7 @@ REM Copy HTML/manifest and optional assets
 echo -- Copying HTML/manifest and assets
 copy /Y index.html docs\index.html >nul
 copy /Y manifest.json docs\manifest.json >nul
+copy /Y generation_worker.js docs\generation_worker.js >nul
 if exist static\style.css.map copy /Y static\style.css.map docs\static\style.css.map >nul
 if exist static\DejaVuSans.ttf copy /Y static\DejaVuSans.ttf docs\static\DejaVuSans.ttf >nul
 